@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::str::FromStr;
 use indexmap::IndexMap;
 use serde::Deserialize;
 use serde_with::{serde_as, OneOrMany, formats::PreferOne};
+use crate::script::{FilterScript, TransformScript, ValueScript};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
@@ -18,11 +20,17 @@ pub struct Config {
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type", rename_all = "kebab-case")]
 pub enum FrontendConfig {
-    HttpRest(HttpRestConfig),
+    HttpRest(Box<HttpRestConfig>),
     HomematicCcu3(HomematicCcu3Config),
     Mqtt(MqttConfig),
     Shell(ShellConfig),
     Journald(JournaldConfig),
+    HttpServer(HttpServerConfig),
+    ModbusTcp(ModbusTcpConfig),
+    ModbusRtu(ModbusRtuConfig),
+    Sml(SmlConfig),
+    Snmp(SnmpConfig),
+    Nut(NutConfig),
 }
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type", rename_all = "kebab-case")]
@@ -34,9 +42,44 @@ pub enum BackendConfig {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct HttpRestConfig {
-    pub url: String,
+    #[serde(flatten)]
+    pub request: HttpRequest,
     pub basic_auth: Option<BasicAuth>,
+    pub bearer_token: Option<String>,
     pub frequency_secs: u32,
+    #[serde(default = "default_http_timeout_secs")]
+    pub timeout_secs: u32,
+    /// accept invalid (e.g. self-signed) TLS certificates of local devices
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    /// request performed before the first request and whenever a request is rejected with
+    /// 401 or 403; the returned session cookies are sent with all following requests
+    pub login: Option<Box<HttpRequest>>,
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpRequest {
+    pub url: String,
+    #[serde(default = "default_http_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: IndexMap<String, String>,
+    /// raw request body
+    pub body: Option<String>,
+    /// request body sent as JSON, can't be combined with `body`
+    pub json: Option<serde_json::Value>,
+}
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpServerConfig {
+    /// address to listen on, e.g. `0.0.0.0:8080`
+    pub listen: SocketAddr,
+    pub basic_auth: Option<BasicAuth>,
+    pub bearer_token: Option<String>,
+    /// format of bodies which are neither JSON nor form-encoded; bodies which can't be
+    /// decoded are used as string
+    #[serde(flatten)]
+    pub payload: Payload,
 }
 #[derive(Debug, Clone, Deserialize)]
 pub struct HomematicCcu3Config {
@@ -45,6 +88,29 @@ pub struct HomematicCcu3Config {
     pub frequency_secs: u32,
     pub username: String,
     pub password: String,
+    /// maximum age of the cached device list; it's also reloaded whenever devices are added or removed
+    #[serde(default = "default_homematic_device_list_refresh_secs")]
+    pub device_list_refresh_secs: u64,
+    /// maximum number of concurrent `getParamset` requests
+    #[serde(default = "default_homematic_paramset_concurrency")]
+    pub paramset_concurrency: usize,
+    /// failed requests are retried after `frequency_secs`, doubling the delay on each further failure up to this
+    #[serde(default = "default_homematic_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    /// receive value changes pushed by the CCU; requires `frontend.data_type = "narrow"`
+    pub callback: Option<HomematicCallback>,
+}
+#[derive(Debug, Clone, Deserialize)]
+pub struct HomematicCallback {
+    /// address the XML-RPC callback server listens on
+    pub listen: SocketAddr,
+    /// URL under which the CCU reaches the callback server, e.g. `http://192.168.1.10:9299`
+    pub url: String,
+    /// XML-RPC URL of each interface to register at, e.g. `{ "HmIP-RF" = "http://ccu3:2010" }`
+    pub interfaces: IndexMap<String, String>,
+    /// interval in which the registration is renewed
+    #[serde(default = "default_homematic_reinit_secs")]
+    pub reinit_secs: u64,
 }
 #[derive(Debug, Clone, Deserialize)]
 pub struct BasicAuth {
@@ -57,8 +123,68 @@ pub struct MqttConfig {
     #[serde(default = "default_mqtt_port")]
     pub port: u16,
     pub auth: Option<MqttAuth>,
+    /// must be stable (and unique) for persistent sessions
     #[serde(default = "default_mqtt_client_id")]
     pub client_id: String,
+    #[serde(default)]
+    pub version: MqttVersion,
+    pub tls: Option<MqttTls>,
+    /// default QoS of subscriptions (0, 1 or 2)
+    #[serde(default)]
+    pub qos: u8,
+    /// with `clean_session = false` the broker keeps the session and queued QoS 1 / 2 messages
+    /// while iot2db is disconnected
+    #[serde(default = "default_true")]
+    pub clean_session: bool,
+    #[serde(default = "default_mqtt_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// maximum number of unacknowledged QoS 1 / 2 messages
+    #[serde(default = "default_mqtt_inflight")]
+    pub inflight: u16,
+    /// maximum number of messages queued per subscription
+    #[serde(default = "default_mqtt_queue_size")]
+    pub queue_size: usize,
+    /// what to do with messages of a subscription whose queue is full
+    #[serde(default)]
+    pub overflow: QueueOverflow,
+    /// directory of the files messages are spilled to with `overflow = "spill-to-disk"`
+    pub spill_directory: Option<String>,
+    /// expose messages as `{ "topic", "_topic": [segments], "payload", "retain", "qos", "dropped" }`
+    /// instead of `{ topic: payload }`
+    #[serde(default)]
+    pub metadata: bool,
+    /// format of message payloads; payloads which can't be decoded are used as string
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MqttVersion {
+    /// MQTT 3.1.1
+    #[default]
+    V4,
+    /// MQTT 5
+    V5,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueOverflow {
+    /// wait until there is space again; QoS 1 / 2 messages are only acknowledged once queued.
+    /// Once twice `queue_size` further messages are waiting, the mqtt connection stops reading.
+    #[default]
+    Block,
+    /// drop the oldest queued message
+    DropOldest,
+    /// append messages to a file, which is read once the queue is empty
+    SpillToDisk,
+}
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttTls {
+    /// path to the PEM CA certificate; the system's certificates are used if not set
+    pub ca: Option<String>,
+    /// paths to the PEM client certificate and key for client authentication
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
 }
 #[derive(Debug, Clone, Deserialize)]
 pub struct MqttAuth {
@@ -68,9 +194,56 @@ pub struct MqttAuth {
 #[derive(Debug, Clone, Deserialize)]
 pub struct ShellConfig {
     pub cmd: String,
-    pub frequency_secs: u32,
+    #[serde(default)]
+    pub mode: ShellMode,
+    /// required in `poll` mode
+    pub frequency_secs: Option<u32>,
+    /// kill the command if it doesn't finish within this time (`poll` mode only)
+    pub timeout_secs: Option<u32>,
+    /// execute `cmd` with `sh -c`, e.g. for pipes
+    #[serde(default)]
+    pub shell: bool,
+    /// additional environment variables
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+    /// don't inherit the environment of iot2db
+    #[serde(default)]
+    pub clear_env: bool,
+    /// working directory of the command
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub on_nonzero_exit: NonZeroExit,
+    /// regexes extracting capture group 1 as the key, or each named capture group as its own key
     #[serde(default)]
     pub regex: HashMap<String, String>,
+    /// return all matches of each regex as array under its key instead of only the first match
+    #[serde(default)]
+    pub regex_all: bool,
+    /// apply the regexes to each line of the output separately
+    #[serde(default)]
+    pub regex_per_line: bool,
+    /// format of the output if no `regex` is given
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NonZeroExit {
+    /// log the exit status and stderr and skip the output
+    #[default]
+    Skip,
+    /// log the exit status and stderr but still parse the output
+    Parse,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShellMode {
+    /// execute the command every `frequency_secs` and parse its whole output
+    #[default]
+    Poll,
+    /// keep the command running and parse each line (or JSON document) of its output,
+    /// restarting it with backoff if it exits
+    Stream,
 }
 #[serde_as]
 #[derive(Debug, Clone, Deserialize)]
@@ -84,6 +257,264 @@ pub struct JournaldConfig {
     #[serde(default)]
     pub unit: Vec<String>,
 }
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModbusTcpConfig {
+    pub host: String,
+    #[serde(default = "default_modbus_port")]
+    pub port: u16,
+    #[serde(flatten)]
+    pub poll: ModbusPoll,
+}
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModbusRtuConfig {
+    /// serial device, e.g. `/dev/ttyUSB0`; frontends with the same device share the bus
+    pub device: String,
+    #[serde(default = "default_modbus_baud_rate")]
+    pub baud_rate: u32,
+    #[serde(default)]
+    pub parity: Parity,
+    #[serde(default = "default_modbus_stop_bits")]
+    pub stop_bits: u8,
+    #[serde(flatten)]
+    pub poll: ModbusPoll,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Parity {
+    #[default]
+    None,
+    Even,
+    Odd,
+}
+/// Registers of Modbus TCP and RTU frontends
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModbusPoll {
+    /// unit id (slave id) of registers without their own
+    #[serde(default = "default_modbus_unit_id")]
+    pub unit_id: u8,
+    pub frequency_secs: u32,
+    /// timeout of each request
+    #[serde(default = "default_modbus_timeout_secs")]
+    pub timeout_secs: u32,
+    /// registers to read, keyed by the name they are returned as
+    #[serde(default)]
+    pub registers: IndexMap<String, ModbusRegister>,
+    /// discover the model blocks of a SunSpec device and return their points
+    pub sunspec: Option<SunSpecConfig>,
+}
+#[derive(Debug, Clone, Deserialize)]
+pub struct SunSpecConfig {
+    /// overrides the frontend's `unit_id`
+    pub unit_id: Option<u8>,
+    /// address of the `SunS` marker; 40000, 50000 and 0 are tried if not set
+    pub base_address: Option<u16>,
+}
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModbusRegister {
+    /// 0-based address of the (first) register / bit
+    pub address: u16,
+    #[serde(default)]
+    pub function: ModbusFunction,
+    #[serde(rename = "type", default)]
+    pub data_type: ModbusDataType,
+    /// order of the registers of values spanning multiple registers
+    #[serde(default)]
+    pub word_order: WordOrder,
+    /// factor the value is multiplied with, e.g. `0.1`
+    pub scale: Option<f64>,
+    /// overrides the frontend's `unit_id`
+    pub unit_id: Option<u8>,
+    /// number of registers of a `string`
+    pub length: Option<u16>,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModbusFunction {
+    /// function code 1
+    Coil,
+    /// function code 2
+    DiscreteInput,
+    /// function code 3
+    #[default]
+    Holding,
+    /// function code 4
+    Input,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModbusDataType {
+    #[default]
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    /// a single coil / discrete input
+    Bool,
+    /// `length` registers of ASCII with two characters each, trailing NULs are removed
+    String,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WordOrder {
+    /// most significant register first
+    #[default]
+    Big,
+    /// least significant register first
+    Little,
+}
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmlConfig {
+    #[serde(default)]
+    pub protocol: SmlProtocol,
+    /// serial device, e.g. the optical IR head `/dev/ttyUSB0`
+    pub device: Option<String>,
+    #[serde(default = "default_sml_baud_rate")]
+    pub baud_rate: u32,
+    #[serde(default)]
+    pub parity: Parity,
+    /// 8 for SML, usually 7 (with `parity = "even"`) for D0
+    #[serde(default = "default_sml_data_bits")]
+    pub data_bits: u8,
+    /// TCP bridge like Tasmota or ser2net, e.g. `192.168.1.60:8888`
+    pub tcp: Option<String>,
+    /// file or named pipe with recorded telegrams, read once
+    pub file: Option<String>,
+    /// D0 only: send the request message `/?!` in this interval to meters which don't push telegrams
+    pub request_secs: Option<u32>,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SmlProtocol {
+    /// binary Smart Message Language
+    #[default]
+    Sml,
+    /// IEC 62056-21 text telegrams
+    D0,
+}
+#[derive(Debug, Clone, Deserialize)]
+pub struct SnmpConfig {
+    pub host: String,
+    #[serde(default = "default_snmp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub version: SnmpVersion,
+    /// v2c only
+    #[serde(default = "default_snmp_community")]
+    pub community: String,
+    /// v3 only: USM user name
+    pub user: Option<String>,
+    /// v3 only: enables authentication
+    pub auth_password: Option<String>,
+    #[serde(default)]
+    pub auth_protocol: SnmpAuthProtocol,
+    /// v3 only: enables privacy (encryption), requires `auth_password`
+    pub priv_password: Option<String>,
+    #[serde(default)]
+    pub priv_protocol: SnmpPrivProtocol,
+    /// v3 only
+    #[serde(default)]
+    pub context: String,
+    pub frequency_secs: u32,
+    /// timeout of each request
+    #[serde(default = "default_snmp_timeout_secs")]
+    pub timeout_secs: u32,
+    /// number of times a request is resent after a timeout
+    #[serde(default = "default_snmp_retries")]
+    pub retries: u32,
+    /// single objects to get, e.g. `uptime = "1.3.6.1.2.1.1.3.0"`
+    #[serde(default)]
+    pub oids: IndexMap<String, String>,
+    /// subtrees to walk, returned as rows keyed by their index
+    #[serde(default)]
+    pub walks: IndexMap<String, SnmpWalk>,
+}
+#[derive(Debug, Clone, Deserialize)]
+pub struct SnmpWalk {
+    /// table entry, e.g. `1.3.6.1.2.1.2.2.1` for `ifEntry`
+    pub oid: String,
+    /// only walk these columns, keyed by the name they are returned as, e.g. `in-octets = 10`;
+    /// all columns are returned by their number if empty
+    #[serde(default)]
+    pub columns: IndexMap<String, u32>,
+}
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct NutConfig {
+    /// host running `upsd` of Network UPS Tools
+    pub host: String,
+    #[serde(default = "default_nut_port")]
+    pub port: u16,
+    /// UPS names as configured in `ups.conf`; all UPS of `LIST UPS` if empty
+    #[serde_as(as = "OneOrMany<_, PreferOne>")]
+    #[serde(default)]
+    pub ups: Vec<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub frequency_secs: u32,
+    /// timeout of each poll
+    #[serde(default = "default_nut_timeout_secs")]
+    pub timeout_secs: u32,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnmpVersion {
+    #[default]
+    V2c,
+    V3,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnmpAuthProtocol {
+    Md5,
+    #[default]
+    Sha,
+    Sha256,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnmpPrivProtocol {
+    Des,
+    /// AES-128
+    #[default]
+    Aes,
+}
+
+/// Format of text payloads (responses, command output, messages) of a frontend
+#[derive(Debug, Clone, Deserialize)]
+pub struct Payload {
+    #[serde(default)]
+    pub format: PayloadFormat,
+    /// for `format = "html"`: extract the text of the first element matching each CSS-selector
+    /// instead of converting the whole page to JSON
+    #[serde(default)]
+    pub selectors: IndexMap<String, String>,
+    #[serde(default = "default_csv_delimiter")]
+    pub csv_delimiter: char,
+    /// for `format = "csv"`: the first row contains the column names; rows become objects
+    /// instead of arrays
+    #[serde(default = "default_true")]
+    pub csv_header: bool,
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    /// HTML page converted to JSON (see `frontend::html`)
+    Html,
+    /// XML converted to JSON with the same conventions as HTML
+    Xml,
+    /// array of rows
+    Csv,
+    /// `key=value` or `key: value` lines
+    KeyValue,
+    /// single number, boolean or string
+    Scalar,
+}
 
 // backends
 
@@ -106,17 +537,58 @@ pub struct DataConfig {
     pub clean_non_persistent_after_days: Option<u32>,
     /// rebo code taking `Value`-map, returning a boolean indicating if the value
     /// should be processed (`true`) or discarded (`false`)
-    pub filter: Option<String>,
+    pub filter: Option<FilterScript>,
+    /// rebo code taking the mapped `record` (map of value-name to preprocessed, unescaped value) and the
+    /// `state` returned by its previous execution (initially an empty map), returning
+    /// `{ "records": [...], "state": ... }`; can add, remove or rename values,
+    /// drop the record (`"records": []`) or split it into multiple records
+    pub transform: Option<TransformScript>,
     #[serde(flatten)]
     pub mapping: Mapping,
 }
 #[serde_as]
 #[derive(Debug, Clone, Deserialize)]
 pub struct Mapping {
+    /// json-pointer to an array or object; if set, the mapping is applied relative to
+    /// each of its elements, producing one record per element
+    pub for_each: Option<String>,
+    /// name of the value the array-index or object-key of the `for_each`-element is stored in
+    pub for_each_key: Option<String>,
     pub direct_values: Option<DirectValues>,
+    /// store each value as its own row instead of one row with one column per value
+    pub narrow: Option<NarrowLayout>,
+    /// when to emit a buffered record for `frontend.data_type = "narrow"`
+    #[serde(default)]
+    pub flush: Flush,
     #[serde_as(as = "IndexMap<_, serde_with::PickFirst<(serde_with::DisplayFromStr, _)>>")]
     pub values: IndexMap<String, Value>,
 }
+/// Narrow table layout: `timestamp, device, measurement, value`
+///
+/// The measurement is the name of the value; the timestamp is usually a constant value.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NarrowLayout {
+    /// template of the device, expanded with the segments captured by the value's pointer (`${1}`, ...)
+    pub device: String,
+    #[serde(default = "default_device_column")]
+    pub device_column: String,
+    #[serde(default = "default_measurement_column")]
+    pub measurement_column: String,
+    #[serde(default = "default_value_column")]
+    pub value_column: String,
+}
+/// A narrow record is always emitted when a value arrives a second time and on shutdown.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Flush {
+    /// emit once all values with a non-wildcard pointer are present (requires at least one)
+    #[serde(default)]
+    pub complete: bool,
+    /// emit if no value arrived for this many seconds
+    pub idle_timeout_secs: Option<u32>,
+    /// emit when a message containing this json-pointer arrives (e.g. `/evcc~1updated`);
+    /// the trigger-message itself isn't stored
+    pub trigger: Option<String>,
+}
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum DirectValues {
@@ -124,6 +596,14 @@ pub enum DirectValues {
     All(DirectValuesAll),
     /// `direct_values = ["foo", "bar"]`
     Keys(Vec<String>),
+    /// `direct_values = { regex = "^/devices/[^/]*/temperature$", name = "${1}" }`
+    ///
+    /// `name` is a template expanded with the regex's capture groups (`${1}`, `${name}`);
+    /// if it isn't set, the key is derived from the json-pointer as with `all`
+    Regex {
+        regex: String,
+        name: Option<String>,
+    },
 }
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -135,16 +615,18 @@ pub struct Value {
     pub kind: ValueKind,
     /// rebo code taking `value`-string before escaping and returning its replacement-string
     /// if there is no value, e.g. the json-pointer doesn't exist, this is _not_ executed
-    pub preprocess: Option<String>,
+    pub preprocess: Option<ValueScript>,
     /// rebo code taking `value`-string after escaping and returning its replacement-string
     /// if there is no value, e.g. the json-pointer doesn't exist, this is _not_ executed
-    pub postprocess: Option<String>,
+    pub postprocess: Option<ValueScript>,
     #[serde(default)]
     pub aggregate: Aggregate,
 }
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum ValueKind {
+    /// `*` in the pointer matches any characters within a single segment;
+    /// the value's name may reference the matched segments as `${1}`, `${2}`, ...
     Pointer { pointer: String },
     Constant { constant_value: String },
 }
@@ -165,6 +647,11 @@ pub struct FrontendRef {
 pub enum FrontendRefData {
     Mqtt {
         mqtt_topic: String,
+        /// QoS of the subscription, overriding the frontend's `qos`
+        mqtt_qos: Option<u8>,
+    },
+    HttpServer {
+        http_path: String,
     },
 }
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
@@ -203,6 +690,54 @@ pub struct PostgresRef {
 
 
 
+impl Config {
+    /// check all rebo scripts for syntax errors and type errors on the path of an example input
+    pub fn check_scripts(&self) -> Result<(), String> {
+        for (data_name, data) in &self.data {
+            if let Some(filter) = &data.filter {
+                filter.check().map_err(|e| format!("filter of data {data_name:?}: {e}"))?;
+            }
+            if let Some(transform) = &data.transform {
+                transform.check().map_err(|e| format!("transform of data {data_name:?}: {e}"))?;
+            }
+            for (value_name, value) in &data.mapping.values {
+                if let Some(preprocess) = &value.preprocess {
+                    preprocess.check().map_err(|e| format!("preprocess of value {value_name:?} of data {data_name:?}: {e}"))?;
+                }
+                if let Some(postprocess) = &value.postprocess {
+                    postprocess.check().map_err(|e| format!("postprocess of value {value_name:?} of data {data_name:?}: {e}"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Mapping {
+    /// absolute json-pointers of all pointer-values and `direct_values` keys of this mapping,
+    /// `None` if `direct_values` (`all` or a regex) may access any pointer
+    ///
+    /// If `for_each` is used, the segment of the iterated element is `*`.
+    pub fn accessed_pointers(&self) -> Option<Vec<String>> {
+        let direct_keys = match &self.direct_values {
+            Some(DirectValues::All(_) | DirectValues::Regex { .. }) => return None,
+            Some(DirectValues::Keys(keys)) => keys.as_slice(),
+            None => &[],
+        };
+        let pointers = self.values.values()
+            .filter_map(|value| match &value.kind {
+                ValueKind::Pointer { pointer } => Some(pointer),
+                ValueKind::Constant { .. } => None,
+            })
+            .chain(direct_keys)
+            .map(|pointer| match &self.for_each {
+                Some(for_each) => format!("{for_each}/*{pointer}"),
+                None => pointer.clone(),
+            });
+        Some(pointers.collect())
+    }
+}
+
 impl FromStr for Value {
     type Err = Infallible;
 
@@ -215,13 +750,49 @@ impl FromStr for Value {
         })
     }
 }
+impl Default for Payload {
+    fn default() -> Self {
+        Payload {
+            format: PayloadFormat::default(),
+            selectors: IndexMap::new(),
+            csv_delimiter: default_csv_delimiter(),
+            csv_header: true,
+        }
+    }
+}
 impl Default for Aggregate {
     fn default() -> Self {
         Aggregate::None
     }
 }
 
+fn default_http_method() -> String { "GET".to_string() }
+fn default_http_timeout_secs() -> u32 { 10 }
+fn default_csv_delimiter() -> char { ',' }
+fn default_homematic_device_list_refresh_secs() -> u64 { 3600 }
+fn default_homematic_paramset_concurrency() -> usize { 4 }
+fn default_homematic_reinit_secs() -> u64 { 600 }
+fn default_homematic_max_backoff_secs() -> u64 { 900 }
+fn default_modbus_port() -> u16 { 502 }
+fn default_modbus_unit_id() -> u8 { 1 }
+fn default_modbus_timeout_secs() -> u32 { 5 }
+fn default_modbus_baud_rate() -> u32 { 9600 }
+fn default_modbus_stop_bits() -> u8 { 1 }
+fn default_sml_baud_rate() -> u32 { 9600 }
+fn default_sml_data_bits() -> u8 { 8 }
+fn default_snmp_port() -> u16 { 161 }
+fn default_snmp_community() -> String { "public".to_string() }
+fn default_snmp_timeout_secs() -> u32 { 5 }
+fn default_snmp_retries() -> u32 { 2 }
+fn default_nut_port() -> u16 { 3493 }
+fn default_nut_timeout_secs() -> u32 { 10 }
 fn default_mqtt_port() -> u16 { 1883 }
 fn default_mqtt_client_id() -> String { "iot2db".to_string() }
+fn default_mqtt_keep_alive_secs() -> u64 { 10 }
+fn default_mqtt_inflight() -> u16 { 10 }
+fn default_mqtt_queue_size() -> usize { 1000 }
+fn default_device_column() -> String { "device".to_string() }
+fn default_measurement_column() -> String { "measurement".to_string() }
+fn default_value_column() -> String { "value".to_string() }
 fn default_postgres_port() -> u16 { 5432 }
 fn default_true() -> bool { true }
@@ -1,119 +1,420 @@
 use std::mem;
 use std::sync::Arc;
-use indexmap::{IndexMap, map::Entry};
+use std::time::{Duration, Instant};
+use indexmap::IndexMap;
+use regex::Regex;
 use serde_json::Value as JsonValue;
-use crate::run_rebo;
 use crate::backend::BackendEscaper;
 use crate::config::{DirectValues, Mapping, Value as ConfigValue, ValueKind};
 use crate::iter_json_value::iter_json_value;
+use crate::script::{TransformScript, ValueScript};
 
 pub trait DataMapper {
-    fn new(mapping: Mapping, escaper: Arc<dyn BackendEscaper + Send + Sync + 'static>) -> Self where Self: Sized;
-    fn consume_value(&mut self, value: JsonValue) -> Option<IndexMap<String, String>>;
+    fn new(mapping: Mapping, transform: Option<TransformScript>, escaper: Arc<dyn BackendEscaper + Send + Sync + 'static>) -> Self where Self: Sized;
+    fn consume_value(&mut self, value: JsonValue) -> Vec<IndexMap<String, String>>;
+    /// called periodically, e.g. to emit records after a timeout
+    fn tick(&mut self) -> Vec<IndexMap<String, String>> {
+        Vec::new()
+    }
+    /// called on shutdown to emit all buffered records
+    fn flush(&mut self) -> Vec<IndexMap<String, String>> {
+        Vec::new()
+    }
 }
 pub struct WideToWide {
     mapping: Mapping,
+    matchers: Matchers,
+    output: RecordOutput,
+}
+
+/// preprocessed but not yet escaped value of a record
+#[derive(Clone)]
+struct RawValue {
+    value: String,
+    /// run on the escaped value
+    postprocess: Option<ValueScript>,
+}
+type RawRecord = IndexMap<String, RawValue>;
+
+impl RawValue {
+    fn new(value: String) -> RawValue {
+        RawValue { value, postprocess: None }
+    }
+}
+
+/// Transforms raw records (if configured), then escapes and postprocesses their values
+struct RecordOutput {
+    transform: Option<RecordTransform>,
     escaper: Arc<dyn BackendEscaper + Send + Sync + 'static>,
 }
 
+impl RecordOutput {
+    fn new(transform: Option<TransformScript>, escaper: Arc<dyn BackendEscaper + Send + Sync + 'static>) -> Self {
+        RecordOutput { transform: transform.map(RecordTransform::new), escaper }
+    }
+
+    fn finish(&mut self, records: Vec<RawRecord>) -> Vec<IndexMap<String, String>> {
+        let RecordOutput { transform, escaper } = self;
+        records.into_iter()
+            .flat_map(|record| match transform {
+                Some(transform) => transform.transform(record),
+                None => vec![record],
+            })
+            .map(|record| record.into_iter().filter_map(|(key, RawValue { value, postprocess })| {
+                let value = escaper.escape_value(value);
+                let value = match postprocess {
+                    Some(postprocess) => postprocess.run(value)?,
+                    None => value,
+                };
+                Some((key, value))
+            }).collect())
+            .collect()
+    }
+}
+
+/// json-pointer matchers of a mapping, compiled once when creating the mapper
+struct Matchers {
+    /// one matcher per value of the mapping, `None` for constants
+    values: Vec<Option<PointerMatcher>>,
+    /// compiled regex of `direct_values = { regex = "..." }`
+    direct_values: Option<Regex>,
+}
+enum PointerMatcher {
+    Exact(String),
+    /// pointer containing `*`, which matches any characters within a single segment
+    Wildcard(Regex),
+}
+
+impl Matchers {
+    fn new(mapping: &Mapping) -> Matchers {
+        let values = mapping.values.values().map(|value| match &value.kind {
+            ValueKind::Pointer { pointer } if pointer.contains('*') => {
+                // every `*` becomes a capture group, which can be referenced in the value name
+                let pattern = pointer.split('*')
+                    .map(regex::escape)
+                    .collect::<Vec<_>>()
+                    .join("([^/]*)");
+                let regex = Regex::new(&format!("^{pattern}$"))
+                    .unwrap_or_else(|e| panic!("invalid wildcard pointer {pointer:?}: {e:?}"));
+                Some(PointerMatcher::Wildcard(regex))
+            }
+            ValueKind::Pointer { pointer } => Some(PointerMatcher::Exact(pointer.clone())),
+            ValueKind::Constant { .. } => None,
+        }).collect();
+        let direct_values = match &mapping.direct_values {
+            Some(DirectValues::Regex { regex, .. }) => Some(Regex::new(regex)
+                .unwrap_or_else(|e| panic!("invalid direct_values regex {regex:?}: {e:?}"))),
+            _ => None,
+        };
+        Matchers { values, direct_values }
+    }
+}
+
+/// A json-value matched by a pointer-value or `direct_values`
+struct MatchedValue<'a> {
+    name: String,
+    /// json-pointer-unescaped segments captured by a wildcard-pointer or the `direct_values`-regex
+    captures: Vec<String>,
+    preprocess: Option<&'a ValueScript>,
+    postprocess: Option<&'a ValueScript>,
+    json_value: &'a JsonValue,
+}
+
+/// Returns the name the json-pointer is stored as and the captured segments
+/// if the `PointerMatcher` matches it.
+///
+/// The name of wildcard-pointers is expanded with their captured segments (`${1}`, `${2}`, ...).
+fn match_pointer(matcher: &PointerMatcher, json_pointer: &str, name: &str) -> Option<(String, Vec<String>)> {
+    match matcher {
+        PointerMatcher::Exact(pointer) => (pointer == json_pointer).then(|| (name.to_string(), Vec::new())),
+        PointerMatcher::Wildcard(regex) => {
+            let captures = regex.captures(json_pointer)?;
+            Some(expand_captures(&captures, name))
+        }
+    }
+}
+
+fn expand_captures(captures: &regex::Captures, template: &str) -> (String, Vec<String>) {
+    let mut expanded = String::new();
+    captures.expand(template, &mut expanded);
+    let captures = captures.iter().skip(1)
+        .map(|capture| unescape_json_pointer(capture.map(|c| c.as_str()).unwrap_or("")))
+        .collect();
+    (unescape_json_pointer(&expanded), captures)
+}
+
+/// expand `${1}`, `${2}`, ... in the template with the given captures
+fn expand_template(template: &str, captures: &[String]) -> String {
+    let mut expanded = template.to_string();
+    for (i, capture) in captures.iter().enumerate().rev() {
+        expanded = expanded.replace(&format!("${{{}}}", i + 1), capture);
+    }
+    expanded
+}
+
 // does *not* handle constants
-fn get_and_process_values(value: &JsonValue, mapping: &Mapping, escaper: &dyn BackendEscaper) -> IndexMap<String, String> {
+fn match_values<'a>(value: &'a JsonValue, mapping: &'a Mapping, matchers: &Matchers) -> Vec<MatchedValue<'a>> {
     iter_json_value(value).filter_map(|(json_pointer, json_value)| {
-        let config_value = mapping.values.iter()
-            .find(|(_, value)| matches!(&value.kind, ValueKind::Pointer { pointer } if *pointer == json_pointer));
-        let (name, preprocess, postprocess) = match (config_value, &mapping.direct_values) {
-            (Some((config_value_name, ConfigValue { kind: ValueKind::Pointer { .. }, preprocess, postprocess, aggregate: _ })), _) => (config_value_name.clone(), preprocess.clone(), postprocess.clone()),
-            (Some((_, ConfigValue { kind: ValueKind::Constant { .. }, .. })), _) => return None,
-            (None, Some(DirectValues::All(_))) if json_pointer == "" => return None,
-            (None, Some(DirectValues::All(_))) => (json_pointer_to_key(&json_pointer), None, None),
-            (None, Some(DirectValues::Keys(keys))) if keys.iter().any(|k| *k == json_pointer) => (json_pointer_to_key(&json_pointer), None, None),
+        let config_value = mapping.values.iter().zip(&matchers.values)
+            .find_map(|((name, value), matcher)| {
+                let (name, captures) = match_pointer(matcher.as_ref()?, &json_pointer, name)?;
+                Some((name, captures, value))
+            });
+        let (name, captures, preprocess, postprocess) = match (config_value, &mapping.direct_values) {
+            (Some((name, captures, ConfigValue { preprocess, postprocess, .. })), _) => (name, captures, preprocess.as_ref(), postprocess.as_ref()),
+            (None, Some(DirectValues::All(_))) if json_pointer.is_empty() => return None,
+            (None, Some(DirectValues::All(_))) => (json_pointer_to_key(&json_pointer), Vec::new(), None, None),
+            (None, Some(DirectValues::Keys(keys))) if keys.iter().any(|k| *k == json_pointer) => (json_pointer_to_key(&json_pointer), Vec::new(), None, None),
+            (None, Some(DirectValues::Regex { name, .. })) => {
+                let captures = matchers.direct_values.as_ref().unwrap().captures(&json_pointer)?;
+                let template = name.as_deref().unwrap_or("");
+                let (expanded, captures) = expand_captures(&captures, template);
+                match name {
+                    Some(_) => (expanded, captures, None, None),
+                    None => (json_pointer_to_key(&json_pointer), captures, None, None),
+                }
+            }
             _ => return None,
         };
-        // `String("uiae").to_string()` results in `"\"uiae\""` but we want `"uiae"`
-        let val = match json_value {
-            JsonValue::String(s) => s.clone(),
-            val => val.to_string(),
-        };
-        Some((name, process_value(val, preprocess, postprocess, escaper)))
+        Some(MatchedValue { name, captures, preprocess, postprocess, json_value })
     }).collect()
 }
 
-fn process_value(val: String, preprocess: Option<String>, postprocess: Option<String>, escaper: &dyn BackendEscaper) -> String {
-    let val = match preprocess.clone() {
-        Some(preprocess) => run_rebo(preprocess, val),
-        None => val,
-    };
-    let val = escaper.escape_value(val);
-    let val = match postprocess.clone() {
-        Some(postprocess) => run_rebo(postprocess, val),
+/// `String("uiae").to_string()` results in `"\"uiae\""` but we want `"uiae"`
+fn json_value_to_string(json_value: &JsonValue) -> String {
+    match json_value {
+        JsonValue::String(s) => s.clone(),
+        val => val.to_string(),
+    }
+}
+
+// does *not* handle constants
+fn get_and_process_values(value: &JsonValue, mapping: &Mapping, matchers: &Matchers) -> RawRecord {
+    let mut map = IndexMap::new();
+    for matched in match_values(value, mapping, matchers) {
+        let val = json_value_to_string(matched.json_value);
+        let Some(val) = process_value(val, matched.preprocess, matched.postprocess) else { continue };
+        if map.insert(matched.name.clone(), val).is_some() {
+            eprintln!("multiple json-pointers map to value `{}`; using the last one", matched.name);
+        }
+    }
+    map
+}
+
+/// runs the preprocess script, returning `None` if it fails; postprocess runs after escaping
+fn process_value(val: String, preprocess: Option<&ValueScript>, postprocess: Option<&ValueScript>) -> Option<RawValue> {
+    let value = match preprocess {
+        Some(preprocess) => preprocess.run(val)?,
         None => val,
     };
-    val
+    Some(RawValue { value, postprocess: postprocess.cloned() })
 }
 
-fn iter_mapped_constants<'a>(mapping: &'a Mapping, escaper: &'a dyn BackendEscaper) -> impl Iterator<Item = (String, String)> + 'a {
+fn iter_mapped_constants(mapping: &Mapping) -> impl Iterator<Item = (String, RawValue)> + '_ {
     mapping.values.iter().filter_map(|(key, mapping_value)| {
         let val = match &mapping_value.kind {
             ValueKind::Pointer { .. } => return None,
             ValueKind::Constant { constant_value: const_value } => const_value.clone(),
         };
-        let val = process_value(val, mapping_value.preprocess.clone(), mapping_value.postprocess.clone(), escaper);
+        let val = process_value(val, mapping_value.preprocess.as_ref(), mapping_value.postprocess.as_ref())?;
         Some((key.clone(), val))
     })
 }
 
 impl DataMapper for WideToWide {
-    fn new(mapping: Mapping, escaper: Arc<dyn BackendEscaper + Send + Sync + 'static>) -> Self
+    fn new(mapping: Mapping, transform: Option<TransformScript>, escaper: Arc<dyn BackendEscaper + Send + Sync + 'static>) -> Self
     where Self: Sized
     {
-        WideToWide { mapping, escaper }
+        let matchers = Matchers::new(&mapping);
+        WideToWide { mapping, matchers, output: RecordOutput::new(transform, escaper) }
     }
 
-    fn consume_value(&mut self, value: JsonValue) -> Option<IndexMap<String, String>> {
-        let mut map = get_and_process_values(&value, &self.mapping, &*self.escaper);
-        map.extend(iter_mapped_constants(&self.mapping, &*self.escaper));
-        Some(map)
+    fn consume_value(&mut self, value: JsonValue) -> Vec<IndexMap<String, String>> {
+        let Some(for_each) = &self.mapping.for_each else {
+            let mut map = get_and_process_values(&value, &self.mapping, &self.matchers);
+            map.extend(iter_mapped_constants(&self.mapping));
+            return self.output.finish(vec![map])
+        };
+        let elements: Vec<(String, &JsonValue)> = match value.pointer(for_each) {
+            Some(JsonValue::Array(array)) => array.iter().enumerate()
+                .map(|(i, element)| (i.to_string(), element))
+                .collect(),
+            Some(JsonValue::Object(object)) => object.iter()
+                .map(|(key, element)| (key.clone(), element))
+                .collect(),
+            Some(_) => {
+                eprintln!("for_each pointer `{for_each}` doesn't point to an array or object");
+                return Vec::new()
+            }
+            None => {
+                eprintln!("for_each pointer `{for_each}` doesn't exist in value");
+                return Vec::new()
+            }
+        };
+        let records = elements.into_iter().map(|(key, element)| {
+            let mut map = get_and_process_values(element, &self.mapping, &self.matchers);
+            if let Some(key_name) = &self.mapping.for_each_key {
+                map.insert(key_name.clone(), RawValue::new(key));
+            }
+            map.extend(iter_mapped_constants(&self.mapping));
+            map
+        }).collect();
+        self.output.finish(records)
     }
 }
 
 pub struct NarrowToWide {
     mapping: Mapping,
-    escaper: Arc<dyn BackendEscaper + Send + Sync + 'static>,
-    buffered_value: IndexMap<String, String>,
+    matchers: Matchers,
+    output: RecordOutput,
+    buffered_value: RawRecord,
+    /// time the last value was added to `buffered_value`
+    last_value: Instant,
+}
+
+impl NarrowToWide {
+    /// take the buffered record (if any) and add constants to it
+    fn take_buffered(&mut self) -> Vec<IndexMap<String, String>> {
+        if self.buffered_value.is_empty() {
+            return Vec::new();
+        }
+        let new = IndexMap::with_capacity(self.mapping.values.len());
+        let mut map = mem::replace(&mut self.buffered_value, new);
+        map.extend(iter_mapped_constants(&self.mapping));
+        self.output.finish(vec![map])
+    }
+
+    /// all values with a non-wildcard pointer are buffered; never if there are none
+    fn is_complete(&self) -> bool {
+        let mut exact = self.mapping.values.iter().zip(&self.matchers.values)
+            .filter(|(_, matcher)| matches!(matcher, Some(PointerMatcher::Exact(_))))
+            .peekable();
+        exact.peek().is_some() && exact.all(|((name, _), _)| self.buffered_value.contains_key(name))
+    }
 }
 
 impl DataMapper for NarrowToWide {
-    fn new(mapping: Mapping, escaper: Arc<dyn BackendEscaper + Send + Sync + 'static>) -> Self
+    fn new(mapping: Mapping, transform: Option<TransformScript>, escaper: Arc<dyn BackendEscaper + Send + Sync + 'static>) -> Self
     where Self: Sized
     {
+        assert!(mapping.for_each.is_none(), "for_each is only supported for frontend.data_type = \"wide\"");
         let values_len = mapping.values.len();
-        NarrowToWide { mapping, escaper, buffered_value: IndexMap::with_capacity(values_len) }
-    }
-
-    fn consume_value(&mut self, value: JsonValue) -> Option<IndexMap<String, String>> {
-        let map = get_and_process_values(&value, &self.mapping, &*self.escaper);
-        assert!(map.len() <= 1);
-        let (key, value) = map.into_iter().next()?;
-
-        match self.buffered_value.entry(key.clone()) {
-            Entry::Vacant(vacant) => { vacant.insert(value); },
-            Entry::Occupied(_) => {
-                let new = IndexMap::with_capacity(self.mapping.values.len());
-                let mut map = mem::replace(&mut self.buffered_value, new);
-                self.buffered_value.insert(key.clone(), value);
-                // add constants to map
-                for (key, mapping_value) in &self.mapping.values {
-                    let val = match &mapping_value.kind {
-                        ValueKind::Pointer { .. } => continue,
-                        ValueKind::Constant { constant_value: const_value } => const_value.clone(),
-                    };
-                    let val = process_value(val, mapping_value.preprocess.clone(), mapping_value.postprocess.clone(), &*self.escaper);
-                    map.insert(key.clone(), val);
-                }
-                return Some(map)
+        let matchers = Matchers::new(&mapping);
+        assert!(
+            !mapping.flush.complete || matchers.values.iter().any(|matcher| matches!(matcher, Some(PointerMatcher::Exact(_)))),
+            "flush.complete requires at least one value with a non-wildcard pointer",
+        );
+        NarrowToWide { mapping, matchers, output: RecordOutput::new(transform, escaper), buffered_value: IndexMap::with_capacity(values_len), last_value: Instant::now() }
+    }
+
+    fn consume_value(&mut self, value: JsonValue) -> Vec<IndexMap<String, String>> {
+        if let Some(trigger) = &self.mapping.flush.trigger {
+            if value.pointer(trigger).is_some() {
+                return self.take_buffered();
             }
         }
-        None
+
+        // usually a single value, but e.g. mqtt metadata can map the topic and the payload
+        let map = get_and_process_values(&value, &self.mapping, &self.matchers);
+        if map.is_empty() {
+            return Vec::new();
+        }
+        self.last_value = Instant::now();
+
+        // a value which is already buffered starts a new record
+        let mut res = Vec::new();
+        if map.keys().any(|key| self.buffered_value.contains_key(key)) {
+            res = self.take_buffered();
+        }
+        self.buffered_value.extend(map);
+        if self.mapping.flush.complete && self.is_complete() {
+            res.extend(self.take_buffered());
+        }
+        res
+    }
+
+    fn tick(&mut self) -> Vec<IndexMap<String, String>> {
+        match self.mapping.flush.idle_timeout_secs {
+            Some(secs) if self.last_value.elapsed() >= Duration::from_secs(secs as u64) => self.take_buffered(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn flush(&mut self) -> Vec<IndexMap<String, String>> {
+        self.take_buffered()
+    }
+}
+
+pub struct NarrowToNarrow {
+    mapping: Mapping,
+    matchers: Matchers,
+    output: RecordOutput,
+}
+
+impl DataMapper for NarrowToNarrow {
+    fn new(mapping: Mapping, transform: Option<TransformScript>, escaper: Arc<dyn BackendEscaper + Send + Sync + 'static>) -> Self
+    where Self: Sized
+    {
+        assert!(mapping.for_each.is_none(), "for_each is only supported for frontend.data_type = \"wide\"");
+        assert!(mapping.narrow.is_some(), "NarrowToNarrow requires a narrow table layout");
+        let matchers = Matchers::new(&mapping);
+        NarrowToNarrow { mapping, matchers, output: RecordOutput::new(transform, escaper) }
+    }
+
+    fn consume_value(&mut self, value: JsonValue) -> Vec<IndexMap<String, String>> {
+        let narrow = self.mapping.narrow.as_ref().unwrap();
+        let records = match_values(&value, &self.mapping, &self.matchers).into_iter().filter_map(|matched| {
+            let val = json_value_to_string(matched.json_value);
+            let val = process_value(val, matched.preprocess, matched.postprocess)?;
+            let device = expand_template(&narrow.device, &matched.captures);
+            let mut map = IndexMap::with_capacity(3 + self.mapping.values.len());
+            map.insert(narrow.device_column.clone(), RawValue::new(device));
+            map.insert(narrow.measurement_column.clone(), RawValue::new(matched.name));
+            map.insert(narrow.value_column.clone(), val);
+            map.extend(iter_mapped_constants(&self.mapping));
+            Some(map)
+        }).collect();
+        self.output.finish(records)
+    }
+}
+
+/// Stateful transformation of mapped, unescaped records with a rebo script
+///
+/// Values of returned records keep the postprocess script of the input value with the same name.
+struct RecordTransform {
+    script: TransformScript,
+    state: JsonValue,
+}
+
+impl RecordTransform {
+    fn new(script: TransformScript) -> Self {
+        RecordTransform { script, state: JsonValue::Object(Default::default()) }
+    }
+
+    fn transform(&mut self, record: RawRecord) -> Vec<RawRecord> {
+        let json = record.iter()
+            .map(|(key, value)| (key.clone(), JsonValue::String(value.value.clone())))
+            .collect();
+        let Some(mut res) = self.script.run(JsonValue::Object(json), self.state.clone()) else { return Vec::new() };
+        if let Some(state) = res.get_mut("state") {
+            self.state = state.take();
+        }
+        let Some(JsonValue::Array(records)) = res.get("records") else {
+            eprintln!("transform didn't return `records`-list: {res}");
+            return Vec::new()
+        };
+        records.iter().filter_map(|transformed| match transformed {
+            JsonValue::Object(transformed) => Some(transformed.iter()
+                .map(|(key, value)| {
+                    let postprocess = record.get(key).and_then(|raw| raw.postprocess.clone());
+                    (key.clone(), RawValue { value: json_value_to_string(value), postprocess })
+                })
+                .collect()),
+            _ => {
+                eprintln!("transform returned a record which isn't a map: {transformed}");
+                None
+            }
+        }).collect()
     }
 }
 
@@ -121,3 +422,240 @@ fn json_pointer_to_key(json_pointer: &String) -> String {
     assert_eq!(&json_pointer[..1], "/");
     json_pointer[1..].replace("/", "_").replace("~1", "/").replace("~0", "~")
 }
+
+fn unescape_json_pointer(segment: &str) -> String {
+    segment.replace("~1", "/").replace("~0", "~")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::NoopEscaper;
+    use crate::script::Execution;
+
+    fn mapper(mapping: &str) -> WideToWide {
+        WideToWide::new(toml::from_str(mapping).unwrap(), None, Arc::new(NoopEscaper))
+    }
+
+    #[test]
+    fn for_each_array() {
+        let mut mapper = mapper(r#"
+            for_each = "/inverter"
+            for_each_key = "inverter"
+            values.power = "/ch/0"
+        "#);
+        let rows = mapper.consume_value(serde_json::json!({
+            "inverter": [{ "ch": [1] }, { "ch": [2] }],
+        }));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["inverter"], "0");
+        assert_eq!(rows[0]["power"], "1");
+        assert_eq!(rows[1]["inverter"], "1");
+        assert_eq!(rows[1]["power"], "2");
+    }
+
+    #[test]
+    fn for_each_object() {
+        let mut mapper = mapper(r#"
+            for_each = ""
+            for_each_key = "device"
+            values.temperature = "/temp"
+        "#);
+        let rows = mapper.consume_value(serde_json::json!({
+            "bar": { "temp": 21.5 },
+            "foo": { "temp": 19 },
+        }));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["device"], "bar");
+        assert_eq!(rows[0]["temperature"], "21.5");
+        assert_eq!(rows[1]["device"], "foo");
+        assert_eq!(rows[1]["temperature"], "19");
+    }
+
+    #[test]
+    fn wildcard_pointer() {
+        let mut mapper = mapper(r#"
+            values."${1}_temperature" = "/devices/*/channels/1/values/TEMPERATURE"
+            values.fixed = "/devices/foo/channels/0/values/LOW_BAT"
+        "#);
+        let rows = mapper.consume_value(serde_json::json!({
+            "devices": {
+                "bar": { "channels": [{}, { "values": { "TEMPERATURE": 20.5 } }] },
+                "foo": { "channels": [{ "values": { "LOW_BAT": false } }, { "values": { "TEMPERATURE": 21 } }] },
+            },
+        }));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].len(), 3);
+        assert_eq!(rows[0]["bar_temperature"], "20.5");
+        assert_eq!(rows[0]["foo_temperature"], "21");
+        assert_eq!(rows[0]["fixed"], "false");
+    }
+
+    #[test]
+    fn direct_values_regex() {
+        let mut mapper = mapper(r#"
+            direct_values = { regex = "^/(?<device>[^/]*)/(temp|hum)$", name = "${device}_${2}" }
+            values = {}
+        "#);
+        let rows = mapper.consume_value(serde_json::json!({
+            "kitchen": { "temp": 20, "hum": 50, "co2": 400 },
+        }));
+        assert_eq!(rows[0].len(), 2);
+        assert_eq!(rows[0]["kitchen_temp"], "20");
+        assert_eq!(rows[0]["kitchen_hum"], "50");
+    }
+
+    #[test]
+    #[should_panic(expected = "flush.complete requires at least one value with a non-wildcard pointer")]
+    fn narrow_flush_complete_without_exact_pointers() {
+        let mapping = toml::from_str(r#"
+            flush.complete = true
+            values.power = "/*/power"
+        "#).unwrap();
+        NarrowToWide::new(mapping, None, Arc::new(NoopEscaper));
+    }
+
+    #[test]
+    fn narrow_flush() {
+        let mapping = toml::from_str(r#"
+            flush = { complete = true, trigger = "/updated" }
+            values.power = "/power"
+            values.energy = "/energy"
+        "#).unwrap();
+        let mut mapper = NarrowToWide::new(mapping, None, Arc::new(NoopEscaper));
+        assert!(mapper.consume_value(serde_json::json!({ "power": 1 })).is_empty());
+        let rows = mapper.consume_value(serde_json::json!({ "energy": 2 }));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["power"], "1");
+        assert_eq!(rows[0]["energy"], "2");
+        assert!(mapper.consume_value(serde_json::json!({ "power": 3 })).is_empty());
+        let rows = mapper.consume_value(serde_json::json!({ "updated": 0 }));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].len(), 1);
+        assert_eq!(rows[0]["power"], "3");
+        assert!(mapper.flush().is_empty());
+    }
+
+    #[test]
+    fn narrow_multiple_values() {
+        // mqtt metadata: device from the topic, value from the payload
+        let mapping = toml::from_str(r#"
+            flush.complete = true
+            values.device = "/_topic/1"
+            values.power = "/payload"
+        "#).unwrap();
+        let mut mapper = NarrowToWide::new(mapping, None, Arc::new(NoopEscaper));
+        let rows = mapper.consume_value(serde_json::json!({ "topic": "meter/garage", "_topic": ["meter", "garage"], "payload": 42 }));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["device"], "garage");
+        assert_eq!(rows[0]["power"], "42");
+        let rows = mapper.consume_value(serde_json::json!({ "topic": "meter/pv", "_topic": ["meter", "pv"], "payload": 7 }));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["device"], "pv");
+        assert_eq!(rows[0]["power"], "7");
+
+        // without flush.complete, an already buffered value emits the record
+        let mapping = toml::from_str(r#"
+            values.device = "/_topic/1"
+            values.power = "/payload"
+        "#).unwrap();
+        let mut mapper = NarrowToWide::new(mapping, None, Arc::new(NoopEscaper));
+        assert!(mapper.consume_value(serde_json::json!({ "_topic": ["meter", "garage"], "payload": 42 })).is_empty());
+        let rows = mapper.consume_value(serde_json::json!({ "_topic": ["meter", "pv"], "payload": 7 }));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["device"], "garage");
+        assert_eq!(mapper.flush()[0]["device"], "pv");
+    }
+
+    #[test]
+    fn narrow_to_narrow() {
+        let mapping = toml::from_str(r#"
+            narrow.device = "${1}"
+            values."${2}" = "/evcc~1*~1*"
+        "#).unwrap();
+        let mut mapper = NarrowToNarrow::new(mapping, None, Arc::new(NoopEscaper));
+        let rows = mapper.consume_value(serde_json::json!({ "evcc/site/pvPower": 42 }));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["device"], "site");
+        assert_eq!(rows[0]["measurement"], "pvPower");
+        assert_eq!(rows[0]["value"], "42");
+    }
+
+    struct QuoteEscaper;
+    impl BackendEscaper for QuoteEscaper {
+        fn escape_value(&self, value: String) -> String {
+            format!("'{}'", value.replace('\'', "''"))
+        }
+    }
+
+    fn transform(execute: fn(&str, JsonValue, JsonValue) -> Execution<JsonValue>) -> Option<TransformScript> {
+        Some(TransformScript::with_executor(String::new(), execute))
+    }
+
+    fn mapper_with_transform(transform: Option<TransformScript>) -> WideToWide {
+        let mapping = toml::from_str(r#"
+            values.power = "/power"
+        "#).unwrap();
+        WideToWide::new(mapping, transform, Arc::new(NoopEscaper))
+    }
+
+    #[test]
+    fn transform_unescaped_then_escape() {
+        let mut mapping: Mapping = toml::from_str(r#"
+            values.name = "/name"
+            values.power = "/power"
+        "#).unwrap();
+        mapping.values["power"].postprocess = Some(ValueScript::with_executor(String::new(), |_, value| Execution::Ok(format!("{value}::int"))));
+        // renames `name` to `device` and keeps `power`
+        let mut mapper = WideToWide::new(mapping, transform(|_, record, state| {
+            assert_eq!(record["name"], "O'Brien");
+            Execution::Ok(serde_json::json!({
+                "records": [{ "device": record["name"], "power": record["power"] }],
+                "state": state,
+            }))
+        }), Arc::new(QuoteEscaper));
+        let rows = mapper.consume_value(serde_json::json!({ "name": "O'Brien", "power": 42 }));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].len(), 2);
+        assert_eq!(rows[0]["device"], "'O''Brien'");
+        assert_eq!(rows[0]["power"], "'42'::int");
+    }
+
+    #[test]
+    fn transform_drop_and_split() {
+        // drops records without power, splits the others into one record per phase
+        let mut mapper = mapper_with_transform(transform(|_, record, state| {
+            let records = match record.get("power") {
+                None => Vec::new(),
+                Some(power) => vec![
+                    serde_json::json!({ "phase": 1, "power": power }),
+                    serde_json::json!({ "phase": 2, "power": power }),
+                ],
+            };
+            Execution::Ok(serde_json::json!({ "records": records, "state": state }))
+        }));
+        assert!(mapper.consume_value(serde_json::json!({ "energy": 1 })).is_empty());
+        let rows = mapper.consume_value(serde_json::json!({ "power": 5 }));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["phase"], "1");
+        assert_eq!(rows[0]["power"], "5");
+        assert_eq!(rows[1]["phase"], "2");
+        assert_eq!(rows[1]["power"], "5");
+    }
+
+    #[test]
+    fn transform_state_persists() {
+        // emits the difference to the previous power
+        let mut mapper = mapper_with_transform(transform(|_, record, state| {
+            let power: i64 = record["power"].as_str().unwrap().parse().unwrap();
+            let records = match state.get("last").and_then(JsonValue::as_i64) {
+                Some(last) => vec![serde_json::json!({ "delta": power - last })],
+                None => Vec::new(),
+            };
+            Execution::Ok(serde_json::json!({ "records": records, "state": { "last": power } }))
+        }));
+        assert!(mapper.consume_value(serde_json::json!({ "power": 5 })).is_empty());
+        assert_eq!(mapper.consume_value(serde_json::json!({ "power": 8 }))[0]["delta"], "3");
+        assert_eq!(mapper.consume_value(serde_json::json!({ "power": 6 }))[0]["delta"], "-2");
+    }
+}
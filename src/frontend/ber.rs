@@ -0,0 +1,217 @@
+//! BER encoding of the ASN.1 subset used by SNMP
+
+pub const INTEGER: u8 = 0x02;
+pub const OCTET_STRING: u8 = 0x04;
+pub const NULL: u8 = 0x05;
+pub const OBJECT_IDENTIFIER: u8 = 0x06;
+pub const SEQUENCE: u8 = 0x30;
+pub const IP_ADDRESS: u8 = 0x40;
+pub const COUNTER32: u8 = 0x41;
+pub const GAUGE32: u8 = 0x42;
+pub const TIME_TICKS: u8 = 0x43;
+pub const COUNTER64: u8 = 0x46;
+pub const NO_SUCH_OBJECT: u8 = 0x80;
+pub const NO_SUCH_INSTANCE: u8 = 0x81;
+pub const END_OF_MIB_VIEW: u8 = 0x82;
+
+pub type Oid = Vec<u32>;
+
+pub fn parse_oid(oid: &str) -> Result<Oid, String> {
+    let oid = oid.trim_start_matches('.');
+    let arcs: Oid = oid.split('.').map(|arc| arc.parse()).collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid OID {oid:?}"))?;
+    if arcs.len() < 2 || arcs[0] > 2 {
+        return Err(format!("invalid OID {oid:?}"));
+    }
+    Ok(arcs)
+}
+
+pub fn format_oid(oid: &[u32]) -> String {
+    oid.iter().map(|arc| arc.to_string()).collect::<Vec<_>>().join(".")
+}
+
+/// tag, length and content
+pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut res = vec![tag];
+    match content.len() {
+        len @ 0..=0x7f => res.push(len as u8),
+        len => {
+            let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|&b| b == 0).collect();
+            res.push(0x80 | bytes.len() as u8);
+            res.extend(bytes);
+        }
+    }
+    res.extend_from_slice(content);
+    res
+}
+
+pub fn sequence(tag: u8, parts: &[Vec<u8>]) -> Vec<u8> {
+    tlv(tag, &parts.concat())
+}
+
+pub fn integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // minimal two's complement representation
+    let mut start = 0;
+    while start < 7 && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0) || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0)) {
+        start += 1;
+    }
+    tlv(INTEGER, &bytes[start..])
+}
+
+/// Counter32, Gauge32, TimeTicks or Counter64
+#[cfg(test)]
+pub fn unsigned(tag: u8, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(7);
+    // a leading 1-bit would make it negative
+    match bytes[start] & 0x80 {
+        0 => tlv(tag, &bytes[start..]),
+        _ => tlv(tag, &[&[0], &bytes[start..]].concat()),
+    }
+}
+
+pub fn octets(value: &[u8]) -> Vec<u8> {
+    tlv(OCTET_STRING, value)
+}
+
+pub fn oid(oid: &[u32]) -> Vec<u8> {
+    let mut content = Vec::new();
+    let first = oid.first().copied().unwrap_or(0) * 40 + oid.get(1).copied().unwrap_or(0);
+    for &arc in std::iter::once(&first).chain(oid.iter().skip(2)) {
+        let mut bytes = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            bytes.push(0x80 | (rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        content.extend(bytes.iter().rev());
+    }
+    tlv(OBJECT_IDENTIFIER, &content)
+}
+
+pub fn decode_integer(content: &[u8]) -> Result<i64, String> {
+    if content.is_empty() || content.len() > 8 {
+        return Err(format!("invalid integer {content:02x?}"));
+    }
+    let sign = if content[0] & 0x80 != 0 { 0xff } else { 0 };
+    let mut bytes = [sign; 8];
+    bytes[8 - content.len()..].copy_from_slice(content);
+    Ok(i64::from_be_bytes(bytes))
+}
+
+pub fn decode_unsigned(content: &[u8]) -> Result<u64, String> {
+    let content = match content {
+        [0, rest @ ..] => rest,
+        content => content,
+    };
+    if content.len() > 8 {
+        return Err(format!("invalid unsigned integer {content:02x?}"));
+    }
+    Ok(content.iter().fold(0, |acc, &b| acc << 8 | b as u64))
+}
+
+pub fn decode_oid(content: &[u8]) -> Result<Oid, String> {
+    let mut arcs = Vec::new();
+    let mut arc: u32 = 0;
+    for &b in content {
+        arc = arc.checked_mul(128).ok_or_else(|| format!("invalid OID {content:02x?}"))? | (b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    Ok(arcs)
+}
+
+/// Reads consecutive TLVs
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    /// next tag and its content
+    pub fn read(&mut self) -> Result<(u8, &'a [u8]), String> {
+        let truncated = || "truncated BER data".to_string();
+        let tag = *self.data.get(self.pos).ok_or_else(truncated)?;
+        let first = *self.data.get(self.pos + 1).ok_or_else(truncated)?;
+        self.pos += 2;
+        let len = match first {
+            len @ 0..=0x7f => len as usize,
+            0x80 => return Err("indefinite BER length".to_string()),
+            long => {
+                let bytes = self.data.get(self.pos..self.pos + (long & 0x7f) as usize).ok_or_else(truncated)?;
+                self.pos += bytes.len();
+                if bytes.len() > 4 {
+                    return Err(format!("BER length of {} bytes", bytes.len()));
+                }
+                bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize)
+            }
+        };
+        let content = self.data.get(self.pos..self.pos + len).ok_or_else(truncated)?;
+        self.pos += len;
+        Ok((tag, content))
+    }
+
+    pub fn expect(&mut self, expected: u8) -> Result<&'a [u8], String> {
+        match self.read()? {
+            (tag, content) if tag == expected => Ok(content),
+            (tag, _) => Err(format!("expected BER tag {expected:02x}, got {tag:02x}")),
+        }
+    }
+
+    pub fn sequence(&mut self, tag: u8) -> Result<Reader<'a>, String> {
+        self.expect(tag).map(Reader::new)
+    }
+
+    pub fn integer(&mut self) -> Result<i64, String> {
+        decode_integer(self.expect(INTEGER)?)
+    }
+
+    pub fn octets(&mut self) -> Result<&'a [u8], String> {
+        self.expect(OCTET_STRING)
+    }
+
+    pub fn oid(&mut self) -> Result<Oid, String> {
+        decode_oid(self.expect(OBJECT_IDENTIFIER)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        for value in [0, 127, 128, -1, -128, -129, 65535, i64::MIN, i64::MAX] {
+            assert_eq!(Reader::new(&integer(value)).integer().unwrap(), value, "{value}");
+        }
+        assert_eq!(integer(128), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(unsigned(COUNTER32, 0xffffffff), [0x41, 0x05, 0x00, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(decode_unsigned(&unsigned(COUNTER64, u64::MAX)[2..]).unwrap(), u64::MAX);
+
+        let sys_descr = parse_oid("1.3.6.1.2.1.1.1.0").unwrap();
+        assert_eq!(oid(&sys_descr), [0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x01, 0x00]);
+        let large = parse_oid(".1.3.6.1.4.1.2021.4294967295").unwrap();
+        assert_eq!(Reader::new(&oid(&large)).oid().unwrap(), large);
+
+        let long = octets(&[0x42; 300]);
+        assert_eq!(long[..4], [0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(Reader::new(&long).octets().unwrap(), &[0x42; 300]);
+    }
+}
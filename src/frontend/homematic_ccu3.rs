@@ -1,16 +1,22 @@
-use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::error::Error;
-use std::sync::Arc;
-use std::time::Duration;
-use futures::{future, FutureExt, Stream, StreamExt, TryFutureExt};
-use futures::stream::FuturesUnordered;
+use std::fmt;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use futures::{Stream, StreamExt};
+use futures::stream::BoxStream;
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
-use serde_json::{json, Value};
-use crate::config;
-use crate::config::{BasicAuth, HomematicCcu3Config, ValueKind};
+use serde_json::{json, Map, Value};
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
+use crate::config::{BasicAuth, HomematicCallback, HomematicCcu3Config};
+use crate::frontend::xmlrpc;
 
 #[derive(Default)]
 struct ParametersetToLoad {
@@ -18,155 +24,585 @@ struct ParametersetToLoad {
     load_master: bool,
 }
 
-pub fn stream<T: Borrow<config::Value>>(config: HomematicCcu3Config, accessed_values: impl Iterator<Item = T>) -> impl Stream<Item = Value> + 'static {
+/// data besides devices, exposed under its own top-level key
+#[derive(Default)]
+struct ExtrasToLoad {
+    /// `/_sysvars/<name>`
+    sysvars: bool,
+    /// `/_programs/<name>`
+    programs: bool,
+    /// `/_rooms/<name>` and `/<device>/channels/<n>/rooms`
+    rooms: bool,
+    /// `/_functions/<name>` and `/<device>/channels/<n>/functions`
+    functions: bool,
+    /// `/_service_messages`
+    service_messages: bool,
+}
+
+/// HomeMatic script writing a line per active service message with the tab-separated channel
+/// address, type, timestamp and device name, which is last as it may contain any character
+const SERVICE_MESSAGES_SCRIPT: &str = r#"
+string id;
+foreach(id, dom.GetObject(ID_SERVICES).EnumUsedIDs()) {
+  object sm = dom.GetObject(id);
+  if (sm.AlState() == asOncoming) {
+    object trigger = dom.GetObject(sm.AlTriggerDP());
+    object channel = dom.GetObject(trigger.Channel());
+    object device = dom.GetObject(channel.Device());
+    WriteLine(channel.Address() # "\t" # trigger.HssType() # "\t" # sm.Timestamp() # "\t" # device.Name());
+  }
+}
+"#;
+
+/// `accessed_pointers` is `None` if all data may be accessed, e.g. by `direct_values = "all"`
+pub fn stream(config: HomematicCcu3Config, accessed_pointers: Option<Vec<String>>) -> impl Stream<Item = Value> + 'static {
     // check which device's channel's parameterSets to load
-    let mut parametersets_to_load: HashMap<(String, usize), ParametersetToLoad> = HashMap::new();
-    for value in accessed_values {
-        let pointer = match &value.borrow().kind {
-            ValueKind::Pointer { pointer } => pointer,
-            ValueKind::Constant { .. } => continue,
-        };
+    // `None` matches all devices / channels (segments containing a `*`-wildcard)
+    let mut parametersets_to_load: HashMap<(Option<String>, Option<usize>), ParametersetToLoad> = HashMap::new();
+    let mut extras = ExtrasToLoad::default();
+    let Some(accessed_pointers) = accessed_pointers else {
+        parametersets_to_load.insert((None, None), ParametersetToLoad { load_values: true, load_master: true });
+        let extras = ExtrasToLoad { sysvars: true, programs: true, rooms: true, functions: true, service_messages: true };
+        return start(config, parametersets_to_load, extras);
+    };
+    for pointer in accessed_pointers {
         let mut parts = pointer.split('/').skip(1).map(|x| x.replace("~1", "/").replace("~0", "~"));
         let Some(device_name) = parts.next() else { continue };
+        match device_name.as_str() {
+            "_sysvars" => { extras.sysvars = true; continue },
+            "_programs" => { extras.programs = true; continue },
+            "_rooms" => { extras.rooms = true; continue },
+            "_functions" => { extras.functions = true; continue },
+            "_service_messages" => { extras.service_messages = true; continue },
+            _ => (),
+        }
+        let device_name = Some(device_name).filter(|name| !name.contains('*'));
         let Some("channels") = parts.next().as_deref() else { continue };
-        let Some(channel) = parts.next().and_then(|c| c.parse().ok()) else { continue };
+        let channel = match parts.next() {
+            Some(channel) if channel.contains('*') => None,
+            Some(channel) => match channel.parse() {
+                Ok(channel) => Some(channel),
+                Err(_) => continue,
+            },
+            None => continue,
+        };
         let entry = parametersets_to_load.entry((device_name, channel)).or_default();
         match parts.next().as_deref() {
             Some("values") => entry.load_values = true,
             Some("master") => entry.load_master = true,
+            Some("rooms") => extras.rooms = true,
+            Some("functions") => extras.functions = true,
             _ => continue,
         }
     }
 
-    let client = Arc::new(JsonRpc::new(&config));
-    let config = Arc::new(config);
-    let parametersets_to_load = Arc::new(parametersets_to_load);
+    start(config, parametersets_to_load, extras)
+}
+
+fn start(config: HomematicCcu3Config, parametersets_to_load: HashMap<(Option<String>, Option<usize>), ParametersetToLoad>, extras: ExtrasToLoad) -> BoxStream<'static, Value> {
+    let ccu = Arc::new(Ccu::new(config, parametersets_to_load, extras));
+    match &ccu.config.callback {
+        Some(callback) => callback_stream(Arc::clone(&ccu), callback.clone()).boxed(),
+        None => poll_stream(ccu).boxed(),
+    }
+}
+
+fn poll_stream(ccu: Arc<Ccu>) -> impl Stream<Item = Value> + 'static {
     futures::stream::unfold(0, move |mut iteration| {
-        let client = Arc::clone(&client);
-        let config = Arc::clone(&config);
-        let parametersets_to_load = Arc::clone(&parametersets_to_load);
+        let ccu = Arc::clone(&ccu);
         async move {
-            'repeat: loop {
-                if iteration != 0 {
-                    tokio::time::sleep(Duration::from_secs(config.frequency_secs as u64)).await;
+            let mut failures = 0;
+            loop {
+                if failures != 0 {
+                    tokio::time::sleep(ccu.backoff(failures)).await;
+                } else if iteration != 0 {
+                    tokio::time::sleep(Duration::from_secs(ccu.config.frequency_secs as u64)).await;
                 }
                 iteration += 1;
-
-                macro_rules! jsonrpc {
-                    ($method:literal, $params:expr) => {
-                        match client.jsonrpc($method, $params).await {
-                            Ok(val) => val,
-                            Err(e) => {
-                                eprintln!("homematic-ccu3: Error executing {}: {e:?}", $method);
-                                continue
-                            }
-                        }
+                match ccu.poll().await {
+                    Ok(res) => break Some((res, iteration)),
+                    Err(e) => {
+                        failures += 1;
+                        eprintln!("homematic-ccu3: {e} - retry in {}s", ccu.backoff(failures).as_secs());
                     }
                 }
+            }
+        }
+    })
+}
 
-                // login
-                let session_id: String = jsonrpc!("Session.login", json!({
-                    "username": &config.username,
-                    "password": &config.password,
-                }));
-
-                // get device list
-                let mut devices: Vec<Value> = jsonrpc!("Device.listAllDetail", json!({
-                    "_session_id_": session_id,
-                }));
-
-                // fetch relevant parameterSets
-                let mut channel_futures = FuturesUnordered::new();
-                for device in &mut devices {
-                    let interface = device["interface"].as_str().unwrap().to_string();
-                    let name = device["name"].as_str().unwrap().to_string();
-                    let client = Arc::new(&client);
-                    let session_id = session_id.clone();
-
-                    for (i, channel) in device["channels"].as_array_mut().unwrap().into_iter().enumerate() {
-                        let channel = channel.as_object_mut().unwrap();
-                        channel.insert("values".to_string(), json!({"NOT_LOADED": "this object hasn't been loaded - access it to load it"}));
-
-                        let Some(parametersets_to_load) = parametersets_to_load.get(&(name.clone(), i)) else {
-                            channel.insert("values".to_string(), json!({"NOT_LOADED": "this object hasn't been loaded - access it to load it"}));
-                            channel.insert("master".to_string(), json!({"NOT_LOADED": "this object hasn't been loaded - access it to load it"}));
-                            continue
-                        };
-                        let values = if parametersets_to_load.load_values {
-                            client.jsonrpc::<_, Value>("Interface.getParamset", json!({
-                                "_session_id_": session_id,
-                                "address": channel["address"],
-                                "interface": interface,
-                                "paramsetKey": "VALUES",
-                            })).map_err(|e| format!("{e:?}")).map(Some).boxed()
-                        } else {
-                            channel.insert("values".to_string(), json!({"NOT_LOADED": "this object hasn't been loaded - access it to load it"}));
-                            futures::future::ready(None).boxed()
-                        };
-                        let master = if parametersets_to_load.load_master {
-                            client.jsonrpc::<_, Value>("Interface.getParamset", json!({
-                                "_session_id_": session_id,
-                                "address": channel["address"],
-                                "interface": interface,
-                                "paramsetKey": "MASTER",
-                            })).map_err(|e| format!("{e:?}")).map(Some).boxed()
-                        } else {
-                            channel.insert("master".to_string(), json!({"NOT_LOADED": "this object hasn't been loaded - access it to load it"}));
-                            futures::future::ready(None).boxed()
-                        };
+/// Registers a callback server at the CCU's interfaces, emitting each pushed value change as
+/// `{ device: { "channels": { "<index>": { "values": { key: value } } } } }`.
+///
+/// After each registration all values are polled once and emitted as one event per datapoint,
+/// as the events are narrow. If the registration fails, values are polled every `frequency_secs`
+/// until it succeeds again.
+fn callback_stream(ccu: Arc<Ccu>, callback: HomematicCallback) -> impl Stream<Item = Value> + 'static {
+    let (tx, rx) = mpsc::channel(100);
+    let addresses: Addresses = Arc::new(StdMutex::new(HashMap::new()));
 
-                        channel_futures.push(future::join(master, values)
-                            .map(|(m, v)| {
-                                match m {
-                                    Some(Ok(m)) => { channel.insert("master".to_string(), m); },
-                                    Some(Err(e)) => {
-                                        eprintln!("homematic-ccu3: Error executing Interface.getParamset MASTER: {e}");
-                                        channel.insert("master".to_string(), json!({ "NOT_LOADED": "Error loading" }));
-                                        return Err(())
-                                    },
-                                    None => (),
-                                }
-                                match v {
-                                    Some(Ok(v)) => { channel.insert("values".to_string(), v); },
-                                    Some(Err(e)) => {
-                                        eprintln!("homematic-ccu3: Error executing Interface.getParamset VALUES: {e}");
-                                        channel.insert("master".to_string(), json!({ "NOT_LOADED": "Error loading" }));
-                                        return Err(())
-                                    },
-                                    None => (),
-                                }
-                                Ok(())
-                            }));
+    let addresses2 = Arc::clone(&addresses);
+    let ccu2 = Arc::clone(&ccu);
+    let tx2 = tx.clone();
+    let make_service = make_service_fn(move |_conn| {
+        let ccu = Arc::clone(&ccu2);
+        let addresses = Arc::clone(&addresses2);
+        let tx = tx2.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle_callback(Arc::clone(&ccu), Arc::clone(&addresses), tx.clone(), req)
+            }))
+        }
+    });
+    let server = Server::try_bind(&callback.listen)
+        .unwrap_or_else(|e| panic!("homematic-ccu3: can't listen on {}: {e}", callback.listen))
+        .serve(make_service);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            eprintln!("homematic-ccu3: error in callback server: {e:?}");
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let registered = ccu.register_callback(&callback, &addresses).await;
+            if let Err(e) = &registered {
+                eprintln!("homematic-ccu3: {e} - falling back to polling");
+            }
+            match ccu.poll().await {
+                Ok(res) => for event in datapoints(res) {
+                    if tx.send(event).await.is_err() {
+                        return
                     }
+                },
+                Err(e) => eprintln!("homematic-ccu3: {e}"),
+            }
+            let wait = match registered {
+                Ok(()) => callback.reinit_secs,
+                Err(_) => ccu.config.frequency_secs as u64,
+            };
+            tokio::time::sleep(Duration::from_secs(wait)).await;
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Splits a polled value into one event per datapoint like the ones of `handle_callback`;
+/// arrays become objects keyed by index (e.g. `channels`), so json-pointers stay valid.
+fn datapoints(value: Value) -> Vec<Value> {
+    let children: Vec<(String, Value)> = match value {
+        Value::Object(object) => object.into_iter().collect(),
+        Value::Array(array) => array.into_iter().enumerate().map(|(i, value)| (i.to_string(), value)).collect(),
+        value => return vec![value],
+    };
+    children.into_iter().flat_map(|(key, child)| {
+        datapoints(child).into_iter().map(move |datapoint| json!({ key.clone(): datapoint }))
+    }).collect()
+}
+
+/// channel address to device name and channel index
+type Addresses = Arc<StdMutex<HashMap<String, (String, usize)>>>;
+
+async fn handle_callback(ccu: Arc<Ccu>, addresses: Addresses, tx: mpsc::Sender<Value>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+        Err(e) => {
+            eprintln!("homematic-ccu3: error reading callback: {e}");
+            return Ok(Response::builder().status(StatusCode::BAD_REQUEST).body(Body::empty()).unwrap());
+        }
+    };
+    let (method, params) = match xmlrpc::parse_method_call(&body) {
+        Ok(call) => call,
+        Err(e) => {
+            eprintln!("homematic-ccu3: invalid callback: {e}");
+            return Ok(Response::builder().status(StatusCode::BAD_REQUEST).body(Body::empty()).unwrap());
+        }
+    };
+    let result = match method.as_str() {
+        "system.multicall" => {
+            let calls = params.into_iter().next().unwrap_or_default();
+            let mut results = Vec::new();
+            for call in calls.as_array().into_iter().flatten() {
+                let method = call["methodName"].as_str().unwrap_or_default();
+                let params = call["params"].as_array().cloned().unwrap_or_default();
+                results.push(json!([handle_callback_method(&ccu, &addresses, &tx, method, params).await]));
+            }
+            Value::Array(results)
+        }
+        method => handle_callback_method(&ccu, &addresses, &tx, method, params).await,
+    };
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/xml")
+        .body(Body::from(xmlrpc::method_response(&result)))
+        .unwrap())
+}
+
+async fn handle_callback_method(ccu: &Ccu, addresses: &Addresses, tx: &mpsc::Sender<Value>, method: &str, params: Vec<Value>) -> Value {
+    match method {
+        "event" => {
+            let [_interface_id, address, key, value] = <[Value; 4]>::try_from(params).unwrap_or_default();
+            let (Some(address), Some(key)) = (address.as_str(), key.as_str()) else { return json!("") };
+            let Some((name, channel)) = addresses.lock().unwrap().get(address).cloned() else {
+                return json!("")
+            };
+            if ccu.parametersets_to_load(&name, channel).load_values {
+                let event = json!({ name: { "channels": { channel.to_string(): { "values": { key: value } } } } });
+                // the pipeline is only closed during shutdown
+                let _ = tx.send(event).await;
+            }
+            json!("")
+        }
+        "listDevices" => json!([]),
+        "system.listMethods" => json!(["system.multicall", "system.listMethods", "event", "listDevices", "newDevices", "deleteDevices"]),
+        _ => json!(""),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Paramset {
+    Values,
+    Master,
+}
+impl Paramset {
+    fn key(self) -> &'static str {
+        match self {
+            Paramset::Values => "VALUES",
+            Paramset::Master => "MASTER",
+        }
+    }
+    /// field of the channel the paramset is inserted into
+    fn field(self) -> &'static str {
+        match self {
+            Paramset::Values => "values",
+            Paramset::Master => "master",
+        }
+    }
+}
+
+const NOT_LOADED: &str = "this object hasn't been loaded - access it to load it";
+
+/// paramset of a channel, identified by device name and channel index
+type ParamsetId = (String, usize, Paramset);
+
+/// consecutive failures of a paramset request, which isn't retried before `retry_at`
+struct Failure {
+    count: u32,
+    retry_at: Instant,
+    error: String,
+}
+
+/// Connection to a CCU, keeping the session and device list between polls
+struct Ccu {
+    client: JsonRpc,
+    config: HomematicCcu3Config,
+    parametersets_to_load: HashMap<(Option<String>, Option<usize>), ParametersetToLoad>,
+    extras: ExtrasToLoad,
+    session_id: Mutex<Option<String>>,
+    devices: Mutex<Option<DeviceList>>,
+    failures: StdMutex<HashMap<ParamsetId, Failure>>,
+    /// number of failed requests per device name
+    device_errors: StdMutex<HashMap<String, u64>>,
+}
+
+struct DeviceList {
+    loaded: Instant,
+    /// sorted ids of all devices, to detect added or removed devices
+    ids: Vec<String>,
+    devices: Vec<Value>,
+}
+
+impl Ccu {
+    fn new(config: HomematicCcu3Config, parametersets_to_load: HashMap<(Option<String>, Option<usize>), ParametersetToLoad>, extras: ExtrasToLoad) -> Self {
+        Ccu {
+            client: JsonRpc::new(&config),
+            config,
+            parametersets_to_load,
+            extras,
+            session_id: Mutex::new(None),
+            devices: Mutex::new(None),
+            failures: StdMutex::new(HashMap::new()),
+            device_errors: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// delay before retrying after `failures` consecutive failures
+    fn backoff(&self, failures: u32) -> Duration {
+        let base = (self.config.frequency_secs as u64).max(1);
+        let secs = base.saturating_mul(1 << failures.saturating_sub(1).min(20));
+        Duration::from_secs(secs.min(self.config.max_backoff_secs.max(base)))
+    }
+
+    /// parametersets of the channel accessed by any pointer
+    fn parametersets_to_load(&self, name: &str, channel: usize) -> ParametersetToLoad {
+        let name = Some(name.to_string());
+        [(name.clone(), Some(channel)), (name, None), (None, Some(channel)), (None, None)]
+            .iter()
+            .filter_map(|key| self.parametersets_to_load.get(key))
+            .fold(ParametersetToLoad::default(), |acc, p| ParametersetToLoad {
+                load_values: acc.load_values || p.load_values,
+                load_master: acc.load_master || p.load_master,
+            })
+    }
+
+    /// register the callback server at all interfaces, updating the channel addresses
+    async fn register_callback(&self, callback: &HomematicCallback, addresses: &Addresses) -> Result<(), String> {
+        let devices = self.devices().await?;
+        let mut map = HashMap::new();
+        for device in &devices {
+            let name = device["name"].as_str().unwrap_or_default();
+            for (i, channel) in device["channels"].as_array().into_iter().flatten().enumerate() {
+                if let Some(address) = channel["address"].as_str() {
+                    map.insert(address.to_string(), (name.to_string(), i));
                 }
+            }
+        }
+        *addresses.lock().unwrap() = map;
+
+        for (interface, url) in &callback.interfaces {
+            let params = [json!(callback.url), json!(format!("iot2db-{interface}"))];
+            let mut req = self.client.client.post(url)
+                .header(CONTENT_TYPE, "text/xml")
+                .body(xmlrpc::method_call("init", &params));
+            if !self.config.username.is_empty() {
+                req = req.basic_auth(&self.config.username, Some(&self.config.password));
+            }
+            let res = async { req.send().await?.error_for_status()?.text().await }.await
+                .map_err(|e| format!("error registering callback at {interface}: {e}"))?;
+            xmlrpc::parse_method_response(&res)
+                .map_err(|e| format!("error registering callback at {interface}: {e}"))?;
+        }
+        Ok(())
+    }
 
-                while let Some(res) = channel_futures.next().await {
-                    match res {
-                        Ok(()) => (),
-                        Err(()) => {
-                            eprintln!("homematic-ccu3: error fetching values - retry");
-                            continue 'repeat
-                        },
+    async fn login(&self) -> Result<String, String> {
+        let session_id: String = self.client.jsonrpc("Session.login", json!({
+            "username": &self.config.username,
+            "password": &self.config.password,
+        })).await.map_err(|e| format!("Error executing Session.login: {e:?}"))?;
+        *self.session_id.lock().await = Some(session_id.clone());
+        Ok(session_id)
+    }
+
+    /// Execute a method within the session, logging in if there is no session yet.
+    /// If the call is denied with an existing session, it may have expired, so the old session
+    /// is logged out and the call is retried once with a new session.
+    async fn call<Res: DeserializeOwned>(&self, method: &str, mut params: Value) -> Result<Res, String> {
+        let existing_session = self.session_id.lock().await.clone();
+        let fresh = existing_session.is_none();
+        let session_id = match existing_session {
+            Some(session_id) => session_id,
+            None => self.login().await?,
+        };
+        params["_session_id_"] = json!(session_id);
+        match self.client.jsonrpc(method, &params).await {
+            Ok(res) => Ok(res),
+            Err(e) if fresh || !is_access_denied(&*e) => Err(format!("Error executing {method}: {e:?}")),
+            Err(_) => {
+                let renewed = {
+                    let mut current = self.session_id.lock().await;
+                    // another request may have renewed the session already
+                    if current.as_deref() == Some(session_id.as_str()) {
+                        *current = None;
                     }
-                }
-                drop(channel_futures);
+                    current.clone()
+                };
+                let session_id = match renewed {
+                    Some(session_id) => session_id,
+                    None => {
+                        // fails if the session expired, but frees it on the CCU otherwise
+                        let _: Result<Value, _> = self.client.jsonrpc("Session.logout", json!({ "_session_id_": session_id })).await;
+                        self.login().await?
+                    }
+                };
+                params["_session_id_"] = json!(session_id);
+                self.client.jsonrpc(method, &params).await
+                    .map_err(|e| format!("Error executing {method}: {e:?}"))
+            }
+        }
+    }
+
+    /// cached device list, reloaded if it's too old or devices were added or removed
+    async fn devices(&self) -> Result<Vec<Value>, String> {
+        let mut cache = self.devices.lock().await;
+        let max_age = Duration::from_secs(self.config.device_list_refresh_secs);
+        if let Some(cached) = cache.as_ref().filter(|cached| cached.loaded.elapsed() < max_age) {
+            let mut ids: Vec<String> = self.call("Device.listAll", json!({})).await?;
+            ids.sort();
+            if ids == cached.ids {
+                return Ok(cached.devices.clone());
+            }
+        }
+        let devices: Vec<Value> = self.call("Device.listAllDetail", json!({})).await?;
+        let mut ids: Vec<String> = devices.iter()
+            .filter_map(|device| device["id"].as_str().map(str::to_string))
+            .collect();
+        ids.sort();
+        *cache = Some(DeviceList { loaded: Instant::now(), ids, devices: devices.clone() });
+        Ok(devices)
+    }
 
-                let devices = devices.into_iter()
-                    .map(|d| (d["name"].as_str().unwrap().to_owned(), d))
-                    .collect();
+    async fn poll(&self) -> Result<Value, String> {
+        let mut devices = self.devices().await?;
 
-                // logout
-                let _: Value = jsonrpc!("Session.logout", json!({
-                    "_session_id_": session_id,
-                }));
+        // collect relevant parameterSets
+        let mut requests = Vec::new();
+        for (device_index, device) in devices.iter_mut().enumerate() {
+            let interface = device["interface"].as_str().unwrap().to_string();
+            let name = device["name"].as_str().unwrap().to_string();
 
-                let res = Value::Object(devices);
-                break Some((res, iteration))
+            for (i, channel) in device["channels"].as_array_mut().unwrap().iter_mut().enumerate() {
+                let channel = channel.as_object_mut().unwrap();
+                channel.insert("values".to_string(), json!({"NOT_LOADED": NOT_LOADED}));
+                channel.insert("master".to_string(), json!({"NOT_LOADED": NOT_LOADED}));
+
+                let parametersets_to_load = self.parametersets_to_load(&name, i);
+                let keys = [(Paramset::Values, parametersets_to_load.load_values), (Paramset::Master, parametersets_to_load.load_master)];
+                for (paramset, load) in keys {
+                    if !load {
+                        continue;
+                    }
+                    // channels which failed recently are reported as erroneous until their retry
+                    let id = (name.clone(), i, paramset);
+                    if let Some(failure) = self.failures.lock().unwrap().get(&id).filter(|f| f.retry_at > Instant::now()) {
+                        channel.insert(paramset.field().to_string(), json!({"ERROR": failure.error}));
+                        continue;
+                    }
+                    requests.push((device_index, id, json!({
+                        "address": channel["address"],
+                        "interface": interface,
+                        "paramsetKey": paramset.key(),
+                    })));
+                }
             }
         }
+
+        // fetch them with limited concurrency
+        let results: Vec<_> = futures::stream::iter(requests)
+            .map(|(device_index, id, params)| async move {
+                let res = self.call::<Value>("Interface.getParamset", params).await;
+                (device_index, id, res)
+            })
+            .buffer_unordered(self.config.paramset_concurrency.max(1))
+            .collect().await;
+        // a failed channel doesn't affect the others; its values are missing
+        for (device_index, id, res) in results {
+            let (name, channel_index, paramset) = &id;
+            let values = match res {
+                Ok(values) => {
+                    self.failures.lock().unwrap().remove(&id);
+                    values
+                }
+                Err(e) => {
+                    let error = format!("error fetching {} paramset of {name}:{channel_index}: {e}", paramset.key());
+                    *self.device_errors.lock().unwrap().entry(name.clone()).or_default() += 1;
+                    let mut failures = self.failures.lock().unwrap();
+                    let count = failures.get(&id).map_or(0, |f| f.count) + 1;
+                    let backoff = self.backoff(count);
+                    eprintln!("homematic-ccu3: {error} - retry in {}s", backoff.as_secs());
+                    failures.insert(id.clone(), Failure { count, retry_at: Instant::now() + backoff, error: error.clone() });
+                    json!({"ERROR": error})
+                }
+            };
+            devices[device_index]["channels"][*channel_index][paramset.field()] = values;
+        }
+        let device_errors = self.device_errors.lock().unwrap().clone();
+        for device in &mut devices {
+            let errors = device["name"].as_str().and_then(|name| device_errors.get(name)).copied().unwrap_or(0);
+            device["errorCount"] = json!(errors);
+        }
+
+        // like paramsets, failing extras don't affect the device data; channels lack `rooms` /
+        // `functions` and the extra's key contains the error
+        let rooms = match self.extras.rooms {
+            true => Some(self.call::<Vec<Value>>("Room.getAll", json!({})).await),
+            false => None,
+        };
+        let functions = match self.extras.functions {
+            true => Some(self.call::<Vec<Value>>("Subsection.getAll", json!({})).await),
+            false => None,
+        };
+        if let Some(Ok(rooms)) = &rooms {
+            assign_channels(&mut devices, rooms, "rooms");
+        }
+        if let Some(Ok(functions)) = &functions {
+            assign_channels(&mut devices, functions, "functions");
+        }
+
+        let mut res: Map<String, Value> = devices.into_iter()
+            .map(|d| (d["name"].as_str().unwrap().to_owned(), d))
+            .collect();
+        if self.extras.sysvars {
+            res.insert("_sysvars".to_string(), extra("system variables", self.get_all_by_name("SysVar.getAll").await));
+        }
+        if self.extras.programs {
+            res.insert("_programs".to_string(), extra("programs", self.get_all_by_name("Program.getAll").await));
+        }
+        if let Some(rooms) = rooms {
+            res.insert("_rooms".to_string(), extra("rooms", rooms.map(by_name)));
+        }
+        if let Some(functions) = functions {
+            res.insert("_functions".to_string(), extra("functions", functions.map(by_name)));
+        }
+        if self.extras.service_messages {
+            let output = self.call::<String>("ReGa.runScript", json!({ "script": SERVICE_MESSAGES_SCRIPT })).await;
+            res.insert("_service_messages".to_string(), extra("service messages", output.map(|output| service_messages(&output))));
+        }
+        Ok(Value::Object(res))
+    }
+
+    /// objects returned by a `*.getAll`-method keyed by their name
+    async fn get_all_by_name(&self, method: &str) -> Result<Value, String> {
+        let objects: Vec<Value> = self.call(method, json!({})).await?;
+        Ok(by_name(objects))
+    }
+}
+
+/// the value of an extra or `{"ERROR": ...}` if fetching it failed
+fn extra(name: &str, res: Result<Value, String>) -> Value {
+    res.unwrap_or_else(|e| {
+        let error = format!("error fetching {name}: {e}");
+        eprintln!("homematic-ccu3: {error}");
+        json!({"ERROR": error})
     })
 }
 
+fn by_name(objects: Vec<Value>) -> Value {
+    Value::Object(objects.into_iter()
+        .map(|object| (object["name"].as_str().unwrap_or_default().to_string(), object))
+        .collect())
+}
+
+/// add the names of the rooms / functions each channel is assigned to as `field`
+fn assign_channels(devices: &mut [Value], groups: &[Value], field: &str) {
+    let mut assigned: HashMap<&str, Vec<Value>> = HashMap::new();
+    for group in groups {
+        for channel_id in group["channelIds"].as_array().into_iter().flatten() {
+            if let Some(channel_id) = channel_id.as_str() {
+                assigned.entry(channel_id).or_default().push(group["name"].clone());
+            }
+        }
+    }
+    for device in devices {
+        for channel in device["channels"].as_array_mut().into_iter().flatten() {
+            let names = channel["id"].as_str().and_then(|id| assigned.get(id)).cloned().unwrap_or_default();
+            channel[field] = Value::Array(names);
+        }
+    }
+}
+
+/// service messages from the output of `SERVICE_MESSAGES_SCRIPT`
+fn service_messages(output: &str) -> Value {
+    output.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            let mut field = || fields.next().unwrap_or_default();
+            let (address, kind, timestamp, device) = (field(), field(), field(), field());
+            json!({ "device": device, "address": address, "type": kind, "timestamp": timestamp })
+        })
+        .collect()
+}
+
 struct JsonRpc {
     client: Client,
     basic_auth: Option<BasicAuth>,
@@ -185,6 +621,27 @@ struct JsonRpcResponse<T> {
     result: Option<T>,
     error: Option<Value>,
 }
+/// `error` of a JSON-RPC response
+struct JsonRpcError(Value);
+
+impl fmt::Display for JsonRpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "JSON-RPC Response Error: {}", self.0)
+    }
+}
+impl fmt::Debug for JsonRpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+impl Error for JsonRpcError {}
+
+/// the CCU denies access with an invalid or expired session
+fn is_access_denied(e: &(dyn Error + Send + Sync + 'static)) -> bool {
+    e.downcast_ref::<JsonRpcError>()
+        .and_then(|JsonRpcError(error)| error["message"].as_str())
+        .is_some_and(|message| message.starts_with("access denied"))
+}
 
 impl JsonRpc {
     fn new(config: &HomematicCcu3Config) -> Self {
@@ -198,7 +655,7 @@ impl JsonRpc {
         }
     }
 
-    async fn jsonrpc<Req: Serialize, Res: DeserializeOwned>(&self, method: &str, params: Req) -> Result<Res, Box<dyn Error>> {
+    async fn jsonrpc<Req: Serialize, Res: DeserializeOwned>(&self, method: &str, params: Req) -> Result<Res, Box<dyn Error + Send + Sync>> {
         let mut req = self.client.post(&self.url);
         if let Some(auth) = &self.basic_auth {
             req = req.basic_auth(&auth.username, auth.password.as_ref());
@@ -214,7 +671,7 @@ impl JsonRpc {
             return Err("JSON-RPC version is not 1.1".into());
         }
         if let Some(error) = json.error {
-            return Err(format!("JSON-RPC Response Error: {error}").into());
+            return Err(Box::new(JsonRpcError(error)));
         }
         if let Some(result) = json.result {
             return Ok(result);
@@ -225,3 +682,341 @@ impl JsonRpc {
 }
 
 
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::sync::Mutex as StdMutex;
+    use hyper::{Body, Request, Response, Server};
+    use hyper::service::{make_service_fn, service_fn};
+    use crate::backend::NoopEscaper;
+    use crate::config::Mapping;
+    use crate::data::{DataMapper, NarrowToWide};
+    use super::*;
+
+    /// mock of the CCU's JSON-RPC API, counting calls per method and accepting only the last session
+    #[derive(Default)]
+    struct MockCcu {
+        calls: StdMutex<HashMap<String, usize>>,
+        session_id: StdMutex<Option<String>>,
+        /// channel addresses whose paramsets can't be fetched
+        unreachable: StdMutex<Vec<String>>,
+        /// methods returning an error
+        failing: StdMutex<Vec<String>>,
+    }
+
+    impl MockCcu {
+        fn handle(&self, req: Value) -> Value {
+            let method = req["method"].as_str().unwrap().to_string();
+            let count = {
+                let mut calls = self.calls.lock().unwrap();
+                *calls.entry(method.clone()).or_default() += 1;
+                calls[&method]
+            };
+            let mut session_id = self.session_id.lock().unwrap();
+            if method == "Session.login" {
+                *session_id = Some(format!("session{count}"));
+                return json!({ "version": "1.1", "result": *session_id, "error": null });
+            }
+            if session_id.is_none() || req["params"]["_session_id_"] != json!(*session_id) {
+                return json!({ "version": "1.1", "result": null, "error": { "code": 400, "message": "access denied" } });
+            }
+            let address = req["params"]["address"].as_str().unwrap_or_default().to_string();
+            if self.failing.lock().unwrap().contains(&method) {
+                return json!({ "version": "1.1", "result": null, "error": { "code": 500, "message": "internal error" } });
+            }
+            if method == "Interface.getParamset" && self.unreachable.lock().unwrap().contains(&address) {
+                return json!({ "version": "1.1", "result": null, "error": { "code": 501, "message": "Unreachable" } });
+            }
+            let result = match method.as_str() {
+                "Device.listAll" => json!(["1001"]),
+                "Device.listAllDetail" => json!([{
+                    "id": "1001",
+                    "name": "Thermostat",
+                    "interface": "HmIP-RF",
+                    "channels": [{ "id": "1002", "address": "000A:0" }, { "id": "1003", "address": "000A:1" }],
+                }]),
+                "SysVar.getAll" => json!([{ "id": "950", "name": "Presence", "type": "BOOL", "value": "true" }]),
+                "Program.getAll" => json!([{ "id": "960", "name": "Heating", "isActive": true }]),
+                "Room.getAll" => json!([{ "id": "970", "name": "Kitchen", "channelIds": ["1003"] }]),
+                "Subsection.getAll" => json!([{ "id": "980", "name": "Climate", "channelIds": ["1002", "1003"] }]),
+                "ReGa.runScript" => json!("000A:0\tLOWBAT\t2024-01-01 12:00:00\tThermostat\n"),
+                "Interface.getParamset" => json!({ "ACTUAL_TEMPERATURE": 21.5, "ADDRESS": req["params"]["address"] }),
+                _ => Value::Null,
+            };
+            json!({ "version": "1.1", "result": result, "error": null })
+        }
+
+        fn calls(&self, method: &str) -> usize {
+            self.calls.lock().unwrap().get(method).copied().unwrap_or(0)
+        }
+    }
+
+    async fn mock_server(mock: Arc<MockCcu>) -> String {
+        let make_service = make_service_fn(move |_conn| {
+            let mock = Arc::clone(&mock);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let mock = Arc::clone(&mock);
+                    async move {
+                        let json_rpc = req.uri().path() == "/api/homematic.cgi";
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let res = match json_rpc {
+                            true => mock.handle(serde_json::from_slice(&body).unwrap()).to_string(),
+                            // XML-RPC of the interfaces
+                            false => {
+                                let (method, _params) = xmlrpc::parse_method_call(std::str::from_utf8(&body).unwrap()).unwrap();
+                                *mock.calls.lock().unwrap().entry(method).or_default() += 1;
+                                xmlrpc::method_response(&json!(""))
+                            }
+                        };
+                        Ok::<_, Infallible>(Response::new(Body::from(res)))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn session_and_device_list_reuse() {
+        let mock = Arc::new(MockCcu::default());
+        let url = mock_server(Arc::clone(&mock)).await;
+        let config: HomematicCcu3Config = toml::from_str(&format!(r#"
+            url = "{url}"
+            frequency_secs = 0
+            username = "iot2db"
+            password = ""
+        "#)).unwrap();
+        let mut stream = stream(config, Some(vec!["/Thermostat/channels/1/values/ACTUAL_TEMPERATURE".to_string()])).boxed();
+
+        let value = stream.next().await.unwrap();
+        assert_eq!(value.pointer("/Thermostat/channels/1/values/ACTUAL_TEMPERATURE"), Some(&json!(21.5)));
+        assert_eq!(value.pointer("/Thermostat/channels/0/values/NOT_LOADED"), Some(&json!(NOT_LOADED)));
+        stream.next().await.unwrap();
+        assert_eq!(mock.calls("Session.login"), 1);
+        assert_eq!(mock.calls("Device.listAllDetail"), 1);
+        assert_eq!(mock.calls("Device.listAll"), 1);
+        assert_eq!(mock.calls("Interface.getParamset"), 2);
+
+        // expired session is renewed
+        *mock.session_id.lock().unwrap() = None;
+        let value = stream.next().await.unwrap();
+        assert_eq!(value.pointer("/Thermostat/channels/1/values/ADDRESS"), Some(&json!("000A:1")));
+        assert_eq!(mock.calls("Session.login"), 2);
+        assert_eq!(mock.calls("Session.logout"), 1);
+        assert_eq!(mock.calls("Device.listAllDetail"), 1);
+    }
+
+    #[tokio::test]
+    async fn channel_errors_are_isolated() {
+        let mock = Arc::new(MockCcu::default());
+        mock.unreachable.lock().unwrap().push("000A:0".to_string());
+        let url = mock_server(Arc::clone(&mock)).await;
+        let config: HomematicCcu3Config = toml::from_str(&format!(r#"
+            url = "{url}"
+            frequency_secs = 0
+            username = "iot2db"
+            password = ""
+        "#)).unwrap();
+        let mut stream = stream(config, Some(vec!["/Thermostat/channels/*/values/ACTUAL_TEMPERATURE".to_string()])).boxed();
+
+        let value = stream.next().await.unwrap();
+        assert_eq!(value.pointer("/Thermostat/channels/1/values/ACTUAL_TEMPERATURE"), Some(&json!(21.5)));
+        assert_eq!(value.pointer("/Thermostat/channels/0/values/ACTUAL_TEMPERATURE"), None);
+        assert!(value.pointer("/Thermostat/channels/0/values/ERROR").is_some());
+        assert_eq!(value.pointer("/Thermostat/errorCount"), Some(&json!(1)));
+
+        // the failed channel isn't requested again before its backoff expired
+        let calls = mock.calls("Interface.getParamset");
+        let value = stream.next().await.unwrap();
+        assert_eq!(mock.calls("Interface.getParamset"), calls + 1);
+        assert!(value.pointer("/Thermostat/channels/0/values/ERROR").is_some());
+        assert_eq!(value.pointer("/Thermostat/errorCount"), Some(&json!(1)));
+        // other errors than access denied keep the session
+        assert_eq!(mock.calls("Session.login"), 1);
+        assert_eq!(mock.calls("Session.logout"), 0);
+    }
+
+    #[tokio::test]
+    async fn extras_only_when_referenced() {
+        let mock = Arc::new(MockCcu::default());
+        let url = mock_server(Arc::clone(&mock)).await;
+        let config: HomematicCcu3Config = toml::from_str(&format!(r#"
+            url = "{url}"
+            frequency_secs = 0
+            username = "iot2db"
+            password = ""
+        "#)).unwrap();
+        let pointers = ["/_sysvars/Presence/value", "/_service_messages/0/type", "/Thermostat/channels/1/rooms/0"];
+        let mut stream = stream(config, Some(pointers.iter().map(|p| p.to_string()).collect())).boxed();
+
+        let value = stream.next().await.unwrap();
+        assert_eq!(value.pointer("/_sysvars/Presence/value"), Some(&json!("true")));
+        assert_eq!(value.pointer("/_service_messages/0/device"), Some(&json!("Thermostat")));
+        assert_eq!(value.pointer("/_rooms/Kitchen/id"), Some(&json!("970")));
+        assert_eq!(value.pointer("/Thermostat/channels/1/rooms"), Some(&json!(["Kitchen"])));
+        assert_eq!(value.pointer("/Thermostat/channels/0/rooms"), Some(&json!([])));
+        assert_eq!(value.get("_programs"), None);
+        assert_eq!(value.get("_functions"), None);
+        assert_eq!(mock.calls("Program.getAll"), 0);
+        assert_eq!(mock.calls("Subsection.getAll"), 0);
+        assert_eq!(mock.calls("Interface.getParamset"), 0);
+        // used for `_rooms` and the channels' rooms
+        assert_eq!(mock.calls("Room.getAll"), 1);
+    }
+
+    #[tokio::test]
+    async fn extra_errors_are_isolated() {
+        let mock = Arc::new(MockCcu::default());
+        mock.failing.lock().unwrap().extend(["Room.getAll".to_string(), "ReGa.runScript".to_string()]);
+        let url = mock_server(Arc::clone(&mock)).await;
+        let config: HomematicCcu3Config = toml::from_str(&format!(r#"
+            url = "{url}"
+            frequency_secs = 0
+            username = "iot2db"
+            password = ""
+        "#)).unwrap();
+        let pointers = [
+            "/_sysvars/Presence/value", "/_service_messages/0/type", "/Thermostat/channels/1/rooms/0",
+            "/Thermostat/channels/1/functions/0", "/Thermostat/channels/1/values/ACTUAL_TEMPERATURE",
+        ];
+        let mut stream = stream(config, Some(pointers.iter().map(|p| p.to_string()).collect())).boxed();
+
+        let value = stream.next().await.unwrap();
+        assert_eq!(value.pointer("/Thermostat/channels/1/values/ACTUAL_TEMPERATURE"), Some(&json!(21.5)));
+        assert_eq!(value.pointer("/Thermostat/channels/1/functions"), Some(&json!(["Climate"])));
+        assert_eq!(value.pointer("/Thermostat/channels/1/rooms"), None);
+        assert!(value.pointer("/_rooms/ERROR").is_some());
+        assert!(value.pointer("/_service_messages/ERROR").is_some());
+        assert_eq!(value.pointer("/_sysvars/Presence/value"), Some(&json!("true")));
+        assert_eq!(value.pointer("/_functions/Climate/id"), Some(&json!("980")));
+    }
+
+    #[test]
+    fn service_messages_with_special_characters() {
+        let output = "000A:0\tLOWBAT\t2024-01-01 12:00:00\tFenster \"Bad\" \\ 1\n000B:1\tUNREACH\t2024-01-02 08:00:00\tTab\tName\n";
+        assert_eq!(service_messages(output), json!([
+            { "device": "Fenster \"Bad\" \\ 1", "address": "000A:0", "type": "LOWBAT", "timestamp": "2024-01-01 12:00:00" },
+            { "device": "Tab\tName", "address": "000B:1", "type": "UNREACH", "timestamp": "2024-01-02 08:00:00" },
+        ]));
+        assert_eq!(service_messages(""), json!([]));
+    }
+
+    #[tokio::test]
+    async fn direct_values_regex_loads_everything() {
+        let mock = Arc::new(MockCcu::default());
+        let url = mock_server(Arc::clone(&mock)).await;
+        let config: HomematicCcu3Config = toml::from_str(&format!(r#"
+            url = "{url}"
+            frequency_secs = 0
+            username = "iot2db"
+            password = ""
+        "#)).unwrap();
+        let mapping: Mapping = toml::from_str(r#"
+            direct_values = { regex = "^/([^/]*)/channels/([0-9]+)/values/ACTUAL_TEMPERATURE$", name = "${1}_${2}" }
+            values = {}
+        "#).unwrap();
+        assert_eq!(mapping.accessed_pointers(), None);
+        let mut stream = stream(config, mapping.accessed_pointers()).boxed();
+
+        let value = stream.next().await.unwrap();
+        assert_eq!(value.pointer("/Thermostat/channels/0/values/ACTUAL_TEMPERATURE"), Some(&json!(21.5)));
+        assert_eq!(value.pointer("/Thermostat/channels/1/values/ACTUAL_TEMPERATURE"), Some(&json!(21.5)));
+        assert_eq!(value.pointer("/Thermostat/channels/1/master/ADDRESS"), Some(&json!("000A:1")));
+        assert!(value.get("_sysvars").is_some());
+    }
+
+    #[tokio::test]
+    async fn callback_events() {
+        let mock = Arc::new(MockCcu::default());
+        let url = mock_server(Arc::clone(&mock)).await;
+        let listen = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config: HomematicCcu3Config = toml::from_str(&format!(r#"
+            url = "{url}"
+            frequency_secs = 0
+            username = "iot2db"
+            password = ""
+            callback.listen = "{listen}"
+            callback.url = "http://{listen}"
+            callback.interfaces = {{ "HmIP-RF" = "{url}/xmlrpc" }}
+        "#)).unwrap();
+        let mut stream = stream(config, Some(vec!["/Thermostat/channels/1/values/ACTUAL_TEMPERATURE".to_string()])).boxed();
+
+        // registration is followed by polling all values once, one event per datapoint
+        loop {
+            let value = stream.next().await.unwrap();
+            assert_eq!(datapoints(value.clone()), std::slice::from_ref(&value));
+            if value.pointer("/Thermostat/channels/1/values/ACTUAL_TEMPERATURE") == Some(&json!(21.5)) {
+                break
+            }
+        }
+        assert_eq!(mock.calls("init"), 1);
+
+        let events = [("000A:1", "ACTUAL_TEMPERATURE", json!(22.0)), ("000A:0", "UNREACH", json!(false))].iter()
+            .map(|(address, key, value)| json!({ "methodName": "event", "params": ["iot2db-HmIP-RF", address, key, value] }))
+            .collect();
+        let res = reqwest::Client::new().post(format!("http://{listen}"))
+            .body(xmlrpc::method_call("system.multicall", &[Value::Array(events)]))
+            .send().await.unwrap()
+            .text().await.unwrap();
+        assert_eq!(xmlrpc::parse_method_response(&res).unwrap(), json!([[""], [""]]));
+        // channel 0 isn't accessed
+        let mut value = stream.next().await.unwrap();
+        // skip the rest of the initial poll
+        while value.pointer("/Thermostat/channels/1/values/ACTUAL_TEMPERATURE") != Some(&json!(22.0)) {
+            value = stream.next().await.unwrap();
+        }
+        assert_eq!(value, json!({ "Thermostat": { "channels": { "1": { "values": { "ACTUAL_TEMPERATURE": 22.0 } } } } }));
+    }
+
+    #[tokio::test]
+    async fn callback_narrow_pipeline() {
+        let mock = Arc::new(MockCcu::default());
+        let url = mock_server(Arc::clone(&mock)).await;
+        let listen = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config: HomematicCcu3Config = toml::from_str(&format!(r#"
+            url = "{url}"
+            frequency_secs = 0
+            username = "iot2db"
+            password = ""
+            callback.listen = "{listen}"
+            callback.url = "http://{listen}"
+            callback.interfaces = {{ "HmIP-RF" = "{url}/xmlrpc" }}
+        "#)).unwrap();
+        let mapping: Mapping = toml::from_str(r#"
+            flush.complete = true
+            values.address = "/Thermostat/channels/1/address"
+            values.temperature = "/Thermostat/channels/1/values/ACTUAL_TEMPERATURE"
+        "#).unwrap();
+        let mut stream = stream(config, mapping.accessed_pointers()).boxed();
+        let mut mapper = NarrowToWide::new(mapping, None, Arc::new(NoopEscaper));
+
+        // the initial poll completes a record
+        let record = loop {
+            if let Some(record) = mapper.consume_value(stream.next().await.unwrap()).pop() {
+                break record
+            }
+        };
+        assert_eq!(record["address"], "000A:1");
+        assert_eq!(record["temperature"], "21.5");
+
+        let event = json!({ "methodName": "event", "params": ["iot2db-HmIP-RF", "000A:1", "ACTUAL_TEMPERATURE", 22.0] });
+        reqwest::Client::new().post(format!("http://{listen}"))
+            .body(xmlrpc::method_call("system.multicall", &[json!([event])]))
+            .send().await.unwrap();
+        loop {
+            let value = stream.next().await.unwrap();
+            let done = value.pointer("/Thermostat/channels/1/values/ACTUAL_TEMPERATURE") == Some(&json!(22.0));
+            assert!(mapper.consume_value(value).is_empty());
+            if done {
+                break
+            }
+        }
+        let records = mapper.flush();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["temperature"], "22.0");
+    }
+}
@@ -0,0 +1,287 @@
+//! Conversion of HTML pages to JSON
+//!
+//! Elements are converted to objects with their attributes as `@name`, their direct text as
+//! `#text` and their child elements grouped by tag name as arrays, e.g.
+//! `<div id="a">foo<span>bar</span></div>` becomes
+//! `{ "div": [{ "@id": "a", "#text": "foo", "span": [{ "#text": "bar" }] }] }`.
+
+use html5ever::parse_document;
+use html5ever::tendril::TendrilSink;
+use indexmap::IndexMap;
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+use serde_json::{Map, Value};
+
+/// parse the html page and convert the whole DOM to JSON
+pub fn to_json(html: &str) -> Value {
+    node_to_json(&parse(html))
+}
+
+/// parse the html page and return the text of the first element matching each selector
+pub fn select(html: &str, selectors: &IndexMap<String, Selector>) -> Value {
+    let document = parse(html);
+    let mut map = Map::new();
+    for (name, selector) in selectors {
+        match select_first(&document, selector) {
+            Some(element) => { map.insert(name.clone(), Value::String(text_content(&element).trim().to_string())); },
+            None => eprintln!("html selector {name:?} doesn't match any element"),
+        }
+    }
+    Value::Object(map)
+}
+
+fn parse(html: &str) -> Handle {
+    parse_document(RcDom::default(), Default::default())
+        .one(html)
+        .document
+}
+
+fn node_to_json(node: &Handle) -> Value {
+    let mut map = Map::new();
+    if let NodeData::Element { attrs, .. } = &node.data {
+        for attr in attrs.borrow().iter() {
+            map.insert(format!("@{}", attr.name.local), Value::String(attr.value.to_string()));
+        }
+    }
+    let mut text = String::new();
+    for child in node.children.borrow().iter() {
+        match &child.data {
+            NodeData::Text { contents } => text.push_str(&contents.borrow()),
+            NodeData::Element { name, .. } => {
+                let children = map.entry(name.local.to_string()).or_insert_with(|| Value::Array(Vec::new()));
+                if let Value::Array(children) = children {
+                    children.push(node_to_json(child));
+                }
+            }
+            _ => (),
+        }
+    }
+    let text = text.trim();
+    if !text.is_empty() {
+        map.insert("#text".to_string(), Value::String(text.to_string()));
+    }
+    Value::Object(map)
+}
+
+fn text_content(node: &Handle) -> String {
+    let mut text = String::new();
+    for child in node.children.borrow().iter() {
+        match &child.data {
+            NodeData::Text { contents } => text.push_str(&contents.borrow()),
+            NodeData::Element { .. } => text.push_str(&text_content(child)),
+            _ => (),
+        }
+    }
+    text
+}
+
+fn parent(node: &Handle) -> Option<Handle> {
+    let weak = node.parent.take();
+    let parent = weak.as_ref().and_then(|weak| weak.upgrade());
+    node.parent.set(weak);
+    parent
+}
+
+/// CSS selector supporting `tag`, `*`, `#id`, `.class`, `[attr]`, `[attr=value]` and
+/// `:nth-child(n)`, combined with the descendant (` `) and child (`>`) combinators
+#[derive(Debug, Clone)]
+pub struct Selector {
+    /// compound selectors from left to right; each combinator relates it to the previous one
+    compounds: Vec<(Combinator, Vec<Simple>)>,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+#[derive(Debug, Clone)]
+enum Simple {
+    Tag(String),
+    Id(String),
+    Class(String),
+    Attribute(String, Option<String>),
+    NthChild(usize),
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Selector, String> {
+        let mut compounds = Vec::new();
+        let mut chars = selector.trim().chars().peekable();
+        let mut combinator = Combinator::Descendant;
+        while chars.peek().is_some() {
+            let mut compound = Vec::new();
+            while let Some(&c) = chars.peek() {
+                match c {
+                    ' ' | '>' => break,
+                    '#' | '.' => {
+                        chars.next();
+                        let ident = take_ident(&mut chars);
+                        if ident.is_empty() {
+                            return Err(format!("missing name after `{c}` in selector {selector:?}"));
+                        }
+                        compound.push(if c == '#' { Simple::Id(ident) } else { Simple::Class(ident) });
+                    }
+                    '[' => {
+                        chars.next();
+                        let name = take_ident(&mut chars);
+                        let value = match chars.next() {
+                            Some(']') => None,
+                            Some('=') => {
+                                let value: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                                Some(value.trim_matches(|c| c == '"' || c == '\'').to_string())
+                            }
+                            _ => return Err(format!("invalid attribute selector in {selector:?}")),
+                        };
+                        compound.push(Simple::Attribute(name, value));
+                    }
+                    ':' => {
+                        chars.next();
+                        let pseudo = take_ident(&mut chars);
+                        let arg: String = match chars.next() {
+                            Some('(') => chars.by_ref().take_while(|&c| c != ')').collect(),
+                            _ => return Err(format!("unsupported pseudo-class `{pseudo}` in selector {selector:?}")),
+                        };
+                        match (pseudo.as_str(), arg.trim().parse()) {
+                            ("nth-child", Ok(n)) => compound.push(Simple::NthChild(n)),
+                            _ => return Err(format!("unsupported pseudo-class `{pseudo}({arg})` in selector {selector:?}")),
+                        }
+                    }
+                    '*' => { chars.next(); }
+                    _ => {
+                        let ident = take_ident(&mut chars);
+                        if ident.is_empty() {
+                            return Err(format!("unexpected character `{c}` in selector {selector:?}"));
+                        }
+                        compound.push(Simple::Tag(ident.to_ascii_lowercase()));
+                    }
+                }
+            }
+            compounds.push((combinator, compound));
+            combinator = Combinator::Descendant;
+            while let Some(&c) = chars.peek() {
+                match c {
+                    ' ' => { chars.next(); }
+                    '>' => { chars.next(); combinator = Combinator::Child; }
+                    _ => break,
+                }
+            }
+        }
+        if compounds.is_empty() {
+            return Err("empty selector".to_string());
+        }
+        Ok(Selector { compounds })
+    }
+
+    fn matches(&self, element: &Handle) -> bool {
+        matches_compounds(&self.compounds, element)
+    }
+}
+
+fn take_ident(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut ident = String::new();
+    while let Some(&c) = chars.peek() {
+        if !(c.is_alphanumeric() || c == '-' || c == '_') {
+            break;
+        }
+        ident.push(c);
+        chars.next();
+    }
+    ident
+}
+
+fn matches_compounds(compounds: &[(Combinator, Vec<Simple>)], element: &Handle) -> bool {
+    let Some(((combinator, compound), rest)) = compounds.split_last() else { return true };
+    if !matches!(element.data, NodeData::Element { .. }) {
+        return false;
+    }
+    if !compound.iter().all(|simple| matches_simple(simple, element)) {
+        return false;
+    }
+    if rest.is_empty() {
+        return true;
+    }
+    let mut ancestor = parent(element);
+    while let Some(node) = ancestor {
+        if matches_compounds(rest, &node) {
+            return true;
+        }
+        if *combinator == Combinator::Child {
+            return false;
+        }
+        ancestor = parent(&node);
+    }
+    false
+}
+
+fn matches_simple(simple: &Simple, element: &Handle) -> bool {
+    let NodeData::Element { name, attrs, .. } = &element.data else { return false };
+    let attr = |attr_name: &str| attrs.borrow().iter()
+        .find(|attr| &*attr.name.local == attr_name)
+        .map(|attr| attr.value.to_string());
+    match simple {
+        Simple::Tag(tag) => &*name.local == tag,
+        Simple::Id(id) => attr("id").as_deref() == Some(id),
+        Simple::Class(class) => attr("class").is_some_and(|classes| classes.split_whitespace().any(|c| c == class)),
+        Simple::Attribute(name, None) => attr(name).is_some(),
+        Simple::Attribute(name, Some(value)) => attr(name).as_deref() == Some(value),
+        Simple::NthChild(n) => {
+            let Some(parent) = parent(element) else { return false };
+            let children = parent.children.borrow();
+            let index = children.iter()
+                .filter(|child| matches!(child.data, NodeData::Element { .. }))
+                .position(|child| std::rc::Rc::ptr_eq(child, element));
+            index.map(|i| i + 1) == Some(*n)
+        }
+    }
+}
+
+fn select_first(node: &Handle, selector: &Selector) -> Option<Handle> {
+    for child in node.children.borrow().iter() {
+        if selector.matches(child) {
+            return Some(child.clone());
+        }
+        if let Some(found) = select_first(child, selector) {
+            return Some(found);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use super::*;
+
+    const HTML: &str = r#"
+        <html><body>
+            <h1 id="title">UPS Status</h1>
+            <table class="status values">
+                <tr><td>Load</td><td>23 %</td></tr>
+                <tr><td>Battery</td><td data-unit="%">100</td></tr>
+            </table>
+        </body></html>
+    "#;
+
+    #[test]
+    fn html_to_json() {
+        let json = to_json(HTML);
+        assert_eq!(json.pointer("/html/0/body/0/h1/0"), Some(&json!({ "@id": "title", "#text": "UPS Status" })));
+        assert_eq!(json.pointer("/html/0/body/0/table/0/@class"), Some(&json!("status values")));
+        assert_eq!(json.pointer("/html/0/body/0/table/0/tbody/0/tr/1/td/1/#text"), Some(&json!("100")));
+    }
+
+    #[test]
+    fn css_selectors() {
+        let selectors = [
+            ("title", "#title"),
+            ("load", "table.status tr:nth-child(1) > td:nth-child(2)"),
+            ("battery", "td[data-unit=\"%\"]"),
+        ].into_iter()
+            .map(|(name, selector)| (name.to_string(), Selector::parse(selector).unwrap()))
+            .collect();
+        assert_eq!(select(HTML, &selectors), json!({
+            "title": "UPS Status",
+            "load": "23 %",
+            "battery": "100",
+        }));
+    }
+}
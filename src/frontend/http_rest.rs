@@ -1,44 +1,201 @@
+use std::sync::Arc;
 use std::time::Duration;
 use futures::Stream;
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
 use serde_json::Value;
-use crate::config::HttpRestConfig;
+use crate::config::{HttpRequest, HttpRestConfig};
+use crate::frontend::payload::PayloadDecoder;
 
 pub fn stream(config: HttpRestConfig) -> impl Stream<Item = Value> + 'static {
     let client = reqwest::ClientBuilder::new()
-        .timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(config.timeout_secs as u64))
+        .danger_accept_invalid_certs(config.accept_invalid_certs)
+        .cookie_store(config.login.is_some())
         .build()
         .expect("can't build reqwest client");
-    futures::stream::unfold(0, move |mut iteration| {
+    // check requests on startup
+    check_request(&config.request);
+    if let Some(login) = &config.login {
+        check_request(login);
+    }
+    let decoder = Arc::new(PayloadDecoder::new(&config.payload));
+    futures::stream::unfold((0, false), move |(mut iteration, mut logged_in)| {
         let client = client.clone();
         let config = config.clone();
+        let decoder = Arc::clone(&decoder);
         async move {
+            let mut retry_now = false;
+            // a rejected request is retried immediately only once after logging in again;
+            // if that's rejected as well, the next try waits `frequency_secs`
+            let mut retried = false;
             loop {
-                if iteration != 0 {
+                if iteration != 0 && !retry_now {
                     tokio::time::sleep(Duration::from_secs(config.frequency_secs as u64)).await;
                 }
                 iteration += 1;
-                let mut req = client.get(&config.url);
+                retry_now = false;
+
+                if let (Some(login), false) = (&config.login, logged_in) {
+                    let res = build_request(&client, login).send().await
+                        .and_then(|res| res.error_for_status());
+                    match res {
+                        Ok(_) => logged_in = true,
+                        Err(e) => {
+                            eprintln!("error performing login request to {:?}: {:?}", login.url, e);
+                            continue
+                        }
+                    }
+                }
+
+                let mut req = build_request(&client, &config.request);
                 if let Some(auth) = &config.basic_auth {
                     req = req.basic_auth(&auth.username, auth.password.as_ref());
                 }
+                if let Some(token) = &config.bearer_token {
+                    req = req.bearer_auth(token);
+                }
                 let res = req.send().await;
                 let res = match res {
-                    Ok(res) => res.json().await,
+                    Ok(res) if config.login.is_some() && matches!(res.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+                        eprintln!("request to {:?} was rejected with {} - login again", config.request.url, res.status());
+                        retry_now = !retried;
+                        retried = true;
+                        logged_in = false;
+                        continue
+                    }
+                    Ok(res) => match res.error_for_status() {
+                        Ok(res) => match res.text().await {
+                            Ok(text) => decoder.decode(&text),
+                            Err(e) => Err(format!("{e:?}")),
+                        },
+                        Err(e) => {
+                            eprintln!("error response from {:?}: {:?}", config.request.url, e);
+                            continue
+                        }
+                    },
                     Err(e) => {
-                        eprintln!("error performing request to {:?}: {:?}", config.url, e);
+                        eprintln!("error performing request to {:?}: {:?}", config.request.url, e);
                         continue
                     }
                 };
                 let res = match res {
                     Ok(res) => res,
                     Err(e) => {
-                        eprintln!("error converting response from {:?} to json: {:?}", config.url, e);
+                        eprintln!("error converting response from {:?} to json: {e}", config.request.url);
                         continue
                     }
                 };
 
-                break Some((res, iteration))
+                break Some((res, (iteration, logged_in)))
             }
         }
     })
-}
\ No newline at end of file
+}
+
+fn check_request(request: &HttpRequest) {
+    parse_method(request);
+    assert!(request.body.is_none() || request.json.is_none(), "http request to {:?} can't have both body and json", request.url);
+}
+
+fn parse_method(request: &HttpRequest) -> Method {
+    Method::from_bytes(request.method.to_ascii_uppercase().as_bytes())
+        .unwrap_or_else(|e| panic!("invalid http method {:?}: {e}", request.method))
+}
+
+fn build_request(client: &Client, request: &HttpRequest) -> RequestBuilder {
+    let mut req = client.request(parse_method(request), &request.url);
+    for (name, value) in &request.headers {
+        req = req.header(name, value);
+    }
+    if let Some(body) = &request.body {
+        req = req.body(body.clone());
+    }
+    if let Some(json) = &request.json {
+        req = req.json(json);
+    }
+    req
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Instant;
+    use futures::StreamExt;
+    use hyper::{Body, Request, Response, Server};
+    use hyper::header::{COOKIE, SET_COOKIE};
+    use hyper::service::{make_service_fn, service_fn};
+    use serde_json::json;
+    use super::*;
+
+    #[derive(Default)]
+    struct Device {
+        /// number of the current session, incremented on login
+        session: AtomicUsize,
+        logins: AtomicUsize,
+        accept: AtomicBool,
+    }
+
+    fn handle(device: &Device, req: Request<Body>) -> Response<Body> {
+        if req.uri().path() == "/login" {
+            device.logins.fetch_add(1, Ordering::SeqCst);
+            let session = device.session.fetch_add(1, Ordering::SeqCst) + 1;
+            return Response::builder().header(SET_COOKIE, format!("session={session}")).body(Body::empty()).unwrap();
+        }
+        let session = format!("session={}", device.session.load(Ordering::SeqCst));
+        let cookie = req.headers().get(COOKIE).and_then(|cookie| cookie.to_str().ok());
+        match device.accept.load(Ordering::SeqCst) && cookie == Some(session.as_str()) {
+            true => Response::new(Body::from(r#"{"power": 42}"#)),
+            false => Response::builder().status(StatusCode::UNAUTHORIZED).body(Body::empty()).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn login_retry() {
+        let device = Arc::new(Device::default());
+        device.accept.store(true, Ordering::SeqCst);
+        let device2 = Arc::clone(&device);
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_conn| {
+            let device = Arc::clone(&device2);
+            async move { Ok::<_, Infallible>(service_fn(move |req| {
+                let res = handle(&device, req);
+                async move { Ok::<_, Infallible>(res) }
+            })) }
+        }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let config: HttpRestConfig = toml::from_str(&format!(r#"
+            url = "http://{addr}/data"
+            login.url = "http://{addr}/login"
+            login.method = "post"
+            frequency_secs = 1
+        "#)).unwrap();
+        let mut stream = stream(config).boxed();
+        assert_eq!(stream.next().await.unwrap(), json!({ "power": 42 }));
+        assert_eq!(device.logins.load(Ordering::SeqCst), 1);
+
+        // expired session: login again and retry immediately
+        device.session.fetch_add(1, Ordering::SeqCst);
+        let start = Instant::now();
+        assert_eq!(stream.next().await.unwrap(), json!({ "power": 42 }));
+        assert!(start.elapsed() < Duration::from_millis(1800), "{:?}", start.elapsed());
+        assert_eq!(device.logins.load(Ordering::SeqCst), 2);
+
+        // rejected despite a fresh login: no busy loop
+        device.accept.store(false, Ordering::SeqCst);
+        assert!(tokio::time::timeout(Duration::from_millis(2500), stream.next()).await.is_err());
+        let logins = device.logins.load(Ordering::SeqCst);
+        assert!(logins <= 5, "{logins} logins");
+    }
+
+    #[test]
+    #[should_panic(expected = "http request to \"http://127.0.0.1/login\" can't have both body and json")]
+    fn body_and_json() {
+        let config: HttpRestConfig = toml::from_str(r#"
+            url = "http://127.0.0.1/data"
+            frequency_secs = 1
+            login = { url = "http://127.0.0.1/login", method = "post", body = "user=foo", json = { user = "foo" } }
+        "#).unwrap();
+        let _ = stream(config);
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+#[cfg(test)]
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures::Stream;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use serde_json::{Map, Value};
+use tokio::sync::mpsc::{self, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use crate::config::HttpServerConfig;
+use crate::frontend::payload::PayloadDecoder;
+
+/// maximum size of a request body
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+type Routes = Arc<StdMutex<HashMap<String, Vec<Sender<Value>>>>>;
+
+pub struct HttpServerFrontend {
+    routes: Routes,
+    #[cfg(test)]
+    local_addr: SocketAddr,
+}
+
+impl HttpServerFrontend {
+    pub async fn new(config: &HttpServerConfig) -> Self {
+        let routes: Routes = Arc::new(StdMutex::new(HashMap::new()));
+        let config2 = Arc::new(config.clone());
+        let decoder2 = Arc::new(PayloadDecoder::new(&config.payload));
+        let routes2 = Arc::clone(&routes);
+        let make_service = make_service_fn(move |_conn| {
+            let config = Arc::clone(&config2);
+            let decoder = Arc::clone(&decoder2);
+            let routes = Arc::clone(&routes2);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle(Arc::clone(&config), Arc::clone(&decoder), Arc::clone(&routes), req)
+                }))
+            }
+        });
+        let server = Server::try_bind(&config.listen)
+            .unwrap_or_else(|e| panic!("can't listen on {}: {e}", config.listen))
+            .serve(make_service);
+        let local_addr = server.local_addr();
+        eprintln!("http-server: listening on {local_addr}");
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                eprintln!("http-server: error in server on {local_addr}: {e:?}");
+            }
+        });
+        HttpServerFrontend { routes, #[cfg(test)] local_addr }
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stream(&self, path: String) -> impl Stream<Item = Value> {
+        let (tx, rx) = mpsc::channel(100);
+        self.routes.lock().unwrap().entry(path).or_default().push(tx);
+        ReceiverStream::new(rx)
+    }
+}
+
+async fn handle(config: Arc<HttpServerConfig>, decoder: Arc<PayloadDecoder>, routes: Routes, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path().to_string();
+    let res = match handle_request(&config, &decoder, &routes, req).await {
+        Ok(()) => response(StatusCode::ACCEPTED, "accepted"),
+        Err((status, msg)) => {
+            eprintln!("http-server: request to {path:?} failed with {status}: {msg}");
+            let mut res = response(status, &msg);
+            if status == StatusCode::UNAUTHORIZED && config.basic_auth.is_some() {
+                res.headers_mut().insert(WWW_AUTHENTICATE, "Basic realm=\"iot2db\"".parse().unwrap());
+            }
+            res
+        }
+    };
+    Ok(res)
+}
+
+/// status code and message of a failed request
+type RequestError = (StatusCode, String);
+
+async fn handle_request(config: &HttpServerConfig, decoder: &PayloadDecoder, routes: &Routes, req: Request<Body>) -> Result<(), RequestError> {
+    check_auth(config, &req)?;
+    let path = req.uri().path().to_string();
+    let senders = routes.lock().unwrap().get(&path).cloned()
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "unknown path".to_string()))?;
+
+    let value = match *req.method() {
+        // GET requests (e.g. Shelly actions) pass their values as query parameters
+        Method::GET => parse_form(req.uri().query().unwrap_or(""))?,
+        Method::POST | Method::PUT => parse_body(decoder, req).await?,
+        _ => return Err((StatusCode::METHOD_NOT_ALLOWED, "method not allowed".to_string())),
+    };
+
+    for sender in senders {
+        sender.send(value.clone()).await
+            .map_err(|_| (StatusCode::SERVICE_UNAVAILABLE, "data pipeline closed".to_string()))?;
+    }
+    Ok(())
+}
+
+fn check_auth(config: &HttpServerConfig, req: &Request<Body>) -> Result<(), RequestError> {
+    if config.basic_auth.is_none() && config.bearer_token.is_none() {
+        return Ok(());
+    }
+    let authorization = req.headers().get(AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .unwrap_or("");
+    if let (Some(token), Some(given)) = (&config.bearer_token, authorization.strip_prefix("Bearer ")) {
+        if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+            return Ok(());
+        }
+    }
+    if let (Some(auth), Some(given)) = (&config.basic_auth, authorization.strip_prefix("Basic ")) {
+        let expected = format!("{}:{}", auth.username, auth.password.as_deref().unwrap_or(""));
+        if BASE64.decode(given).is_ok_and(|given| constant_time_eq(&given, expected.as_bytes())) {
+            return Ok(());
+        }
+    }
+    Err((StatusCode::UNAUTHORIZED, "unauthorized".to_string()))
+}
+
+/// compares the content without returning early, so the timing only reveals the length
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn parse_body(decoder: &PayloadDecoder, req: Request<Body>) -> Result<Value, RequestError> {
+    let content_type = req.headers().get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .and_then(|ct| ct.split(';').next())
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    if hyper::body::HttpBody::size_hint(req.body()).lower() > MAX_BODY_SIZE {
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, "body too large".to_string()));
+    }
+    let body = hyper::body::to_bytes(req.into_body()).await
+        .map_err(|_| (StatusCode::BAD_REQUEST, "error reading body".to_string()))?;
+    if body.len() as u64 > MAX_BODY_SIZE {
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, "body too large".to_string()));
+    }
+    let body = std::str::from_utf8(&body)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "body is not valid utf-8".to_string()))?;
+    match content_type.as_str() {
+        "application/json" => serde_json::from_str(body)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid json: {e}"))),
+        "application/x-www-form-urlencoded" => parse_form(body),
+        // if it can't be decoded, interpret it as simple String
+        _ => Ok(decoder.decode(body).unwrap_or_else(|_| Value::String(body.to_string()))),
+    }
+}
+
+fn parse_form(form: &str) -> Result<Value, RequestError> {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(form)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid form data: {e}")))?;
+    let map: Map<String, Value> = pairs.into_iter()
+        .map(|(key, value)| (key, Value::String(value)))
+        .collect();
+    Ok(Value::Object(map))
+}
+
+fn response(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(format!("{body}\n")))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+    use serde_json::json;
+    use crate::config::BasicAuth;
+    use super::*;
+
+    #[tokio::test]
+    async fn receive_requests() {
+        let frontend = HttpServerFrontend::new(&HttpServerConfig {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            basic_auth: None,
+            bearer_token: Some("secret".to_string()),
+            payload: Default::default(),
+        }).await;
+        let mut stream = frontend.stream("/shelly".to_string()).boxed();
+        let url = format!("http://{}/shelly", frontend.local_addr());
+        let client = reqwest::Client::new();
+
+        let res = client.post(&url).json(&json!({ "temp": 21.5 })).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = client.post(&url).bearer_auth("secret").json(&json!({ "temp": 21.5 })).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(stream.next().await.unwrap(), json!({ "temp": 21.5 }));
+
+        let res = client.post(&url).bearer_auth("secret").form(&[("hum", "50")]).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(stream.next().await.unwrap(), json!({ "hum": "50" }));
+
+        let res = client.get(format!("{url}?state=on")).bearer_auth("secret").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(stream.next().await.unwrap(), json!({ "state": "on" }));
+
+        let res = client.post(&url).bearer_auth("secret").body("42").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(stream.next().await.unwrap(), json!(42));
+
+        let res = client.post(format!("http://{}/unknown", frontend.local_addr())).bearer_auth("secret").body("").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn basic_auth() {
+        let frontend = HttpServerFrontend::new(&HttpServerConfig {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            basic_auth: Some(BasicAuth { username: "user".to_string(), password: Some("pass".to_string()) }),
+            bearer_token: None,
+            payload: Default::default(),
+        }).await;
+        let _stream = frontend.stream("/".to_string());
+        let url = format!("http://{}/", frontend.local_addr());
+        let client = reqwest::Client::new();
+
+        let res = client.post(&url).basic_auth("user", Some("wrong")).body("1").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = client.post(&url).basic_auth("user", Some("pass")).body("1").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+    }
+}
@@ -1,25 +1,41 @@
-use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::process::exit;
 use futures::stream::BoxStream;
 use futures::StreamExt;
 use serde_json::Value;
-use crate::config;
-use crate::config::{DataType, FrontendConfig, FrontendRef, FrontendRefData, HomematicCcu3Config, HttpRestConfig, JournaldConfig, ShellConfig};
+use crate::config::{DataType, FrontendConfig, FrontendRef, FrontendRefData, HomematicCcu3Config, HttpRestConfig, JournaldConfig, Mapping, ModbusRtuConfig, ModbusTcpConfig, NutConfig, ShellConfig, ShellMode, SmlConfig, SnmpConfig};
+use crate::frontend::http_server::HttpServerFrontend;
 use crate::frontend::mqtt::MqttFrontend;
 
 mod http_rest;
 mod homematic_ccu3;
 mod mqtt;
 mod shell;
+mod sml;
+mod snmp;
 mod journald;
+mod http_server;
+mod ber;
+mod html;
+mod modbus;
+mod nut;
+mod payload;
+mod queue;
+mod sunspec;
+mod xmlrpc;
 
 enum Frontend {
     HomematicCcu3(HomematicCcu3Config),
-    HttpRest(HttpRestConfig),
+    HttpRest(Box<HttpRestConfig>),
     Mqtt(MqttFrontend),
     Shell(ShellConfig),
     Journald(JournaldConfig),
+    HttpServer(HttpServerFrontend),
+    ModbusTcp(ModbusTcpConfig),
+    ModbusRtu(ModbusRtuConfig),
+    Sml(SmlConfig),
+    Snmp(SnmpConfig),
+    Nut(NutConfig),
 }
 
 pub struct Frontends {
@@ -38,6 +54,12 @@ impl Frontends {
             FrontendConfig::Mqtt(config) => Frontend::Mqtt(MqttFrontend::new(&config).await),
             FrontendConfig::Shell(config) => Frontend::Shell(config),
             FrontendConfig::Journald(config) => Frontend::Journald(config),
+            FrontendConfig::HttpServer(config) => Frontend::HttpServer(HttpServerFrontend::new(&config).await),
+            FrontendConfig::ModbusTcp(config) => Frontend::ModbusTcp(config),
+            FrontendConfig::ModbusRtu(config) => Frontend::ModbusRtu(config),
+            FrontendConfig::Sml(config) => Frontend::Sml(config),
+            FrontendConfig::Snmp(config) => Frontend::Snmp(config),
+            FrontendConfig::Nut(config) => Frontend::Nut(config),
         };
         let old = self.frontends.insert(name.clone(), frontend);
         if !old.is_none() {
@@ -46,34 +68,71 @@ impl Frontends {
         }
     }
 
-    pub async fn stream<T: Borrow<config::Value>>(&self, frontend_ref: FrontendRef, values: impl Iterator<Item = T>) -> BoxStream<'static, Value> {
+    pub async fn stream(&self, frontend_ref: FrontendRef, mapping: &Mapping) -> BoxStream<'static, Value> {
         match self.frontends.get(&frontend_ref.name) {
             Some(Frontend::HomematicCcu3(hm)) => {
                 assert_eq!(frontend_ref.data, None);
-                assert_eq!(frontend_ref.data_type, DataType::Wide, "Homematic CCU3 only supports frontend.data_type = \"wide\"");
-                homematic_ccu3::stream(hm.clone(), values).boxed()
+                match hm.callback {
+                    Some(_) => assert_eq!(frontend_ref.data_type, DataType::Narrow, "Homematic CCU3 with callback only supports frontend.data_type = \"narrow\""),
+                    None => assert_eq!(frontend_ref.data_type, DataType::Wide, "Homematic CCU3 only supports frontend.data_type = \"wide\""),
+                }
+                homematic_ccu3::stream(hm.clone(), mapping.accessed_pointers()).boxed()
             }
             Some(Frontend::HttpRest(rest)) => {
                 assert_eq!(frontend_ref.data, None);
                 assert_eq!(frontend_ref.data_type, DataType::Wide, "HTTP REST only supports frontend.data_type = \"wide\"");
-                http_rest::stream(rest.clone()).boxed()
+                http_rest::stream((**rest).clone()).boxed()
             }
             Some(Frontend::Mqtt(mqtt)) => {
-                let Some(FrontendRefData::Mqtt { mqtt_topic }) = frontend_ref.data else {
+                let Some(FrontendRefData::Mqtt { mqtt_topic, mqtt_qos }) = frontend_ref.data else {
                     panic!("Usage of MQTT frontend `{}` requires data to provide mqtt_topic", frontend_ref.name)
                 };
-                mqtt.stream(mqtt_topic).await.boxed()
+                mqtt.stream(mqtt_topic, mqtt_qos).await.boxed()
             }
             Some(Frontend::Shell(config)) => {
                 assert_eq!(frontend_ref.data, None);
                 assert_eq!(frontend_ref.data_type, DataType::Wide, "Shell only supports frontend.data_type = \"wide\"");
-                shell::stream(config).boxed()
+                match config.mode {
+                    ShellMode::Poll => shell::stream(config).boxed(),
+                    ShellMode::Stream => shell::stream_process(config).boxed(),
+                }
             }
             Some(Frontend::Journald(config)) => {
                 assert_eq!(frontend_ref.data, None);
                 assert_eq!(frontend_ref.data_type, DataType::Wide, "Journald only supports frontend.data_type = \"wide\"");
                 journald::stream(config.clone()).boxed()
             }
+            Some(Frontend::HttpServer(server)) => {
+                let Some(FrontendRefData::HttpServer { http_path }) = frontend_ref.data else {
+                    panic!("Usage of HTTP server frontend `{}` requires data to provide http_path", frontend_ref.name)
+                };
+                server.stream(http_path).boxed()
+            }
+            Some(Frontend::ModbusTcp(config)) => {
+                assert_eq!(frontend_ref.data, None);
+                assert_eq!(frontend_ref.data_type, DataType::Wide, "Modbus TCP only supports frontend.data_type = \"wide\"");
+                modbus::stream(config.clone()).boxed()
+            }
+            Some(Frontend::ModbusRtu(config)) => {
+                assert_eq!(frontend_ref.data, None);
+                assert_eq!(frontend_ref.data_type, DataType::Wide, "Modbus RTU only supports frontend.data_type = \"wide\"");
+                modbus::stream_rtu(config.clone()).boxed()
+            }
+            Some(Frontend::Sml(config)) => {
+                assert_eq!(frontend_ref.data, None);
+                assert_eq!(frontend_ref.data_type, DataType::Wide, "SML only supports frontend.data_type = \"wide\"");
+                sml::stream(config.clone()).boxed()
+            }
+            Some(Frontend::Snmp(config)) => {
+                assert_eq!(frontend_ref.data, None);
+                assert_eq!(frontend_ref.data_type, DataType::Wide, "SNMP only supports frontend.data_type = \"wide\"");
+                snmp::stream(config.clone()).boxed()
+            }
+            Some(Frontend::Nut(config)) => {
+                assert_eq!(frontend_ref.data, None);
+                assert_eq!(frontend_ref.data_type, DataType::Wide, "NUT only supports frontend.data_type = \"wide\"");
+                nut::stream(config.clone()).boxed()
+            }
             None => panic!("unknown frontend {} for data", frontend_ref.name),
         }
     }
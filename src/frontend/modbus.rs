@@ -0,0 +1,760 @@
+//! Modbus client polling registers, see the
+//! [Modbus Application Protocol Specification](https://modbus.org/docs/Modbus_Application_Protocol_V1_1b3.pdf)
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use async_trait::async_trait;
+use futures::Stream;
+use indexmap::IndexMap;
+use once_cell::sync::Lazy;
+use serde_json::{Map, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+use crate::config::{ModbusDataType, ModbusFunction, ModbusPoll, ModbusRegister, ModbusRtuConfig, ModbusTcpConfig, Parity, WordOrder};
+use crate::frontend::sunspec;
+
+/// maximum number of registers of a single read request
+const MAX_REGISTERS: u16 = 125;
+/// maximum number of coils / discrete inputs of a single read request
+const MAX_BITS: u16 = 2000;
+
+pub fn stream(config: ModbusTcpConfig) -> impl Stream<Item = Value> + 'static {
+    let transport = TcpTransport::new(format!("{}:{}", config.host, config.port), Duration::from_secs(config.poll.timeout_secs as u64));
+    poll_stream(transport, config.poll)
+}
+
+pub fn stream_rtu(config: ModbusRtuConfig) -> impl Stream<Item = Value> + 'static {
+    poll_stream(shared_bus(&config), config.poll)
+}
+
+fn poll_stream(transport: impl Transport + 'static, config: ModbusPoll) -> impl Stream<Item = Value> + 'static {
+    assert!(!config.registers.is_empty() || config.sunspec.is_some(), "modbus frontend requires registers or sunspec");
+    let reads = plan_reads(&config.registers, config.unit_id);
+    // SunSpec models are discovered during the first poll
+    let models: Option<Vec<sunspec::Model>> = None;
+    futures::stream::unfold((transport, models, 0), move |(mut transport, mut models, mut iteration)| {
+        let reads = reads.clone();
+        let config = config.clone();
+        async move {
+            loop {
+                if iteration != 0 {
+                    tokio::time::sleep(Duration::from_secs(config.frequency_secs as u64)).await;
+                }
+                iteration += 1;
+                let mut res = match poll(&mut transport, &reads).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        eprintln!("modbus: {e} - retry");
+                        continue
+                    }
+                };
+                if let Some(sunspec) = &config.sunspec {
+                    let unit = sunspec.unit_id.unwrap_or(config.unit_id);
+                    if models.is_none() {
+                        match sunspec::discover(&mut transport, unit, sunspec.base_address).await {
+                            Ok(discovered) => models = Some(discovered),
+                            Err(e) => eprintln!("modbus: error discovering SunSpec models: {e}"),
+                        }
+                    }
+                    if let Some(models) = &models {
+                        match sunspec::read(&mut transport, unit, models).await {
+                            Ok(points) => res.extend(points),
+                            Err(e) => eprintln!("modbus: error reading SunSpec models: {e}"),
+                        }
+                    }
+                }
+                if res.is_empty() {
+                    eprintln!("modbus: nothing could be read - retry");
+                    continue
+                }
+                break Some((Value::Object(res), (transport, models, iteration)));
+            }
+        }
+    })
+}
+
+/// Reads all registers. Registers of a request answered with an exception are missing.
+/// Transport errors skip the remaining reads of the unit if the transport isolates units,
+/// otherwise they abort the whole poll.
+async fn poll(transport: &mut impl Transport, reads: &[Read]) -> Result<Map<String, Value>, String> {
+    let mut res = Map::new();
+    let mut failed_units = Vec::new();
+    for read in reads {
+        if failed_units.contains(&read.unit) {
+            continue;
+        }
+        match read.execute(transport).await {
+            Ok(data) => {
+                for (name, register) in &read.registers {
+                    res.insert(name.clone(), decode(register, &data, register.address - read.address));
+                }
+            }
+            Err(Error::Exception(code)) => {
+                let names: Vec<_> = read.registers.iter().map(|(name, _)| name.as_str()).collect();
+                eprintln!("modbus: error reading {names:?} from unit {}: {}", read.unit, exception_name(code));
+            }
+            Err(Error::Transport(e)) if transport.isolates_units() => {
+                eprintln!("modbus: {e} - skipping the remaining registers of unit {}", read.unit);
+                failed_units.push(read.unit);
+            }
+            Err(Error::Transport(e)) => return Err(e),
+        }
+    }
+    Ok(res)
+}
+
+pub enum Error {
+    /// exception code returned by the device
+    Exception(u8),
+    /// the request or its response failed
+    Transport(String),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Exception(code) => write!(f, "{}", exception_name(*code)),
+            Error::Transport(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+fn exception_name(code: u8) -> String {
+    match code {
+        1 => "illegal function".to_string(),
+        2 => "illegal data address".to_string(),
+        3 => "illegal data value".to_string(),
+        4 => "server device failure".to_string(),
+        5 => "acknowledge".to_string(),
+        6 => "server device busy".to_string(),
+        10 => "gateway path unavailable".to_string(),
+        11 => "gateway target device failed to respond".to_string(),
+        code => format!("exception {code}"),
+    }
+}
+
+/// Sends a request PDU to a unit, returning the response PDU.
+#[async_trait]
+pub trait Transport: Send {
+    async fn request(&mut self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>, String>;
+
+    /// whether a failed request only concerns its unit (e.g. an unpowered slave on a serial bus),
+    /// such that other units can still be read
+    fn isolates_units(&self) -> bool {
+        false
+    }
+}
+
+/// Modbus TCP, connecting lazily and reconnecting after errors
+pub struct TcpTransport {
+    addr: String,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+    transaction_id: u16,
+}
+
+impl TcpTransport {
+    pub fn new(addr: String, timeout: Duration) -> Self {
+        TcpTransport { addr, timeout, stream: None, transaction_id: 0 }
+    }
+
+    async fn exchange(&mut self, unit: u8, pdu: &[u8]) -> std::io::Result<Vec<u8>> {
+        if self.stream.is_none() {
+            self.stream = Some(TcpStream::connect(&self.addr).await?);
+        }
+        let stream = self.stream.as_mut().unwrap();
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+
+        // MBAP header: transaction id, protocol id 0, length of unit id and PDU, unit id
+        let mut frame = Vec::with_capacity(7 + pdu.len());
+        frame.extend_from_slice(&self.transaction_id.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+        frame.push(unit);
+        frame.extend_from_slice(pdu);
+        stream.write_all(&frame).await?;
+
+        loop {
+            let mut header = [0; 7];
+            stream.read_exact(&mut header).await?;
+            let len = u16::from_be_bytes([header[4], header[5]]);
+            if len < 2 {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid length {len}")));
+            }
+            let mut response = vec![0; len as usize - 1];
+            stream.read_exact(&mut response).await?;
+            // skip late responses of previous requests which timed out
+            if header[0..2] == self.transaction_id.to_be_bytes() {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn request(&mut self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>, String> {
+        let res = tokio::time::timeout(self.timeout, self.exchange(unit, pdu)).await
+            .unwrap_or_else(|_| Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timeout")));
+        res.map_err(|e| {
+            // the connection may be out of sync
+            self.stream = None;
+            format!("error requesting {}: {e}", self.addr)
+        })
+    }
+}
+
+/// serial settings of a bus
+type BusSettings = (u32, Parity, u8);
+
+/// open serial buses by device, shared by all frontends using the device
+static BUSES: Lazy<StdMutex<HashMap<String, (BusSettings, SharedTransport)>>> = Lazy::new(Default::default);
+
+/// The bus of the device, opening it if it isn't used yet.
+/// Requests of all users are serialized, as only one request may be pending on a bus.
+fn shared_bus(config: &ModbusRtuConfig) -> SharedTransport {
+    let settings = (config.baud_rate, config.parity, config.stop_bits);
+    let mut buses = BUSES.lock().unwrap();
+    if let Some((existing, bus)) = buses.get(&config.device) {
+        assert_eq!(*existing, settings, "modbus-rtu frontends of {} use different serial settings", config.device);
+        return bus.clone();
+    }
+    let parity = match config.parity {
+        Parity::None => tokio_serial::Parity::None,
+        Parity::Even => tokio_serial::Parity::Even,
+        Parity::Odd => tokio_serial::Parity::Odd,
+    };
+    let stop_bits = match config.stop_bits {
+        1 => tokio_serial::StopBits::One,
+        2 => tokio_serial::StopBits::Two,
+        bits => panic!("modbus-rtu: invalid number of stop bits {bits}"),
+    };
+    let port = tokio_serial::new(&config.device, config.baud_rate)
+        .parity(parity)
+        .stop_bits(stop_bits)
+        .open_native_async()
+        .unwrap_or_else(|e| panic!("modbus-rtu: can't open {}: {e}", config.device));
+    let transport = RtuTransport::new(port, config.baud_rate, Duration::from_secs(config.poll.timeout_secs as u64));
+    let bus = SharedTransport(Arc::new(Mutex::new(transport)));
+    buses.insert(config.device.clone(), (settings, bus.clone()));
+    bus
+}
+
+#[derive(Clone)]
+pub struct SharedTransport(Arc<Mutex<RtuTransport>>);
+
+#[async_trait]
+impl Transport for SharedTransport {
+    async fn request(&mut self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>, String> {
+        self.0.lock().await.request(unit, pdu).await
+    }
+
+    fn isolates_units(&self) -> bool {
+        true
+    }
+}
+
+/// Modbus RTU over a serial port
+pub struct RtuTransport {
+    port: SerialStream,
+    /// silent interval of 3.5 characters required between frames
+    silence: Duration,
+    timeout: Duration,
+}
+
+impl RtuTransport {
+    pub fn new(port: SerialStream, baud_rate: u32, timeout: Duration) -> Self {
+        // 11 bits per character; fixed 1.75ms above 19200 baud
+        let silence = match baud_rate {
+            0..=19200 => Duration::from_micros(3_500_000 * 11 / baud_rate.max(1) as u64),
+            _ => Duration::from_micros(1750),
+        };
+        RtuTransport { port, silence, timeout }
+    }
+
+    async fn exchange(&mut self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>, String> {
+        tokio::time::sleep(self.silence).await;
+        let mut frame = vec![unit];
+        frame.extend_from_slice(pdu);
+        frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+        self.port.write_all(&frame).await.map_err(|e| e.to_string())?;
+
+        // the length of the response depends on its function code
+        let mut response = vec![0; 3];
+        self.port.read_exact(&mut response).await.map_err(|e| e.to_string())?;
+        let remaining = match response[1] {
+            code if code & 0x80 != 0 => 2,
+            1..=4 => response[2] as usize + 2,
+            code => return Err(format!("unsupported function {code} in response")),
+        };
+        response.resize(3 + remaining, 0);
+        self.port.read_exact(&mut response[3..]).await.map_err(|e| e.to_string())?;
+
+        let (body, crc) = response.split_at(response.len() - 2);
+        if crc16(body).to_le_bytes() != crc {
+            return Err(format!("invalid CRC of response {response:02x?}"));
+        }
+        if body[0] != unit {
+            return Err(format!("response from unit {} instead of {unit}", body[0]));
+        }
+        Ok(body[1..].to_vec())
+    }
+}
+
+#[async_trait]
+impl Transport for RtuTransport {
+    async fn request(&mut self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>, String> {
+        let res = tokio::time::timeout(self.timeout, self.exchange(unit, pdu)).await
+            .unwrap_or_else(|_| Err("timeout".to_string()));
+        res.map_err(|e| {
+            // discard partial or late responses
+            let _ = self.port.clear(tokio_serial::ClearBuffer::Input);
+            format!("error requesting unit {unit}: {e}")
+        })
+    }
+
+    fn isolates_units(&self) -> bool {
+        true
+    }
+}
+
+/// CRC-16/MODBUS, transmitted little-endian
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xa001,
+                _ => crc >> 1,
+            };
+        }
+    }
+    crc
+}
+
+/// Single read request covering one or more adjacent registers
+#[derive(Debug, Clone)]
+pub struct Read {
+    pub unit: u8,
+    pub function: ModbusFunction,
+    pub address: u16,
+    /// number of registers or bits
+    pub count: u16,
+    pub registers: Vec<(String, ModbusRegister)>,
+}
+
+pub enum Data {
+    Registers(Vec<u16>),
+    Bits(Vec<bool>),
+}
+
+impl Read {
+    pub async fn execute(&self, transport: &mut impl Transport) -> Result<Data, Error> {
+        let code = function_code(self.function);
+        let mut pdu = vec![code];
+        pdu.extend_from_slice(&self.address.to_be_bytes());
+        pdu.extend_from_slice(&self.count.to_be_bytes());
+        let response = transport.request(self.unit, &pdu).await.map_err(Error::Transport)?;
+        let invalid = || Error::Transport(format!("invalid response {response:02x?} to function {code}"));
+        match response.first() {
+            Some(&c) if c == code | 0x80 => return Err(Error::Exception(response.get(1).copied().ok_or_else(invalid)?)),
+            Some(&c) if c == code => (),
+            _ => return Err(invalid()),
+        }
+        let data = response.get(2..).filter(|data| Some(&(data.len() as u8)) == response.get(1)).ok_or_else(invalid)?;
+        match self.function {
+            ModbusFunction::Holding | ModbusFunction::Input => {
+                if data.len() != self.count as usize * 2 {
+                    return Err(invalid());
+                }
+                Ok(Data::Registers(data.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect()))
+            }
+            ModbusFunction::Coil | ModbusFunction::DiscreteInput => {
+                if data.len() != (self.count as usize).div_ceil(8) {
+                    return Err(invalid());
+                }
+                Ok(Data::Bits((0..self.count as usize).map(|i| data[i / 8] & (1 << (i % 8)) != 0).collect()))
+            }
+        }
+    }
+}
+
+fn function_code(function: ModbusFunction) -> u8 {
+    match function {
+        ModbusFunction::Coil => 1,
+        ModbusFunction::DiscreteInput => 2,
+        ModbusFunction::Holding => 3,
+        ModbusFunction::Input => 4,
+    }
+}
+
+/// number of registers (or bits) of the value, panicking on invalid configuration
+fn size(name: &str, register: &ModbusRegister) -> u16 {
+    let bits = matches!(register.function, ModbusFunction::Coil | ModbusFunction::DiscreteInput);
+    match (register.data_type, bits) {
+        (ModbusDataType::Bool, true) => 1,
+        (ModbusDataType::Bool, false) => panic!("modbus register {name:?}: type bool requires function coil or discrete-input"),
+        (_, true) => panic!("modbus register {name:?}: coils and discrete inputs require type bool"),
+        (ModbusDataType::U16 | ModbusDataType::I16, false) => 1,
+        (ModbusDataType::U32 | ModbusDataType::I32 | ModbusDataType::F32, false) => 2,
+        (ModbusDataType::U64 | ModbusDataType::I64 | ModbusDataType::F64, false) => 4,
+        (ModbusDataType::String, false) => match register.length {
+            Some(length) if (1..=MAX_REGISTERS).contains(&length) => length,
+            _ => panic!("modbus register {name:?}: type string requires a length between 1 and {MAX_REGISTERS}"),
+        },
+    }
+}
+
+/// Groups the registers into as few requests as possible, merging adjacent or overlapping ones.
+/// Gaps aren't read, as devices may reject unmapped addresses.
+pub fn plan_reads(registers: &IndexMap<String, ModbusRegister>, default_unit: u8) -> Vec<Read> {
+    let mut registers: Vec<_> = registers.iter()
+        .map(|(name, register)| {
+            let size = size(name, register);
+            assert!(register.address as u32 + size as u32 <= 0x10000, "modbus register {name:?} exceeds the address space");
+            (register.unit_id.unwrap_or(default_unit), function_code(register.function), register.address, size, name, register)
+        })
+        .collect();
+    registers.sort_by_key(|&(unit, code, address, size, _, _)| (unit, code, address, size));
+
+    let mut reads: Vec<Read> = Vec::new();
+    for (unit, _, address, size, name, register) in registers {
+        let max = match register.function {
+            ModbusFunction::Coil | ModbusFunction::DiscreteInput => MAX_BITS,
+            ModbusFunction::Holding | ModbusFunction::Input => MAX_REGISTERS,
+        };
+        let end = address as u32 + size as u32;
+        if let Some(read) = reads.last_mut() {
+            let read_end = read.address as u32 + read.count as u32;
+            if read.unit == unit && read.function == register.function && address as u32 <= read_end
+                && end.max(read_end) - read.address as u32 <= max as u32
+            {
+                read.count = (end.max(read_end) - read.address as u32) as u16;
+                read.registers.push((name.clone(), register.clone()));
+                continue;
+            }
+        }
+        reads.push(Read { unit, function: register.function, address, count: size, registers: vec![(name.clone(), register.clone())] });
+    }
+    reads
+}
+
+/// value of the register at `offset` within the read data
+pub fn decode(register: &ModbusRegister, data: &Data, offset: u16) -> Value {
+    let offset = offset as usize;
+    let words = match data {
+        Data::Bits(bits) => return Value::Bool(bits[offset]),
+        Data::Registers(words) => words,
+    };
+    let len = match register.data_type {
+        ModbusDataType::U16 | ModbusDataType::I16 => 1,
+        ModbusDataType::U32 | ModbusDataType::I32 | ModbusDataType::F32 => 2,
+        ModbusDataType::U64 | ModbusDataType::I64 | ModbusDataType::F64 => 4,
+        ModbusDataType::String => register.length.unwrap_or(1) as usize,
+        ModbusDataType::Bool => unreachable!("bool registers are rejected by plan_reads"),
+    };
+    let mut words = words[offset..offset + len].to_vec();
+    if register.word_order == WordOrder::Little && register.data_type != ModbusDataType::String {
+        words.reverse();
+    }
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+    let number = match register.data_type {
+        ModbusDataType::U16 => u16::from_be_bytes(bytes[..2].try_into().unwrap()) as f64,
+        ModbusDataType::I16 => i16::from_be_bytes(bytes[..2].try_into().unwrap()) as f64,
+        ModbusDataType::U32 => u32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64,
+        ModbusDataType::I32 => i32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64,
+        ModbusDataType::F32 => f32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64,
+        ModbusDataType::F64 => f64::from_be_bytes(bytes[..8].try_into().unwrap()),
+        // 64-bit integers aren't converted to f64 to keep their precision
+        ModbusDataType::U64 => {
+            let value = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+            match register.scale {
+                Some(_) => value as f64,
+                None => return Value::from(value),
+            }
+        }
+        ModbusDataType::I64 => {
+            let value = i64::from_be_bytes(bytes[..8].try_into().unwrap());
+            match register.scale {
+                Some(_) => value as f64,
+                None => return Value::from(value),
+            }
+        }
+        ModbusDataType::String => {
+            let text = String::from_utf8_lossy(&bytes);
+            return Value::String(text.trim_end_matches(['\0', ' ']).to_string());
+        }
+        ModbusDataType::Bool => unreachable!(),
+    };
+    let integer = matches!(register.data_type, ModbusDataType::U16 | ModbusDataType::I16 | ModbusDataType::U32 | ModbusDataType::I32);
+    match register.scale {
+        None if integer => Value::from(number as i64),
+        None => serde_json::Number::from_f64(number).map_or(Value::Null, Value::Number),
+        Some(scale) => serde_json::Number::from_f64(apply_scale(number, scale)).map_or(Value::Null, Value::Number),
+    }
+}
+
+/// `number * scale`, dividing by the inverse of scales like `0.1` to avoid results like `230.10000000000002`
+fn apply_scale(number: f64, scale: f64) -> f64 {
+    let inverse = 1.0 / scale;
+    if scale.abs() < 1.0 && (inverse - inverse.round()).abs() < 1e-9 {
+        number / inverse.round()
+    } else {
+        number * scale
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use futures::StreamExt;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use super::*;
+
+    /// simulated device answering read requests of its register and bit maps
+    #[derive(Default)]
+    struct Simulator {
+        registers: HashMap<(u8, u16), u16>,
+        bits: HashMap<(u8, u16), bool>,
+        requests: usize,
+    }
+
+    impl Simulator {
+        /// response PDU to a request PDU of a unit
+        fn respond(&mut self, unit: u8, pdu: &[u8]) -> Vec<u8> {
+            self.requests += 1;
+            let code = pdu[0];
+            let address = u16::from_be_bytes([pdu[1], pdu[2]]);
+            let count = u16::from_be_bytes([pdu[3], pdu[4]]);
+            let addresses = (address..address + count).map(|address| (unit, address));
+            match code {
+                3 | 4 => {
+                    let Some(words) = addresses.map(|key| self.registers.get(&key).copied()).collect::<Option<Vec<_>>>() else {
+                        return vec![code | 0x80, 2];
+                    };
+                    let mut res = vec![code, count as u8 * 2];
+                    res.extend(words.iter().flat_map(|word| word.to_be_bytes()));
+                    res
+                }
+                1 | 2 => {
+                    let Some(bits) = addresses.map(|key| self.bits.get(&key).copied()).collect::<Option<Vec<_>>>() else {
+                        return vec![code | 0x80, 2];
+                    };
+                    let mut res = vec![code, count.div_ceil(8) as u8];
+                    res.resize(2 + count.div_ceil(8) as usize, 0);
+                    for (i, &bit) in bits.iter().enumerate() {
+                        res[2 + i / 8] |= (bit as u8) << (i % 8);
+                    }
+                    res
+                }
+                _ => vec![code | 0x80, 1],
+            }
+        }
+    }
+
+    /// Modbus TCP server of the simulator, returning its address
+    async fn tcp_server(simulator: Arc<Mutex<Simulator>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let simulator = Arc::clone(&simulator);
+                tokio::spawn(async move {
+                    let mut header = [0; 7];
+                    while stream.read_exact(&mut header).await.is_ok() {
+                        let mut pdu = vec![0; u16::from_be_bytes([header[4], header[5]]) as usize - 1];
+                        stream.read_exact(&mut pdu).await.unwrap();
+                        let res = simulator.lock().unwrap().respond(header[6], &pdu);
+                        let mut frame = header[..4].to_vec();
+                        frame.extend_from_slice(&(res.len() as u16 + 1).to_be_bytes());
+                        frame.push(header[6]);
+                        frame.extend(res);
+                        stream.write_all(&frame).await.unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn tcp_registers() {
+        let mut simulator = Simulator::default();
+        // u16 voltage, little-endian i32 power, f32 temperature, string
+        let words = [2301, 0xfc18, 0xffff, 0x41b4, 0x0000, u16::from_be_bytes(*b"SN"), u16::from_be_bytes(*b"1\0")];
+        for (i, word) in words.into_iter().enumerate() {
+            simulator.registers.insert((1, 100 + i as u16), word);
+        }
+        simulator.registers.insert((2, 0), 7);
+        simulator.bits.insert((1, 5), true);
+        let simulator = Arc::new(Mutex::new(simulator));
+        let addr = tcp_server(Arc::clone(&simulator)).await;
+        let (host, port) = addr.split_once(':').unwrap();
+
+        let config: ModbusTcpConfig = toml::from_str(&format!(r#"
+            host = "{host}"
+            port = {port}
+            frequency_secs = 0
+            registers.voltage = {{ address = 100, scale = 0.1 }}
+            registers.power = {{ address = 101, type = "i32", word_order = "little" }}
+            registers.temperature = {{ address = 103, type = "f32" }}
+            registers.serial = {{ address = 105, type = "string", length = 2 }}
+            registers.other_unit = {{ address = 0, unit_id = 2 }}
+            registers.relay = {{ address = 5, function = "coil", type = "bool" }}
+            registers.missing = {{ address = 200, function = "input" }}
+        "#)).unwrap();
+        assert_eq!(plan_reads(&config.poll.registers, config.poll.unit_id).len(), 4);
+        let mut stream = stream(config).boxed();
+        assert_eq!(stream.next().await.unwrap(), json!({
+            "voltage": 230.1,
+            "power": -1000,
+            "temperature": 22.5,
+            "serial": "SN1",
+            "relay": true,
+            "other_unit": 7,
+        }));
+        assert_eq!(simulator.lock().unwrap().requests, 4);
+    }
+
+    /// SunSpec device with a common and a three phase inverter model at `base`
+    fn sunspec_registers(simulator: &mut Simulator, unit: u8, base: u16) {
+        let mut words = vec![0x5375, 0x6e53, 1, 66];
+        let mut common = vec![0; 66];
+        common[0] = u16::from_be_bytes(*b"AB");
+        common[1] = u16::from_be_bytes(*b"C\0");
+        words.extend(common);
+        words.extend([103, 50]);
+        let mut inverter = vec![0; 50];
+        // AphB unimplemented, PhVphA 230.1 V, W 1234 W, WH 100 Wh, St 4
+        inverter[2] = 0xffff;
+        inverter[8] = 2301;
+        inverter[11] = (-1i16) as u16;
+        inverter[12] = 1234;
+        inverter[23] = 100;
+        inverter[36] = 4;
+        words.extend(inverter);
+        words.extend([0xffff, 0]);
+        for (i, word) in words.into_iter().enumerate() {
+            simulator.registers.insert((unit, base + i as u16), word);
+        }
+    }
+
+    #[tokio::test]
+    async fn rtu_sunspec() {
+        let mut simulator = Simulator::default();
+        sunspec_registers(&mut simulator, 1, 40000);
+        simulator.registers.insert((2, 10), 42);
+        let (mut slave, port) = SerialStream::pair().unwrap();
+        tokio::spawn(async move {
+            let mut frame = [0; 8];
+            while slave.read_exact(&mut frame).await.is_ok() {
+                assert_eq!(crc16(&frame[..6]).to_le_bytes(), frame[6..]);
+                let mut res = vec![frame[0]];
+                res.extend(simulator.respond(frame[0], &frame[1..6]));
+                res.extend(crc16(&res).to_le_bytes());
+                slave.write_all(&res).await.unwrap();
+            }
+        });
+
+        let config: ModbusRtuConfig = toml::from_str(r#"
+            device = "/dev/null"
+            baud_rate = 115200
+            frequency_secs = 0
+            registers.meter = { address = 10, unit_id = 2 }
+            sunspec = {}
+        "#).unwrap();
+        let transport = RtuTransport::new(port, config.baud_rate, Duration::from_secs(1));
+        let mut stream = poll_stream(transport, config.poll).boxed();
+        let value = stream.next().await.unwrap();
+        assert_eq!(value["meter"], json!(42));
+        assert_eq!(value["common"], json!({ "Mn": "ABC", "Md": "", "Opt": "", "Vr": "", "SN": "", "DA": 0 }));
+        assert_eq!(value.pointer("/inverter/PhVphA"), Some(&json!(230.1)));
+        assert_eq!(value.pointer("/inverter/W"), Some(&json!(1234)));
+        assert_eq!(value.pointer("/inverter/WH"), Some(&json!(100)));
+        assert_eq!(value.pointer("/inverter/St"), Some(&json!(4)));
+        assert_eq!(value.pointer("/inverter/AphB"), None);
+        assert_eq!(value.pointer("/inverter/V_SF"), None);
+    }
+
+    /// bus calling the simulator directly, timing out for requests of `silent` addresses or units
+    #[derive(Default)]
+    struct DirectTransport {
+        simulator: Simulator,
+        silent: Vec<u16>,
+        silent_units: Vec<u8>,
+        timeouts: usize,
+    }
+
+    #[async_trait]
+    impl Transport for DirectTransport {
+        async fn request(&mut self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>, String> {
+            if self.silent.contains(&u16::from_be_bytes([pdu[1], pdu[2]])) || self.silent_units.contains(&unit) {
+                self.timeouts += 1;
+                return Err(format!("error requesting unit {unit}: timeout"));
+            }
+            Ok(self.simulator.respond(unit, pdu))
+        }
+
+        fn isolates_units(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_unit() {
+        let mut transport = DirectTransport { silent_units: vec![3], ..DirectTransport::default() };
+        sunspec_registers(&mut transport.simulator, 1, 40000);
+        transport.simulator.registers.insert((2, 10), 42);
+        transport.simulator.registers.insert((3, 10), 1);
+        transport.simulator.registers.insert((3, 100), 2);
+        let config: ModbusRtuConfig = toml::from_str(r#"
+            device = "/dev/null"
+            frequency_secs = 0
+            registers.meter = { address = 10, unit_id = 2 }
+            registers.unpowered = { address = 10, unit_id = 3 }
+            registers.unpowered2 = { address = 100, unit_id = 3 }
+            sunspec = {}
+        "#).unwrap();
+        let reads = plan_reads(&config.poll.registers, config.poll.unit_id);
+        assert_eq!(reads.len(), 3);
+        let res = poll(&mut transport, &reads).await.unwrap();
+        assert_eq!(Value::Object(res), json!({ "meter": 42 }));
+        // the second read of the silent unit is skipped
+        assert_eq!(transport.timeouts, 1);
+
+        // the SunSpec models of another unit are still read
+        let value = poll_stream(transport, config.poll).boxed().next().await.unwrap();
+        assert_eq!(value["meter"], json!(42));
+        assert_eq!(value.pointer("/inverter/W"), Some(&json!(1234)));
+    }
+
+    #[tokio::test]
+    async fn sunspec_discovery() {
+        let mut transport = DirectTransport { silent: vec![40000], ..DirectTransport::default() };
+        sunspec_registers(&mut transport.simulator, 1, 50000);
+        // the default base addresses are tried after a timeout
+        let models = sunspec::discover(&mut transport, 1, None).await.map_err(|e| e.to_string()).unwrap();
+        assert_eq!(models.iter().map(|model| (model.id, model.address)).collect::<Vec<_>>(), [(1, 50004), (103, 50072)]);
+        // but not a configured base address
+        assert!(matches!(sunspec::discover(&mut transport, 1, Some(40000)).await, Err(Error::Transport(_))));
+
+        // model exceeding the register space
+        for (i, word) in [0x5375, 0x6e53, 1, 100].into_iter().enumerate() {
+            transport.simulator.registers.insert((2, 65500 + i as u16), word);
+        }
+        let Err(Error::Transport(e)) = sunspec::discover(&mut transport, 2, Some(65500)).await else { panic!("model is accepted") };
+        assert!(e.contains("exceeds the register space"), "{e}");
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(&[0x01, 0x04, 0x02, 0xff, 0xff]), 0x80b8);
+    }
+}
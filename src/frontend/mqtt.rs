@@ -1,61 +1,141 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
 use std::sync::{Arc, Mutex as StdMutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use futures::Stream;
 use regex::Regex;
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
+use rumqttc::{v5, AsyncClient, Event, Incoming, MqttOptions, QoS, TlsConfiguration, Transport};
+use rumqttc::v5::mqttbytes::v5::ConnectProperties;
 use serde_json::{json, Value};
-use tokio::sync::broadcast::{self, Sender};
-use tokio_stream::StreamExt;
-use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
-use crate::config::MqttConfig;
+use tokio::sync::mpsc;
+use crate::config::{MqttConfig, MqttTls, MqttVersion, QueueOverflow};
+use crate::frontend::payload::PayloadDecoder;
+use crate::frontend::queue::{self, Queue};
 
 pub struct MqttFrontend {
-    client: AsyncClient,
-    receivers: Arc<StdMutex<Vec<(Regex, Sender<Value>)>>>,
+    client: Client,
+    receivers: Receivers,
+    /// topics and QoS of all subscriptions, re-subscribed after reconnecting without session
+    subscriptions: Arc<StdMutex<Vec<(String, QoS)>>>,
+    qos: QoS,
+    config: MqttConfig,
 }
 
+type Receivers = Arc<StdMutex<Vec<(Regex, Arc<Queue>)>>>;
+
+/// interval in which increased drop counters are logged
+const DROP_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
 impl MqttFrontend {
     pub async fn new(config: &MqttConfig) -> Self {
-        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
-        options.set_keep_alive(Duration::from_secs(10));
-        if let Some(auth) = &config.auth {
-            options.set_credentials(&auth.username, &auth.password);
-        }
-        let (client, mut eventloop) = AsyncClient::new(options, 10);
-        let receivers: Arc<StdMutex<Vec<(Regex, Sender<Value>)>>> = Arc::new(StdMutex::new(Vec::new()));
+        let transport = config.tls.as_ref().map(tls_transport);
+        let (client, mut eventloop) = match config.version {
+            MqttVersion::V4 => {
+                let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+                options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+                options.set_clean_session(config.clean_session);
+                options.set_inflight(config.inflight);
+                options.set_manual_acks(true);
+                if let Some(auth) = &config.auth {
+                    options.set_credentials(&auth.username, &auth.password);
+                }
+                if let Some(transport) = transport {
+                    options.set_transport(transport);
+                }
+                let (client, eventloop) = AsyncClient::new(options, request_capacity(config));
+                (Client::V4(client), EventLoop::V4(Box::new(eventloop)))
+            }
+            MqttVersion::V5 => {
+                let mut options = v5::MqttOptions::new(&config.client_id, &config.host, config.port);
+                if !config.clean_session {
+                    // keep the session as long as possible after disconnecting
+                    let mut properties = ConnectProperties::new();
+                    properties.session_expiry_interval = Some(u32::MAX);
+                    options.set_connect_properties(properties);
+                }
+                options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+                options.set_clean_start(config.clean_session);
+                options.set_outgoing_inflight_upper_limit(config.inflight);
+                options.set_manual_acks(true);
+                if let Some(auth) = &config.auth {
+                    options.set_credentials(&auth.username, &auth.password);
+                }
+                if let Some(transport) = transport {
+                    options.set_transport(transport);
+                }
+                let (client, eventloop) = v5::AsyncClient::new(options, request_capacity(config));
+                (Client::V5(client), EventLoop::V5(Box::new(eventloop)))
+            }
+        };
+        let receivers: Receivers = Arc::new(StdMutex::new(Vec::new()));
+        let unmatched = Arc::new(AtomicU64::new(0));
+        tokio::spawn(log_drops(Arc::clone(&receivers), Arc::clone(&unmatched)));
+        let subscriptions: Arc<StdMutex<Vec<(String, QoS)>>> = Arc::new(StdMutex::new(Vec::new()));
+
+        let (messages_tx, messages_rx) = mpsc::channel(config.queue_size);
+        let decoder = PayloadDecoder::new(&config.payload);
+        tokio::spawn(forward(messages_rx, Arc::clone(&receivers), unmatched, decoder, config.metadata, client.clone()));
 
-        let receivers2 = Arc::clone(&receivers);
+        let subscriptions2 = Arc::clone(&subscriptions);
+        let client2 = client.clone();
+        // the eventloop sends pings when polled after the keep-alive interval
+        let ping_interval = Duration::from_millis(config.keep_alive_secs.max(1) * 500);
+        let max_held = config.queue_size;
         tokio::spawn(async move {
+            let mut connected = false;
+            // messages received while `forward` can't take more (only with `overflow = "block"`)
+            let mut held = VecDeque::new();
             loop {
-                match eventloop.poll().await {
-                    Ok(Event::Incoming(Incoming::Publish(p))) => {
-                        let value: Value = match serde_json::from_slice(&p.payload) {
-                            Ok(value) => value,
-                            // if it's not JSON, interpret it as simple String
-                            Err(_) => Value::String(String::from_utf8_lossy(&p.payload).into_owned()),
-                        };
-                        let value = json!({ &p.topic: value });
-                        let receivers = receivers2.lock().unwrap();
-                        let senders = receivers.iter()
-                            .filter_map(|(regex, sender)| regex.is_match(&p.topic).then_some(sender));
-                        let mut sent = false;
-                        for sender in senders {
-                            sender.send(value.clone()).unwrap();
-                            sent = true;
+                if let Some(message) = held.pop_front() {
+                    // keep polling the eventloop every half keep-alive interval, such that the
+                    // connection stays alive, until too many messages are held back
+                    let permit = match held.len() + 1 < max_held {
+                        true => tokio::time::timeout(ping_interval, messages_tx.reserve()).await.ok(),
+                        false => Some(messages_tx.reserve().await),
+                    };
+                    match permit {
+                        Some(Ok(permit)) => {
+                            permit.send(message);
+                            continue
                         }
-                        if !sent {
-                            eprintln!("got message for topic {:?} but can't find any subscriber", p.topic);
+                        // `forward` ended
+                        Some(Err(_)) => return,
+                        None => held.push_front(message),
+                    }
+                }
+                match eventloop.poll().await {
+                    Ok(Received::Publish(message)) => held.push_back(message),
+                    Ok(Received::ConnAck { session_present }) => {
+                        // subscriptions are sent by `stream` when connecting initially
+                        if !std::mem::replace(&mut connected, true) || session_present {
+                            continue
                         }
-                    },
-                    Err(x) => eprintln!("error in mqtt: {x:?}"),
-                    _ => (),
+                        // the broker doesn't know our subscriptions (anymore)
+                        let subscriptions = subscriptions2.lock().unwrap().clone();
+                        let client = client2.clone();
+                        // subscribe in a separate task, as the request channel is drained by polling the eventloop
+                        tokio::spawn(async move {
+                            for (topic, qos) in subscriptions {
+                                if let Err(e) = client.subscribe(&topic, qos).await {
+                                    eprintln!("error re-subscribing to mqtt topic {topic:?}: {e}");
+                                }
+                            }
+                        });
+                    }
+                    Ok(Received::Other) => (),
+                    Err(e) => {
+                        eprintln!("error in mqtt: {e}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
                 }
             }
         });
-        MqttFrontend { client, receivers }
+        let qos = qos(config.qos);
+        MqttFrontend { client, receivers, subscriptions, qos, config: config.clone() }
     }
 
-    pub async fn stream(&self, topic: String) -> impl Stream<Item = Value> {
+    pub async fn stream(&self, topic: String, qos: Option<u8>) -> impl Stream<Item = Value> {
         // convert topic into a regex
         let pattern = regex::escape(&topic);
         // topic wildcard `+` matches everything in a single-level
@@ -68,25 +148,381 @@ impl MqttFrontend {
         };
         // pattern must match the full string
         let pattern = format!("^{pattern}$");
-        let rx = {
+        let queue = {
             let mut receivers = self.receivers.lock().unwrap();
-            let receiver = receivers.iter().find(|(regex, _)| regex.as_str() == &pattern);
-            match receiver {
-                Some((_topic, sender)) => sender.subscribe(),
-                None => {
-                    receivers.push((Regex::new(&pattern).unwrap(), broadcast::channel(200).0));
-                    receivers.last().unwrap().1.subscribe()
-                },
-            }
+            let spill_file = match self.config.overflow {
+                QueueOverflow::SpillToDisk => {
+                    let directory = self.config.spill_directory.as_ref()
+                        .expect("mqtt overflow = \"spill-to-disk\" requires spill_directory");
+                    // subscriptions of the same topic are numbered in config order
+                    let index = receivers.iter().filter(|(regex, _)| regex.as_str() == pattern).count();
+                    let name: String = topic.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+                    Some(Path::new(directory).join(format!("{}-{name}-{index}.jsonl", self.config.client_id)))
+                }
+                _ => None,
+            };
+            let queue = Queue::new(format!("mqtt subscription {topic:?}"), self.config.queue_size, self.config.overflow, spill_file);
+            receivers.push((Regex::new(&pattern).unwrap(), Arc::clone(&queue)));
+            queue
+        };
+        let qos = qos.map(self::qos).unwrap_or(self.qos);
+        self.subscriptions.lock().unwrap().push((topic.clone(), qos));
+        self.client.subscribe(&topic, qos).await.unwrap();
+        queue::stream(queue)
+    }
+}
+
+/// capacity of the client's request channel
+///
+/// While the eventloop waits for `forward`, it takes up to `queue_size` messages from the channel
+/// and `queue_size` held back ones without the eventloop sending their acks.
+fn request_capacity(config: &MqttConfig) -> usize {
+    2 * config.queue_size + 10
+}
+
+/// Queues received messages for all matching subscriptions, acknowledging them afterwards.
+///
+/// With `overflow = "block"` a full queue blocks this task, which stalls the eventloop once
+/// `queue_size` messages are waiting in the channel and another `queue_size` are held back.
+/// As QoS 1 / 2 messages are only acknowledged once queued, the broker stops sending
+/// when its in-flight limit is reached. Other overflow policies never block, so messages
+/// are dropped or spilled by the subscriber queues, where they are counted.
+async fn forward(
+    mut messages: mpsc::Receiver<Message>, receivers: Receivers, unmatched: Arc<AtomicU64>,
+    decoder: PayloadDecoder, metadata: bool, client: Client,
+) {
+    while let Some(Message { topic, payload, retain, qos, ack }) = messages.recv().await {
+        let payload = String::from_utf8_lossy(&payload);
+        let value = match decoder.decode(&payload) {
+            Ok(value) => value,
+            // if it can't be decoded, interpret it as simple String
+            Err(_) => Value::String(payload.into_owned()),
         };
-        self.client.subscribe(&topic, QoS::AtMostOnce).await.unwrap();
-        BroadcastStream::new(rx)
-            .filter_map(move |val| match val {
-                Ok(val) => Some(val),
-                Err(BroadcastStreamRecvError::Lagged(num)) => {
-                    eprintln!("mqtt receiver for {topic} lagged by {num}");
-                    None
+        let queues: Vec<_> = receivers.lock().unwrap().iter()
+            .filter_map(|(regex, queue)| regex.is_match(&topic).then(|| Arc::clone(queue)))
+            .collect();
+        if queues.is_empty() {
+            unmatched.fetch_add(1, Ordering::Relaxed);
+            eprintln!("got message for topic {topic:?} but can't find any subscriber");
+        }
+        for queue in queues {
+            let value = match metadata {
+                true => json!({
+                    "topic": &topic,
+                    "_topic": topic.split('/').collect::<Vec<_>>(),
+                    "payload": value,
+                    "retain": retain,
+                    "qos": qos,
+                    "dropped": queue.dropped(),
+                }),
+                false => json!({ &topic: value }),
+            };
+            // blocks with `overflow = "block"` if the queue is full
+            queue.push(value).await;
+        }
+        if let Err(e) = client.ack(&ack).await {
+            eprintln!("error acknowledging mqtt message of topic {topic:?}: {e}");
+        }
+    }
+}
+
+/// log increased drop counters of all subscriptions
+async fn log_drops(receivers: Receivers, unmatched: Arc<AtomicU64>) {
+    let mut last_unmatched = 0;
+    let mut last_dropped = HashMap::new();
+    loop {
+        tokio::time::sleep(DROP_LOG_INTERVAL).await;
+        let unmatched = unmatched.load(Ordering::Relaxed);
+        if unmatched > last_unmatched {
+            eprintln!("mqtt: {unmatched} messages without subscriber in total");
+            last_unmatched = unmatched;
+        }
+        let queues: Vec<_> = receivers.lock().unwrap().iter().map(|(_, queue)| Arc::clone(queue)).collect();
+        for (index, queue) in queues.iter().enumerate() {
+            let dropped = queue.dropped();
+            let last = last_dropped.entry(index).or_insert(0);
+            if dropped > *last {
+                eprintln!("mqtt: {dropped} messages of {} dropped in total because its queue was full", queue.name());
+                *last = dropped;
+            }
+        }
+    }
+}
+
+fn qos(qos: u8) -> QoS {
+    rumqttc::qos(qos).unwrap_or_else(|_| panic!("invalid mqtt qos {qos}, must be 0, 1 or 2"))
+}
+
+fn tls_transport(tls: &MqttTls) -> Transport {
+    let read = |path: &str| std::fs::read(path)
+        .unwrap_or_else(|e| panic!("can't read mqtt tls file {path:?}: {e}"));
+    let client_auth = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => Some((read(cert), read(key))),
+        (None, None) => None,
+        _ => panic!("mqtt tls requires both client_cert and client_key for client authentication"),
+    };
+    match &tls.ca {
+        Some(ca) => Transport::tls(read(ca), client_auth, None),
+        None if client_auth.is_none() => Transport::tls_with_config(TlsConfiguration::default()),
+        None => panic!("mqtt tls client authentication requires ca"),
+    }
+}
+
+/// client of either protocol version
+#[derive(Clone)]
+enum Client {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+impl Client {
+    async fn subscribe(&self, topic: &str, qos: QoS) -> Result<(), String> {
+        match self {
+            Client::V4(client) => client.subscribe(topic, qos).await.map_err(|e| e.to_string()),
+            Client::V5(client) => client.subscribe(topic, v5_qos(qos)).await.map_err(|e| e.to_string()),
+        }
+    }
+
+    async fn ack(&self, ack: &Ack) -> Result<(), String> {
+        match (self, ack) {
+            (Client::V4(client), Ack::V4(publish)) => client.ack(publish).await.map_err(|e| e.to_string()),
+            (Client::V5(client), Ack::V5(publish)) => client.ack(publish).await.map_err(|e| e.to_string()),
+            _ => unreachable!("client and message of different mqtt versions"),
+        }
+    }
+}
+
+fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+/// eventloop of either protocol version
+enum EventLoop {
+    V4(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+/// protocol-independent incoming event
+enum Received {
+    Publish(Message),
+    ConnAck { session_present: bool },
+    Other,
+}
+struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+    qos: u8,
+    ack: Ack,
+}
+/// received publish, to acknowledge it with manual acks
+enum Ack {
+    V4(Box<rumqttc::Publish>),
+    V5(Box<v5::mqttbytes::v5::Publish>),
+}
+impl EventLoop {
+    async fn poll(&mut self) -> Result<Received, String> {
+        match self {
+            EventLoop::V4(eventloop) => match eventloop.poll().await.map_err(|e| format!("{e:?}"))? {
+                Event::Incoming(Incoming::Publish(p)) => Ok(Received::Publish(Message {
+                    topic: p.topic.clone(),
+                    payload: p.payload.to_vec(),
+                    retain: p.retain,
+                    qos: p.qos as u8,
+                    ack: Ack::V4(Box::new(p)),
+                })),
+                Event::Incoming(Incoming::ConnAck(ack)) => Ok(Received::ConnAck { session_present: ack.session_present }),
+                _ => Ok(Received::Other),
+            },
+            EventLoop::V5(eventloop) => match eventloop.poll().await.map_err(|e| format!("{e:?}"))? {
+                v5::Event::Incoming(v5::Incoming::Publish(p)) => Ok(Received::Publish(Message {
+                    topic: String::from_utf8_lossy(&p.topic).into_owned(),
+                    payload: p.payload.to_vec(),
+                    retain: p.retain,
+                    qos: p.qos as u8,
+                    ack: Ack::V5(Box::new(p)),
+                })),
+                v5::Event::Incoming(v5::Incoming::ConnAck(ack)) => Ok(Received::ConnAck { session_present: ack.session_present }),
+                _ => Ok(Received::Other),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use super::*;
+
+    /// reads the packet type and content of an MQTT packet
+    async fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let header = stream.read_u8().await.ok()?;
+        let mut len = 0;
+        for shift in (0..28).step_by(7) {
+            let byte = stream.read_u8().await.ok()?;
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break
+            }
+        }
+        let mut content = vec![0; len];
+        stream.read_exact(&mut content).await.ok()?;
+        Some((header >> 4, content))
+    }
+
+    fn publish(topic: &str, payload: &str) -> Vec<u8> {
+        let len = 2 + topic.len() + payload.len();
+        [&[0x30, len as u8, 0, topic.len() as u8], topic.as_bytes(), payload.as_bytes()].concat()
+    }
+
+    /// MQTT 3.1.1 broker without sessions, which publishes one message to the first subscription
+    /// of each connection and closes the first connection afterwards; reports the number of
+    /// subscriptions per connection
+    async fn broker(subscribes: mpsc::UnboundedSender<usize>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for connection in 0.. {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut count = 0;
+                loop {
+                    let packet = match connection {
+                        // wait for duplicate subscriptions before closing the connection
+                        0 if count > 0 => match tokio::time::timeout(Duration::from_millis(200), read_packet(&mut stream)).await {
+                            Ok(packet) => packet,
+                            Err(_) => break,
+                        },
+                        _ => read_packet(&mut stream).await,
+                    };
+                    let Some((kind, content)) = packet else { break };
+                    match kind {
+                        // CONNECT -> CONNACK without session
+                        1 => stream.write_all(&[0x20, 2, 0, 0]).await.unwrap(),
+                        // SUBSCRIBE -> SUBACK
+                        8 => {
+                            stream.write_all(&[0x90, 3, content[0], content[1], 0]).await.unwrap();
+                            count += 1;
+                            subscribes.send(count).unwrap();
+                            if count == 1 {
+                                let topic = format!("test/{connection}");
+                                stream.write_all(&publish(&topic, &connection.to_string())).await.unwrap();
+                            }
+                        }
+                        // PINGREQ -> PINGRESP
+                        12 => stream.write_all(&[0xd0, 0]).await.unwrap(),
+                        _ => (),
+                    }
                 }
-            })
+                subscribes.send(0).unwrap();
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn resubscribe_after_reconnect() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let port = broker(tx).await;
+        let config: MqttConfig = toml::from_str(&format!(r#"
+            host = "127.0.0.1"
+            port = {port}
+        "#)).unwrap();
+        let frontend = MqttFrontend::new(&config).await;
+        let mut stream = frontend.stream("test/+".to_string(), None).await.boxed();
+        assert_eq!(stream.next().await.unwrap(), json!({ "test/0": 0 }));
+        // only subscribed once on the first connection, which is closed by the broker
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, Some(0));
+        // subscribed again after reconnecting
+        assert_eq!(stream.next().await.unwrap(), json!({ "test/1": 1 }));
+        assert_eq!(rx.recv().await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn block_acks_once_queued() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (acks_tx, mut acks) = mpsc::unbounded_channel();
+        // broker publishing three QoS 1 messages, reporting the packet ids of PUBACKs
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            while let Some((kind, content)) = read_packet(&mut stream).await {
+                match kind {
+                    1 => stream.write_all(&[0x20, 2, 0, 0]).await.unwrap(),
+                    8 => {
+                        stream.write_all(&[0x90, 3, content[0], content[1], 1]).await.unwrap();
+                        for id in 1..=3 {
+                            stream.write_all(&[0x32, 8, 0, 3, b'a', b'/', b'b', 0, id, b'0' + id]).await.unwrap();
+                        }
+                    }
+                    4 => acks_tx.send(content[1]).unwrap(),
+                    12 => stream.write_all(&[0xd0, 0]).await.unwrap(),
+                    _ => (),
+                }
+            }
+        });
+        let config: MqttConfig = toml::from_str(&format!(r#"
+            host = "127.0.0.1"
+            port = {port}
+            qos = 1
+            queue_size = 1
+        "#)).unwrap();
+        let frontend = MqttFrontend::new(&config).await;
+        let mut stream = frontend.stream("a/+".to_string(), None).await.boxed();
+        // the first message is queued, the second one waits for space
+        assert_eq!(acks.recv().await, Some(1));
+        assert!(tokio::time::timeout(Duration::from_millis(300), acks.recv()).await.is_err());
+        for i in 1..=3 {
+            assert_eq!(stream.next().await.unwrap(), json!({ "a/b": i }));
+        }
+        assert_eq!(acks.recv().await, Some(2));
+        assert_eq!(acks.recv().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn block_keeps_connection_alive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (pings_tx, mut pings) = mpsc::unbounded_channel();
+        // broker publishing more QoS 0 messages than fit into the queue and the channel
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            while let Some((kind, content)) = read_packet(&mut stream).await {
+                match kind {
+                    1 => stream.write_all(&[0x20, 2, 0, 0]).await.unwrap(),
+                    8 => {
+                        stream.write_all(&[0x90, 3, content[0], content[1], 0]).await.unwrap();
+                        for i in 1..=6 {
+                            stream.write_all(&publish("a/b", &i.to_string())).await.unwrap();
+                        }
+                    }
+                    12 => {
+                        stream.write_all(&[0xd0, 0]).await.unwrap();
+                        pings_tx.send(()).unwrap();
+                    }
+                    _ => (),
+                }
+            }
+        });
+        let config: MqttConfig = toml::from_str(&format!(r#"
+            host = "127.0.0.1"
+            port = {port}
+            keep_alive_secs = 1
+            queue_size = 2
+        "#)).unwrap();
+        let frontend = MqttFrontend::new(&config).await;
+        let mut stream = frontend.stream("a/+".to_string(), None).await.boxed();
+        // two messages queued, one waiting in `forward`, two in the channel and one held back
+        for _ in 0..2 {
+            tokio::time::timeout(Duration::from_secs(3), pings.recv()).await.unwrap();
+        }
+        for i in 1..=6 {
+            assert_eq!(stream.next().await.unwrap(), json!({ "a/b": i }));
+        }
     }
 }
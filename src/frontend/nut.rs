@@ -0,0 +1,270 @@
+//! Network UPS Tools client polling the variables of UPS from `upsd`, see the
+//! [network protocol](https://networkupstools.org/docs/developer-guide.chunked/net-protocol.html)
+
+use std::fmt;
+use std::time::Duration;
+use futures::Stream;
+use serde_json::{Map, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use crate::config::NutConfig;
+
+pub fn stream(config: NutConfig) -> impl Stream<Item = Value> + 'static {
+    let frequency = Duration::from_secs(config.frequency_secs as u64);
+    let timeout = Duration::from_secs(config.timeout_secs as u64);
+    // the connection is kept open between polls and reestablished after errors
+    futures::stream::unfold((config, None, 0), move |(config, mut connection, mut iteration)| async move {
+        loop {
+            if iteration != 0 {
+                tokio::time::sleep(frequency).await;
+            }
+            iteration += 1;
+            let res = match tokio::time::timeout(timeout, poll(&config, &mut connection)).await {
+                Ok(Ok(res)) => res,
+                Ok(Err(e)) => {
+                    eprintln!("nut: {e} - retry");
+                    connection = None;
+                    continue
+                }
+                Err(_) => {
+                    eprintln!("nut: timeout polling {}:{} - retry", config.host, config.port);
+                    connection = None;
+                    continue
+                }
+            };
+            if res.is_empty() {
+                eprintln!("nut: no UPS variables received - retry");
+                continue
+            }
+            return Some((Value::Object(res), (config, connection, iteration)));
+        }
+    })
+}
+
+/// variables of each UPS, keyed by UPS name and variable name
+async fn poll(config: &NutConfig, connection: &mut Option<Connection>) -> Result<Map<String, Value>, Error> {
+    if connection.is_none() {
+        *connection = Some(Connection::connect(config).await?);
+    }
+    let connection = connection.as_mut().unwrap();
+    let names = match config.ups.is_empty() {
+        true => connection.list("UPS").await?.into_iter().filter_map(|mut words| (words.len() > 1).then(|| words.swap_remove(1))).collect(),
+        false => config.ups.clone(),
+    };
+    let mut res = Map::new();
+    for name in names {
+        let vars = match connection.list(&format!("VAR {name}")).await {
+            Ok(vars) => vars,
+            // e.g. DATA-STALE if the driver lost the UPS, other UPS are unaffected
+            Err(Error::Nut(e)) => {
+                eprintln!("nut: error listing variables of UPS {name}: {e}");
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        let vars = vars.into_iter()
+            .filter_map(|words| match <[String; 4]>::try_from(words) {
+                Ok([_, _, var, value]) => Some((var, Value::String(value))),
+                Err(_) => None,
+            }).collect();
+        res.insert(name, Value::Object(vars));
+    }
+    Ok(res)
+}
+
+enum Error {
+    /// `ERR` response of upsd
+    Nut(String),
+    Connection(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Nut(e) => write!(f, "ERR {e}"),
+            Error::Connection(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl Connection {
+    async fn connect(config: &NutConfig) -> Result<Self, Error> {
+        let address = format!("{}:{}", config.host, config.port);
+        let stream = TcpStream::connect(&address).await
+            .map_err(|e| Error::Connection(format!("can't connect to {address}: {e}")))?;
+        let (reader, writer) = stream.into_split();
+        let mut connection = Connection { reader: BufReader::new(reader), writer };
+        if let Some(username) = &config.username {
+            connection.command(&format!("USERNAME {}", quote(username))).await?;
+        }
+        if let Some(password) = &config.password {
+            connection.command(&format!("PASSWORD {}", quote(password))).await?;
+        }
+        Ok(connection)
+    }
+
+    /// sends a command and returns the first line of its response
+    async fn command(&mut self, command: &str) -> Result<String, Error> {
+        self.writer.write_all(format!("{command}\n").as_bytes()).await
+            .map_err(|e| Error::Connection(format!("error sending command: {e}")))?;
+        let line = self.read_line().await?;
+        match line.strip_prefix("ERR ") {
+            Some(e) => Err(Error::Nut(e.to_string())),
+            None => Ok(line),
+        }
+    }
+
+    /// words of each line of `LIST <query>`
+    async fn list(&mut self, query: &str) -> Result<Vec<Vec<String>>, Error> {
+        let begin = self.command(&format!("LIST {query}")).await?;
+        if begin != format!("BEGIN LIST {query}") {
+            return Err(Error::Connection(format!("unexpected response to LIST {query}: {begin:?}")));
+        }
+        let end = format!("END LIST {query}");
+        let mut res = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if line == end {
+                return Ok(res);
+            }
+            res.push(split_words(&line));
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line).await {
+            Ok(0) => Err(Error::Connection("connection closed by upsd".to_string())),
+            Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) => Err(Error::Connection(format!("error reading response: {e}"))),
+        }
+    }
+}
+
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// splits a line into words, which are separated by spaces or quoted with `\` escapes
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => continue,
+            '"' => {
+                let mut word = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => word.extend(chars.next()),
+                        c => word.push(c),
+                    }
+                }
+                words.push(word);
+            }
+            c => {
+                let mut word = c.to_string();
+                for c in chars.by_ref() {
+                    if c == ' ' {
+                        break;
+                    }
+                    word.push(c);
+                }
+                words.push(word);
+            }
+        }
+    }
+    words
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use futures::StreamExt;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use super::*;
+
+    /// fake upsd with a healthy and a stale UPS, requiring login
+    async fn upsd(connections: Arc<AtomicUsize>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                connections.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    let mut logged_in = false;
+                    while let Some(line) = lines.next_line().await.unwrap() {
+                        let response = match line.as_str() {
+                            r#"USERNAME "monuser""# => "OK\n",
+                            r#"PASSWORD "secret \"pw\"""# => {
+                                logged_in = true;
+                                "OK\n"
+                            }
+                            _ if !logged_in => "ERR ACCESS-DENIED\n",
+                            "LIST UPS" => "BEGIN LIST UPS\nUPS cyberpower \"CyberPower Value2200E\"\nUPS garage \"Garage UPS\"\nEND LIST UPS\n",
+                            "LIST VAR cyberpower" => concat!(
+                                "BEGIN LIST VAR cyberpower\n",
+                                "VAR cyberpower battery.charge \"97\"\n",
+                                "VAR cyberpower battery.runtime \"1440\"\n",
+                                "VAR cyberpower device.mfr \"CPS\"\n",
+                                "VAR cyberpower device.model \"Value2200E \\\"EU\\\"\"\n",
+                                "VAR cyberpower ups.load \"22\"\n",
+                                "VAR cyberpower ups.status \"OL CHRG\"\n",
+                                "END LIST VAR cyberpower\n",
+                            ),
+                            "LIST VAR garage" => "ERR DATA-STALE\n",
+                            _ => "ERR UNKNOWN-COMMAND\n",
+                        };
+                        writer.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn list_vars() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let port = upsd(Arc::clone(&connections)).await;
+        let config: NutConfig = toml::from_str(&format!(r#"
+            host = "127.0.0.1"
+            port = {port}
+            username = "monuser"
+            password = 'secret "pw"'
+            frequency_secs = 0
+        "#)).unwrap();
+        let mut stream = stream(config).boxed();
+        let expected = json!({
+            "cyberpower": {
+                "battery.charge": "97",
+                "battery.runtime": "1440",
+                "device.mfr": "CPS",
+                "device.model": "Value2200E \"EU\"",
+                "ups.load": "22",
+                "ups.status": "OL CHRG",
+            },
+        });
+        assert_eq!(stream.next().await.unwrap(), expected);
+        assert_eq!(stream.next().await.unwrap(), expected);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(split_words(r#"VAR ups ups.status "OL \"CHRG\" \\ " "#), ["VAR", "ups", "ups.status", r#"OL "CHRG" \ "#]);
+        assert_eq!(split_words(r#"VAR ups empty """#), ["VAR", "ups", "empty", ""]);
+    }
+}
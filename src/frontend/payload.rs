@@ -0,0 +1,204 @@
+use indexmap::IndexMap;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::{Map, Value};
+use crate::config::{Payload, PayloadFormat};
+use crate::frontend::html::{self, Selector};
+
+/// Decodes text payloads of frontends into JSON according to their configured `format`
+pub struct PayloadDecoder {
+    config: Payload,
+    selectors: IndexMap<String, Selector>,
+}
+
+impl PayloadDecoder {
+    pub fn new(config: &Payload) -> Self {
+        let selectors: IndexMap<_, _> = config.selectors.iter()
+            .map(|(name, selector)| (name.clone(), Selector::parse(selector).unwrap_or_else(|e| panic!("invalid html selector {selector:?}: {e}"))))
+            .collect();
+        assert!(selectors.is_empty() || config.format == PayloadFormat::Html, "selectors require format = \"html\"");
+        PayloadDecoder { config: config.clone(), selectors }
+    }
+
+    pub fn format(&self) -> PayloadFormat {
+        self.config.format
+    }
+
+    pub fn decode(&self, text: &str) -> Result<Value, String> {
+        match self.config.format {
+            PayloadFormat::Json => serde_json::from_str(text).map_err(|e| format!("invalid json: {e}")),
+            PayloadFormat::Html if self.selectors.is_empty() => Ok(html::to_json(text)),
+            PayloadFormat::Html => Ok(html::select(text, &self.selectors)),
+            PayloadFormat::Xml => xml_to_json(text),
+            PayloadFormat::Csv => csv_to_json(text, self.config.csv_delimiter, self.config.csv_header),
+            PayloadFormat::KeyValue => Ok(key_value_to_json(text)),
+            PayloadFormat::Scalar => Ok(scalar_to_json(text)),
+        }
+    }
+}
+
+/// Converts XML to JSON with the same conventions as `html::to_json`:
+/// attributes as `@name`, text as `#text` and child elements grouped by name as arrays.
+fn xml_to_json(xml: &str) -> Result<Value, String> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    // stack of currently open elements: (name, element, text); the first one is the document
+    let mut stack = vec![(String::new(), Map::new(), String::new())];
+    loop {
+        let event = reader.read_event()
+            .map_err(|e| format!("invalid xml at position {}: {e}", reader.buffer_position()))?;
+        match event {
+            Event::Start(start) => {
+                let (name, element) = xml_element(&start)?;
+                stack.push((name, element, String::new()));
+            }
+            Event::Empty(start) => {
+                let (name, element) = xml_element(&start)?;
+                push_child(&mut stack.last_mut().unwrap().1, name, Value::Object(element));
+            }
+            Event::Text(text) => {
+                let text = text.unescape().map_err(|e| format!("invalid xml text: {e}"))?;
+                stack.last_mut().unwrap().2.push_str(&text);
+            }
+            Event::CData(cdata) => {
+                stack.last_mut().unwrap().2.push_str(&String::from_utf8_lossy(&cdata));
+            }
+            Event::End(_) => {
+                if stack.len() < 2 {
+                    return Err("unmatched closing tag in xml".to_string());
+                }
+                let (name, mut element, text) = stack.pop().unwrap();
+                if !text.is_empty() {
+                    element.insert("#text".to_string(), Value::String(text));
+                }
+                push_child(&mut stack.last_mut().unwrap().1, name, Value::Object(element));
+            }
+            Event::Eof => break,
+            Event::Comment(_) | Event::Decl(_) | Event::PI(_) | Event::DocType(_) => (),
+        }
+    }
+    if stack.len() != 1 {
+        return Err("unclosed tag in xml".to_string());
+    }
+    Ok(Value::Object(stack.pop().unwrap().1))
+}
+
+fn xml_element(start: &BytesStart) -> Result<(String, Map<String, Value>), String> {
+    let name = String::from_utf8_lossy(start.local_name().as_ref()).into_owned();
+    let mut element = Map::new();
+    for attr in start.attributes() {
+        let attr = attr.map_err(|e| format!("invalid xml attribute: {e}"))?;
+        let key = String::from_utf8_lossy(attr.key.local_name().as_ref()).into_owned();
+        let value = attr.unescape_value().map_err(|e| format!("invalid xml attribute value: {e}"))?;
+        element.insert(format!("@{key}"), Value::String(value.into_owned()));
+    }
+    Ok((name, element))
+}
+
+fn push_child(parent: &mut Map<String, Value>, name: String, child: Value) {
+    if let Value::Array(children) = parent.entry(name).or_insert_with(|| Value::Array(Vec::new())) {
+        children.push(child);
+    }
+}
+
+/// Converts CSV to an array of rows; with a header, rows are objects keyed by the header's columns.
+fn csv_to_json(text: &str, delimiter: char, header: bool) -> Result<Value, String> {
+    let delimiter = u8::try_from(delimiter).map_err(|_| format!("csv delimiter {delimiter:?} must be ascii"))?;
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(header)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+    let headers = match header {
+        true => Some(reader.headers().map_err(|e| format!("invalid csv header: {e}"))?.clone()),
+        false => None,
+    };
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("invalid csv: {e}"))?;
+        let row = match &headers {
+            Some(headers) => Value::Object(headers.iter().zip(record.iter())
+                .map(|(key, value)| (key.to_string(), Value::String(value.to_string())))
+                .collect()),
+            None => Value::Array(record.iter().map(|value| Value::String(value.to_string())).collect()),
+        };
+        rows.push(row);
+    }
+    Ok(Value::Array(rows))
+}
+
+/// Converts `key=value` / `key: value` lines to an object; empty lines and lines without
+/// separator are ignored.
+fn key_value_to_json(text: &str) -> Value {
+    let map = text.lines().filter_map(|line| {
+        let separator = line.find(['=', ':'])?;
+        let key = line[..separator].trim();
+        let value = line[separator + 1..].trim();
+        (!key.is_empty()).then(|| (key.to_string(), Value::String(value.to_string())))
+    }).collect();
+    Value::Object(map)
+}
+
+/// Converts a plain number, boolean or string to JSON.
+fn scalar_to_json(text: &str) -> Value {
+    let text = text.trim();
+    match serde_json::from_str(text) {
+        Ok(value @ (Value::Number(_) | Value::Bool(_))) => value,
+        _ => Value::String(text.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use super::*;
+
+    fn decode(format: &str, text: &str) -> Value {
+        let config: Payload = toml::from_str(&format!("format = {format:?}")).unwrap();
+        PayloadDecoder::new(&config).decode(text).unwrap()
+    }
+
+    #[test]
+    fn xml() {
+        let xml = r#"<?xml version="1.0"?>
+            <Body>
+                <Data unit="W">
+                    <PAC>1234</PAC>
+                    <Empty/>
+                </Data>
+                <Data unit="Wh"><E>5 &amp; 6</E></Data>
+            </Body>"#;
+        assert_eq!(decode("xml", xml), json!({
+            "Body": [{
+                "Data": [
+                    { "@unit": "W", "PAC": [{ "#text": "1234" }], "Empty": [{}] },
+                    { "@unit": "Wh", "E": [{ "#text": "5 & 6" }] },
+                ],
+            }],
+        }));
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(decode("csv", "name, value\nfoo, 1\nbar, 2\n"), json!([
+            { "name": "foo", "value": "1" },
+            { "name": "bar", "value": "2" },
+        ]));
+    }
+
+    #[test]
+    fn key_value() {
+        assert_eq!(decode("key-value", "MemTotal:  16318504 kB\nfoo=bar\n\ninvalid line\n"), json!({
+            "MemTotal": "16318504 kB",
+            "foo": "bar",
+        }));
+    }
+
+    #[test]
+    fn scalar() {
+        assert_eq!(decode("scalar", "42.5\n"), json!(42.5));
+        assert_eq!(decode("scalar", "true"), json!(true));
+        assert_eq!(decode("scalar", " on "), json!("on"));
+    }
+}
@@ -0,0 +1,249 @@
+//! Bounded queue between a frontend and the data pipeline of one subscriber
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use futures::Stream;
+use serde_json::Value;
+use tokio::sync::{Mutex, Notify};
+use crate::config::QueueOverflow;
+
+pub struct Queue {
+    name: String,
+    capacity: usize,
+    overflow: QueueOverflow,
+    /// file messages are appended to while the queue is full (`spill-to-disk` only)
+    spill_file: Option<PathBuf>,
+    /// kept locked while accessing the spill file
+    state: Mutex<State>,
+    readable: Notify,
+    writable: Notify,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+}
+
+#[derive(Default)]
+struct State {
+    memory: VecDeque<Value>,
+    /// the spill file contains messages not read yet, which are newer than all in memory
+    spilled: bool,
+    /// position of the first unread message in the spill file
+    read_offset: u64,
+}
+
+impl Queue {
+    /// Creates a queue; messages left in an existing spill file are delivered first.
+    pub fn new(name: String, capacity: usize, overflow: QueueOverflow, spill_file: Option<PathBuf>) -> Arc<Queue> {
+        assert!(capacity > 0, "queue size of {name} must be at least 1");
+        assert_eq!(spill_file.is_some(), overflow == QueueOverflow::SpillToDisk, "spill-to-disk requires a spill file");
+        let spilled = spill_file.as_ref()
+            .and_then(|path| std::fs::metadata(path).ok())
+            .is_some_and(|meta| meta.len() > 0);
+        Arc::new(Queue {
+            name,
+            capacity,
+            overflow,
+            spill_file,
+            state: Mutex::new(State { spilled, ..State::default() }),
+            readable: Notify::new(),
+            writable: Notify::new(),
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// number of messages dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Adds a message, waiting for free space with `block`.
+    pub async fn push(&self, value: Value) {
+        loop {
+            if self.closed.load(Ordering::Relaxed) {
+                return;
+            }
+            {
+                let mut state = self.state.lock().await;
+                if state.memory.len() < self.capacity && !state.spilled {
+                    state.memory.push_back(value);
+                    self.readable.notify_one();
+                    return;
+                }
+                match self.overflow {
+                    QueueOverflow::Block => (),
+                    QueueOverflow::DropOldest => {
+                        state.memory.pop_front();
+                        state.memory.push_back(value);
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                    QueueOverflow::SpillToDisk => {
+                        let path = self.spill_file.clone().unwrap();
+                        let line = format!("{value}\n");
+                        let res = tokio::task::spawn_blocking(move || spill(&path, &line)).await.unwrap();
+                        match res {
+                            Ok(()) => state.spilled = true,
+                            Err(e) => {
+                                eprintln!("can't spill message of {} to disk, dropping it: {e}", self.name);
+                                self.dropped.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        self.readable.notify_one();
+                        return;
+                    }
+                }
+            }
+            self.writable.notified().await;
+        }
+    }
+
+    /// Removes the oldest message, waiting for one if the queue is empty.
+    pub async fn pop(&self) -> Value {
+        loop {
+            {
+                let mut state = self.state.lock().await;
+                if state.memory.is_empty() && state.spilled {
+                    if let Err(e) = self.unspill(&mut state).await {
+                        eprintln!("can't read spilled messages of {} from disk, dropping them: {e}", self.name);
+                        state.spilled = false;
+                    }
+                }
+                if let Some(value) = state.memory.pop_front() {
+                    self.writable.notify_one();
+                    return value;
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    /// move up to `capacity` messages from the spill file into memory
+    async fn unspill(&self, state: &mut State) -> io::Result<()> {
+        let path = self.spill_file.clone().unwrap();
+        let (offset, max) = (state.read_offset, self.capacity - state.memory.len());
+        let (values, offset) = tokio::task::spawn_blocking(move || read_spilled(&path, offset, max)).await.unwrap()?;
+        state.memory.extend(values);
+        match offset {
+            Some(offset) => state.read_offset = offset,
+            None => {
+                state.spilled = false;
+                state.read_offset = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn spill(path: &Path, line: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())
+}
+
+/// Reads up to `max` messages from the spill file starting at `offset`, returning them and the
+/// offset of the next unread message, or `None` if everything is read and the file was emptied.
+fn read_spilled(path: &Path, mut offset: u64, max: usize) -> io::Result<(Vec<Value>, Option<u64>)> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    let mut values = Vec::new();
+    while values.len() < max && offset < len {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        offset += read as u64;
+        match serde_json::from_str(&line) {
+            Ok(value) => values.push(value),
+            Err(e) => eprintln!("invalid message in spill file {}: {e}", path.display()),
+        }
+    }
+    if offset >= len {
+        // everything is read, start over with an empty file
+        OpenOptions::new().write(true).open(path)?.set_len(0)?;
+        return Ok((values, None));
+    }
+    Ok((values, Some(offset)))
+}
+
+/// Stream of the queue's messages; the queue is closed when it's dropped.
+pub fn stream(queue: Arc<Queue>) -> impl Stream<Item = Value> {
+    let receiver = Receiver(queue);
+    futures::stream::unfold(receiver, |receiver| async move {
+        let value = receiver.0.pop().await;
+        Some((value, receiver))
+    })
+}
+
+struct Receiver(Arc<Queue>);
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::Relaxed);
+        self.0.writable.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use super::*;
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let queue = Queue::new("test".to_string(), 2, QueueOverflow::DropOldest, None);
+        for i in 0..4 {
+            queue.push(json!(i)).await;
+        }
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.pop().await, json!(2));
+        assert_eq!(queue.pop().await, json!(3));
+    }
+
+    #[tokio::test]
+    async fn block() {
+        let queue = Queue::new("test".to_string(), 1, QueueOverflow::Block, None);
+        queue.push(json!(0)).await;
+        let queue2 = Arc::clone(&queue);
+        let pusher = tokio::spawn(async move { queue2.push(json!(1)).await });
+        tokio::task::yield_now().await;
+        assert!(!pusher.is_finished());
+        assert_eq!(queue.pop().await, json!(0));
+        pusher.await.unwrap();
+        assert_eq!(queue.pop().await, json!(1));
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn spill_to_disk() {
+        let path = std::env::temp_dir().join(format!("iot2db-spill-test-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let queue = Queue::new("test".to_string(), 2, QueueOverflow::SpillToDisk, Some(path.clone()));
+        for i in 0..5 {
+            queue.push(json!(i)).await;
+        }
+        assert_eq!(queue.pop().await, json!(0));
+        // spilled messages stay behind newer messages
+        queue.push(json!(5)).await;
+        drop(queue);
+
+        // spilled messages survive a restart
+        let queue = Queue::new("test".to_string(), 2, QueueOverflow::SpillToDisk, Some(path.clone()));
+        let mut values = Vec::new();
+        for _ in 0..4 {
+            values.push(queue.pop().await);
+        }
+        assert_eq!(values, vec![json!(2), json!(3), json!(4), json!(5)]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,83 +1,360 @@
 use std::collections::HashMap;
-use std::time::Duration;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use futures::Stream;
-use regex::Regex;
+use regex::{Captures, Regex};
 use serde_json::{Map, Value};
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
-use crate::config::ShellConfig;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use crate::config::{NonZeroExit, PayloadFormat, ShellConfig};
+use crate::frontend::payload::PayloadDecoder;
+
+#[cfg(not(test))]
+const MIN_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+#[cfg(test)]
+const MIN_RESTART_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+/// maximum size of a multi-line JSON document of `mode = "stream"`
+const MAX_DOCUMENT: usize = 1024 * 1024;
 
 pub fn stream(config: &ShellConfig) -> impl Stream<Item = Value> + 'static {
     let config = config.clone();
+    let frequency_secs = config.frequency_secs
+        .unwrap_or_else(|| panic!("shell command `{}` requires frequency_secs", config.cmd));
+    let decoder = Arc::new(PayloadDecoder::new(&config.payload));
+    let regexes = Arc::new(compile_regexes(&config));
     futures::stream::unfold(0, move |mut iteration| {
         let config = config.clone();
-        let args = shlex::split(&config.cmd)
-            .expect("invalid command string for shell frontend");
-        let program = args.first()
-            .expect("command string for shell frontend doesn't contain a program");
-        let args = &args[1..];
-        let mut command = Command::new(program);
-        command.args(args);
-        let regexes: HashMap<_, _> = config.regex.iter()
-            .map(|(name, regex)| (name.clone(), Regex::new(regex).unwrap_or_else(|e| panic!("invalid regex {}: {e:?}", regex))))
-            .collect();
+        let decoder = Arc::clone(&decoder);
+        let regexes = Arc::clone(&regexes);
+        let mut command = command(&config);
+        command.stdin(Stdio::null());
         async move {
             loop {
                 if iteration != 0 {
-                    tokio::time::sleep(Duration::from_secs(config.frequency_secs as u64)).await;
+                    tokio::time::sleep(Duration::from_secs(frequency_secs as u64)).await;
                 }
                 iteration += 1;
-                let output = command.output().await;
-                let output = match output {
-                    Ok(output) => output,
-                    Err(e) => {
-                        eprintln!("error executing `{}`: {:?}", config.cmd, e);
-                        continue
-                    }
-                }.stdout;
-                let output = match String::from_utf8(output) {
+                let output = match execute(&mut command, &config).await {
                     Ok(output) => output,
                     Err(e) => {
-                        eprintln!("output of shell command `{}` was not valid utf-8: {e:?}", config.cmd);
+                        eprintln!("{e}");
                         continue
                     }
                 };
                 // convert to json
                 let json = match config.regex.is_empty() {
-                    true => match serde_json::from_str(&output) {
+                    true => match decoder.decode(&output) {
                         Ok(json) => json,
                         Err(e) => {
-                            eprintln!("error parsing json from command `{}`: {:?}", config.cmd, e);
+                            eprintln!("error parsing output of command `{}`: {e}", config.cmd);
                             continue
                         }
                     }
-                    false => {
-                        let mut map = Map::new();
-                        for (name, regex) in &regexes {
-                            let val = match regex.captures(&output) {
-                                Some(val) => val,
-                                None => {
-                                    eprintln!("regex `{regex}` doesn't match for key `{name}` in output of shell command `{}`", config.cmd);
-                                    continue
-                                }
-                            };
-                            let val = match val.get(1) {
-                                Some(val) => val.as_str(),
-                                None => {
-                                    eprintln!("no capture group found for key `{name}` in output of shell command `{}`", config.cmd);
-                                    continue
-                                }
-                            };
-                            match map.insert(name.clone(), Value::from(val)) {
-                                None => (),
-                                Some(_) => eprintln!("duplicate regex key `{name:?}` for shell command `{}`", config.cmd),
+                    false => Value::Object(regex_to_json(&output, &regexes, &config)),
+                };
+
+                break Some((json, iteration))
+            }
+        }
+    })
+}
+
+/// Keep the command running, sending each line (or JSON document) of its output as value.
+///
+/// If the command exits, it's restarted with exponential backoff.
+pub fn stream_process(config: &ShellConfig) -> impl Stream<Item = Value> + 'static {
+    let config = config.clone();
+    let decoder = PayloadDecoder::new(&config.payload);
+    let regexes = compile_regexes(&config);
+    let mut command = command(&config);
+    command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let (tx, rx) = mpsc::channel(100);
+    tokio::spawn(async move {
+        let mut backoff = MIN_RESTART_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let mut child = match command.spawn() {
+                Ok(child) => child,
+                Err(e) => {
+                    eprintln!("error executing `{}`: {e:?}", config.cmd);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+                    continue
+                }
+            };
+            let stderr = child.stderr.take().unwrap();
+            let cmd = config.cmd.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    eprintln!("`{cmd}`: {line}");
+                }
+            });
+
+            let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+            // JSON documents may span multiple lines
+            let mut document = String::new();
+            loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("error reading output of shell command `{}`: {e:?}", config.cmd);
+                        break
+                    }
+                };
+                let json = match (regexes.is_empty(), decoder.format()) {
+                    (false, _) => Value::Object(regex_to_json(&line, &regexes, &config)),
+                    (true, PayloadFormat::Json) => {
+                        document.push_str(&line);
+                        document.push('\n');
+                        match serde_json::from_str(&document) {
+                            Ok(json) => { document.clear(); json },
+                            Err(e) if e.is_eof() && document.len() > MAX_DOCUMENT => {
+                                eprintln!("no complete JSON document within {MAX_DOCUMENT} bytes of output of command `{}`", config.cmd);
+                                document.clear();
+                                continue
+                            }
+                            Err(e) if e.is_eof() => continue,
+                            Err(e) => {
+                                eprintln!("error parsing output of command `{}`: {e}", config.cmd);
+                                document.clear();
+                                continue
                             }
                         }
-                        Value::Object(map)
+                    }
+                    (true, _) if line.trim().is_empty() => continue,
+                    (true, _) => match decoder.decode(&line) {
+                        Ok(json) => json,
+                        Err(e) => {
+                            eprintln!("error parsing output of command `{}`: {e}", config.cmd);
+                            continue
+                        }
                     }
                 };
+                if tx.send(json).await.is_err() {
+                    // data pipeline is closed, the process is killed on drop
+                    return
+                }
+            }
 
-                break Some((json, iteration))
+            match child.wait().await {
+                Ok(status) => eprintln!("shell command `{}` exited with {status}", config.cmd),
+                Err(e) => eprintln!("error waiting for shell command `{}`: {e:?}", config.cmd),
+            }
+            if started.elapsed() > MAX_RESTART_BACKOFF {
+                backoff = MIN_RESTART_BACKOFF;
             }
+            eprintln!("restarting shell command `{}` in {backoff:?}", config.cmd);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
         }
-    })
-}
\ No newline at end of file
+    });
+    ReceiverStream::new(rx)
+}
+
+/// execute the command once, returning its stdout
+async fn execute(command: &mut Command, config: &ShellConfig) -> Result<String, String> {
+    let output = command.output();
+    let output = match config.timeout_secs {
+        // the process is killed when the future is dropped
+        Some(secs) => match tokio::time::timeout(Duration::from_secs(secs as u64), output).await {
+            Ok(output) => output,
+            Err(_) => return Err(format!("shell command `{}` timed out after {secs}s", config.cmd)),
+        },
+        None => output.await,
+    };
+    let output = output.map_err(|e| format!("error executing `{}`: {e:?}", config.cmd))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim();
+    if !output.status.success() {
+        let msg = format!("shell command `{}` exited with {}: {stderr}", config.cmd, output.status);
+        match config.on_nonzero_exit {
+            NonZeroExit::Skip => return Err(msg),
+            NonZeroExit::Parse => eprintln!("{msg}"),
+        }
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|e| format!("output of shell command `{}` was not valid utf-8: {e:?}; stderr: {stderr}", config.cmd))
+}
+
+fn command(config: &ShellConfig) -> Command {
+    let mut command = match config.shell {
+        true => {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&config.cmd);
+            command
+        }
+        false => {
+            let args = shlex::split(&config.cmd)
+                .expect("invalid command string for shell frontend");
+            let program = args.first()
+                .expect("command string for shell frontend doesn't contain a program");
+            let mut command = Command::new(program);
+            command.args(&args[1..]);
+            command
+        }
+    };
+    if config.clear_env {
+        command.env_clear();
+    }
+    command.envs(&config.env);
+    if let Some(cwd) = &config.cwd {
+        command.current_dir(cwd);
+    }
+    command.kill_on_drop(true);
+    command
+}
+
+fn compile_regexes(config: &ShellConfig) -> HashMap<String, Regex> {
+    config.regex.iter()
+        .map(|(name, regex)| (name.clone(), Regex::new(regex).unwrap_or_else(|e| panic!("invalid regex {}: {e:?}", regex))))
+        .collect()
+}
+
+/// extract the values of all regexes from the output
+fn regex_to_json(output: &str, regexes: &HashMap<String, Regex>, config: &ShellConfig) -> Map<String, Value> {
+    let mut map = Map::new();
+    for (name, regex) in regexes {
+        let mut matches = match config.regex_per_line {
+            true => output.lines().flat_map(|line| regex.captures_iter(line)).collect(),
+            false => regex.captures_iter(output).collect::<Vec<_>>(),
+        };
+        if !config.regex_all {
+            matches.truncate(1);
+        }
+        let mut values = Vec::new();
+        for captures in matches {
+            match captures_to_json(regex, &captures) {
+                Some(value) => values.push(value),
+                None => eprintln!("no capture group found for key `{name}` in output of shell command `{}`", config.cmd),
+            }
+        }
+        if values.is_empty() && !config.regex_all {
+            eprintln!("regex `{regex}` doesn't match for key `{name}` in output of shell command `{}`", config.cmd);
+            continue
+        }
+        let entries = match config.regex_all {
+            true => vec![(name.clone(), Value::Array(values))],
+            false => match values.pop().unwrap() {
+                // named groups are keys on their own
+                Value::Object(named) => named.into_iter().collect(),
+                value => vec![(name.clone(), value)],
+            },
+        };
+        for (key, value) in entries {
+            if map.insert(key.clone(), value).is_some() {
+                eprintln!("duplicate regex key `{key:?}` for shell command `{}`", config.cmd);
+            }
+        }
+    }
+    map
+}
+
+/// object of the named capture groups if the regex has any, otherwise capture group 1
+fn captures_to_json(regex: &Regex, captures: &Captures) -> Option<Value> {
+    let names: Vec<_> = regex.capture_names().flatten().collect();
+    match names.is_empty() {
+        true => captures.get(1).map(|val| Value::from(val.as_str())),
+        false => Some(Value::Object(names.into_iter()
+            .filter_map(|name| captures.name(name).map(|val| (name.to_string(), Value::from(val.as_str()))))
+            .collect())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use super::*;
+
+    const DF: &str = "\
+Filesystem     1K-blocks     Used Available Use% Mounted on
+/dev/sda1       41152736 21034208  18004952  54% /
+/dev/sdb1      960302804 52145972 859302632   6% /data
+";
+
+    fn regex_json(toml: &str) -> Value {
+        let config: ShellConfig = toml::from_str(&format!("cmd = \"df\"\nfrequency_secs = 1\n{toml}")).unwrap();
+        let regexes = config.regex.iter()
+            .map(|(name, regex)| (name.clone(), Regex::new(regex).unwrap()))
+            .collect();
+        Value::Object(regex_to_json(DF, &regexes, &config))
+    }
+
+    #[test]
+    fn named_groups() {
+        assert_eq!(regex_json(r#"regex.root = '(?m)(?P<root_used>\d+)%\s+/$'"#), json!({ "root_used": "54" }));
+        assert_eq!(regex_json(r#"regex.root = '(\d+)%'"#), json!({ "root": "54" }));
+    }
+
+    #[test]
+    fn all_per_line() {
+        let json = regex_json(r#"
+            regex_all = true
+            regex_per_line = true
+            regex.disks = '^(?P<device>/dev/\S+)\s.*\s(?P<use>\d+)%\s+(?P<mount>\S+)$'
+        "#);
+        assert_eq!(json, json!({ "disks": [
+            { "device": "/dev/sda1", "use": "54", "mount": "/" },
+            { "device": "/dev/sdb1", "use": "6", "mount": "/data" },
+        ]}));
+    }
+
+    #[tokio::test]
+    async fn stream_json_documents() {
+        use futures::StreamExt;
+        let config: ShellConfig = toml::from_str(r#"
+            cmd = """sh -c 'printf "{\\"a\\":\\n1}\\n{\\"b\\": 2}\\n"; echo error >&2'"""
+            mode = "stream"
+        "#).unwrap();
+        let values: Vec<_> = stream_process(&config).take(3).collect().await;
+        assert_eq!(values, vec![json!({ "a": 1 }), json!({ "b": 2 }), json!({ "a": 1 })]);
+    }
+
+    #[tokio::test]
+    async fn stream_oversized_document() {
+        use futures::StreamExt;
+        let config: ShellConfig = toml::from_str(r#"
+            cmd = """printf '{"a": "'; head -c 1100000 /dev/zero | tr '\\0' x; echo; echo '{"b": 2}'"""
+            shell = true
+            mode = "stream"
+        "#).unwrap();
+        let mut stream = stream_process(&config).boxed();
+        assert_eq!(stream.next().await.unwrap(), json!({ "b": 2 }));
+    }
+
+    fn shell_config(toml: &str) -> ShellConfig {
+        toml::from_str(&format!("frequency_secs = 1\n{toml}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn execute_controls() {
+        let dir = std::env::temp_dir();
+        let config = shell_config(&format!(r#"
+            cmd = "echo $GREETING from $(pwd) | tr a-z A-Z"
+            shell = true
+            cwd = {:?}
+            env = {{ GREETING = "hello" }}
+        "#, dir.display().to_string()));
+        let output = execute(&mut command(&config), &config).await.unwrap();
+        assert_eq!(output.trim(), format!("HELLO FROM {}", dir.display()).to_uppercase());
+
+        let config = shell_config(r#"cmd = "sleep 5"
+            timeout_secs = 1"#);
+        let err = execute(&mut command(&config), &config).await.unwrap_err();
+        assert!(err.contains("timed out"), "{err}");
+
+        let config = shell_config(r#"cmd = "echo 1; echo broken >&2; exit 3"
+            shell = true"#);
+        let err = execute(&mut command(&config), &config).await.unwrap_err();
+        assert!(err.contains("exit status: 3") && err.contains("broken"), "{err}");
+        let config = shell_config(r#"cmd = "echo 1; exit 3"
+            shell = true
+            on_nonzero_exit = "parse""#);
+        assert_eq!(execute(&mut command(&config), &config).await.unwrap(), "1\n");
+    }
+}
@@ -0,0 +1,484 @@
+//! Smart meter telegrams: SML (Smart Message Language, binary) and IEC 62056-21 D0 (text)
+//!
+//! Each telegram is returned as object of its values keyed by OBIS code, e.g. `1-0:1.8.0`.
+
+use std::time::Duration;
+use futures::Stream;
+use serde_json::{Map, Number, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Interval;
+use tokio_serial::SerialPortBuilderExt;
+use tokio_stream::wrappers::ReceiverStream;
+use crate::config::{Parity, SmlConfig, SmlProtocol};
+
+const ESCAPE: [u8; 4] = [0x1b; 4];
+const START: [u8; 4] = [0x01; 4];
+const END: u8 = 0x1a;
+/// telegrams exceeding this size are dropped
+const MAX_TELEGRAM: usize = 64 * 1024;
+/// maximum nesting of SML lists; valid telegrams are nested about 5 levels deep
+const MAX_DEPTH: usize = 16;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+pub fn stream(config: SmlConfig) -> impl Stream<Item = Value> + 'static {
+    let sources = [&config.device, &config.tcp, &config.file].iter().filter(|source| source.is_some()).count();
+    assert_eq!(sources, 1, "sml frontend requires exactly one of device, tcp or file");
+    assert!(config.request_secs.is_none() || config.protocol == SmlProtocol::D0, "sml frontend: request_secs requires protocol = \"d0\"");
+
+    let (tx, rx) = mpsc::channel(100);
+    tokio::spawn(async move {
+        loop {
+            let res = match (&config.device, &config.tcp, &config.file) {
+                (Some(device), _, _) => match open_serial(device, &config) {
+                    Ok(port) => read_telegrams(port, &config, &tx).await,
+                    Err(e) => Err(format!("can't open {device}: {e}")),
+                },
+                (_, Some(addr), _) => match TcpStream::connect(addr).await {
+                    Ok(stream) => read_telegrams(stream, &config, &tx).await,
+                    Err(e) => Err(format!("can't connect to {addr}: {e}")),
+                },
+                (_, _, Some(file)) => {
+                    let res = match tokio::fs::File::open(file).await {
+                        Ok(file) => read_telegrams(file, &config, &tx).await,
+                        Err(e) => Err(format!("can't open {file}: {e}")),
+                    };
+                    if let Err(e) = res {
+                        eprintln!("sml: {e}");
+                    }
+                    return;
+                }
+                (None, None, None) => unreachable!(),
+            };
+            if tx.is_closed() {
+                return;
+            }
+            match res {
+                Ok(()) => eprintln!("sml: connection closed - reconnect"),
+                Err(e) => eprintln!("sml: {e} - reconnect"),
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+fn open_serial(device: &str, config: &SmlConfig) -> tokio_serial::Result<tokio_serial::SerialStream> {
+    let parity = match config.parity {
+        Parity::None => tokio_serial::Parity::None,
+        Parity::Even => tokio_serial::Parity::Even,
+        Parity::Odd => tokio_serial::Parity::Odd,
+    };
+    let data_bits = match config.data_bits {
+        7 => tokio_serial::DataBits::Seven,
+        8 => tokio_serial::DataBits::Eight,
+        bits => panic!("sml: unsupported number of data bits {bits}"),
+    };
+    tokio_serial::new(device, config.baud_rate)
+        .parity(parity)
+        .data_bits(data_bits)
+        .open_native_async()
+}
+
+/// Reads telegrams until the end of the stream, sending each to `tx`.
+async fn read_telegrams(stream: impl AsyncRead + AsyncWrite, config: &SmlConfig, tx: &mpsc::Sender<Value>) -> Result<(), String> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut framer = Framer::new(config.protocol);
+    let mut request = config.request_secs.map(|secs| tokio::time::interval(Duration::from_secs(secs as u64)));
+    let mut buf = [0; 4096];
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buf) => {
+                let read = read.map_err(|e| format!("error reading: {e}"))?;
+                if read == 0 {
+                    return Ok(());
+                }
+                framer.push(&buf[..read]);
+                while let Some(telegram) = framer.next_telegram() {
+                    match telegram {
+                        Ok(value) => if tx.send(value).await.is_err() {
+                            return Ok(());
+                        },
+                        Err(e) => eprintln!("sml: invalid telegram: {e}"),
+                    }
+                }
+            }
+            () = tick(&mut request) => {
+                writer.write_all(b"/?!\r\n").await.map_err(|e| format!("error sending request: {e}"))?;
+            }
+        }
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => { interval.tick().await; }
+        None => std::future::pending().await,
+    }
+}
+
+/// Splits received bytes into telegrams
+struct Framer {
+    protocol: SmlProtocol,
+    buf: Vec<u8>,
+}
+
+impl Framer {
+    fn new(protocol: SmlProtocol) -> Self {
+        Framer { protocol, buf: Vec::new() }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// next complete telegram, decoded
+    fn next_telegram(&mut self) -> Option<Result<Value, String>> {
+        let res = match self.protocol {
+            SmlProtocol::Sml => self.next_sml(),
+            SmlProtocol::D0 => self.next_d0(),
+        };
+        if res.is_none() && self.buf.len() > MAX_TELEGRAM {
+            self.buf.clear();
+            return Some(Err(format!("no telegram within {MAX_TELEGRAM} bytes")));
+        }
+        res
+    }
+
+    fn next_sml(&mut self) -> Option<Result<Value, String>> {
+        loop {
+            let start = self.buf.windows(8).position(|window| window[..4] == ESCAPE && window[4..] == START);
+            let Some(start) = start else {
+                // keep a possibly incomplete start sequence
+                let keep = self.buf.len().min(7);
+                self.buf.drain(..self.buf.len() - keep);
+                return None;
+            };
+            self.buf.drain(..start);
+
+            // escape sequences are aligned to 4 bytes from the start
+            let mut i = 8;
+            loop {
+                if self.buf.len() < i + 8 {
+                    return None;
+                }
+                if self.buf[i..i + 4] != ESCAPE {
+                    i += 4;
+                    continue;
+                }
+                match &self.buf[i + 4..i + 8] {
+                    // escaped escape sequence
+                    escape if escape == ESCAPE => i += 8,
+                    end if end[0] == END => {
+                        let telegram: Vec<u8> = self.buf.drain(..i + 8).collect();
+                        return Some(decode_sml(&telegram));
+                    }
+                    // a new telegram started before the end of this one
+                    start if start == START => {
+                        self.buf.drain(..i);
+                        break;
+                    }
+                    _ => {
+                        self.buf.drain(..i + 8);
+                        return Some(Err("invalid escape sequence".to_string()));
+                    }
+                }
+            }
+        }
+    }
+
+    fn next_d0(&mut self) -> Option<Result<Value, String>> {
+        let Some(start) = self.buf.iter().position(|&b| b == b'/') else {
+            self.buf.clear();
+            return None;
+        };
+        self.buf.drain(..start);
+        let end = self.buf.iter().position(|&b| b == b'!')?;
+        let telegram: Vec<u8> = self.buf.drain(..=end).collect();
+        Some(decode_d0(&String::from_utf8_lossy(&telegram)))
+    }
+}
+
+/// CRC-16/X-25 of the SML transport
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x8408,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+/// decode a telegram from its start to its end escape sequence
+fn decode_sml(telegram: &[u8]) -> Result<Value, String> {
+    let len = telegram.len();
+    let crc = u16::from_le_bytes([telegram[len - 2], telegram[len - 1]]);
+    if crc16(&telegram[..len - 2]) != crc {
+        return Err(format!("invalid CRC {crc:04x}"));
+    }
+    let padding = telegram[len - 3] as usize;
+    let mut data = Vec::with_capacity(len);
+    let mut chunks = telegram[8..len - 8].chunks(4);
+    while let Some(chunk) = chunks.next() {
+        data.extend_from_slice(chunk);
+        if chunk == ESCAPE {
+            chunks.next();
+        }
+    }
+    data.truncate(data.len().saturating_sub(padding));
+
+    let mut parser = Parser { data: &data, pos: 0, depth: 0 };
+    let mut res = Map::new();
+    loop {
+        // padding
+        while parser.data.get(parser.pos) == Some(&0) {
+            parser.pos += 1;
+        }
+        if parser.pos >= parser.data.len() {
+            break;
+        }
+        let message = parser.node()?;
+        // transactionId, groupNo, abortOnError, messageBody, crc16, endOfSmlMsg
+        let Node::List(message) = message else { return Err("message isn't a list".to_string()) };
+        let Some(Node::List(body)) = message.get(3) else { return Err("message without body".to_string()) };
+        // GetListResponse
+        if let [Node::Uint(0x701), Node::List(response)] = body.as_slice() {
+            let Some(Node::List(entries)) = response.get(4) else { return Err("GetListResponse without valList".to_string()) };
+            for entry in entries {
+                // objName, status, valTime, unit, scaler, value, valueSignature
+                let Node::List(entry) = entry else { continue };
+                let (Some(Node::Octets(obis)), Some(value)) = (entry.first(), entry.get(5)) else { continue };
+                let scaler = match entry.get(4) {
+                    Some(Node::Int(scaler)) => *scaler,
+                    _ => 0,
+                };
+                if let Some(value) = sml_value(value, scaler) {
+                    res.insert(obis_code(obis), value);
+                }
+            }
+        }
+    }
+    Ok(Value::Object(res))
+}
+
+/// `A-B:C.D.E`, with `*F` unless it's 255
+fn obis_code(obis: &[u8]) -> String {
+    match obis {
+        [a, b, c, d, e, 255] => format!("{a}-{b}:{c}.{d}.{e}"),
+        [a, b, c, d, e, f] => format!("{a}-{b}:{c}.{d}.{e}*{f}"),
+        _ => obis.iter().map(|b| format!("{b:02x}")).collect(),
+    }
+}
+
+fn sml_value(value: &Node, scaler: i64) -> Option<Value> {
+    let number = |value: Value, as_f64: f64| match scaler {
+        0 => Some(value),
+        // dividing by 10^-scaler avoids results like `230.10000000000002`
+        scaler if scaler < 0 => Number::from_f64(as_f64 / 10f64.powi(-scaler as i32)).map(Value::Number),
+        scaler => Number::from_f64(as_f64 * 10f64.powi(scaler as i32)).map(Value::Number),
+    };
+    match value {
+        Node::Int(i) => number(Value::from(*i), *i as f64),
+        Node::Uint(u) => number(Value::from(*u), *u as f64),
+        Node::Bool(b) => Some(Value::Bool(*b)),
+        Node::Octets(bytes) if !bytes.is_empty() && bytes.iter().all(|b| (0x20..0x7f).contains(b)) => {
+            Some(Value::String(String::from_utf8_lossy(bytes).into_owned()))
+        }
+        Node::Octets(bytes) => Some(Value::String(bytes.iter().map(|b| format!("{b:02x}")).collect())),
+        Node::List(_) | Node::None => None,
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Octets(Vec<u8>),
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    List(Vec<Node>),
+    /// optional value which isn't set
+    None,
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// number of lists the current node is nested in
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.data.get(self.pos).ok_or("unexpected end of telegram")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn node(&mut self) -> Result<Node, String> {
+        let first = self.byte()?;
+        // endOfSmlMsg or unset optional value
+        if first == 0x00 || first == 0x01 {
+            return Ok(Node::None);
+        }
+        let ty = first & 0x70;
+        let mut len = (first & 0x0f) as usize;
+        let mut tl_len = 1;
+        let mut tl = first;
+        while tl & 0x80 != 0 {
+            tl = self.byte()?;
+            len = (len << 4) | (tl & 0x0f) as usize;
+            tl_len += 1;
+            if len > self.data.len() {
+                return Err(format!("invalid length at {}", self.pos));
+            }
+        }
+        if ty == 0x70 {
+            if self.depth >= MAX_DEPTH {
+                return Err(format!("lists nested deeper than {MAX_DEPTH} levels at {}", self.pos));
+            }
+            self.depth += 1;
+            let list = (0..len).map(|_| self.node()).collect::<Result<_, _>>().map(Node::List);
+            self.depth -= 1;
+            return list;
+        }
+        let len = len.checked_sub(tl_len).ok_or_else(|| format!("invalid length at {}", self.pos))?;
+        let end = self.pos.checked_add(len).ok_or("unexpected end of telegram")?;
+        let bytes = self.data.get(self.pos..end).ok_or("unexpected end of telegram")?;
+        self.pos += len;
+        match ty {
+            0x00 => Ok(Node::Octets(bytes.to_vec())),
+            0x40 => Ok(Node::Bool(bytes.iter().any(|&b| b != 0))),
+            0x50 if (1..=8).contains(&len) => {
+                let sign = if bytes[0] & 0x80 != 0 { 0xff } else { 0 };
+                let mut buf = [sign; 8];
+                buf[8 - len..].copy_from_slice(bytes);
+                Ok(Node::Int(i64::from_be_bytes(buf)))
+            }
+            0x60 if (1..=8).contains(&len) => {
+                let mut buf = [0; 8];
+                buf[8 - len..].copy_from_slice(bytes);
+                Ok(Node::Uint(u64::from_be_bytes(buf)))
+            }
+            _ => Err(format!("invalid type-length field {first:02x} at {}", self.pos)),
+        }
+    }
+}
+
+/// decode a telegram from `/` to `!`, e.g.
+/// ```text
+/// /ESY5Q3DA1004 V3.04
+///
+/// 1-0:1.8.0*255(00012345.6789012*kWh)
+/// !
+/// ```
+fn decode_d0(telegram: &str) -> Result<Value, String> {
+    let mut lines = telegram.lines();
+    let identification = lines.next().unwrap_or_default().trim_start_matches('/').trim();
+    let mut res = Map::new();
+    res.insert("identification".to_string(), Value::String(identification.to_string()));
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() || line == "!" {
+            continue;
+        }
+        let Some((code, rest)) = line.split_once('(') else {
+            return Err(format!("invalid line {line:?}"));
+        };
+        let value = rest.split(')').next().unwrap_or_default();
+        let value = value.split_once('*').map_or(value, |(value, _unit)| value);
+        let code = code.strip_suffix("*255").unwrap_or(code);
+        res.insert(code.to_string(), d0_value(value));
+    }
+    Ok(Value::Object(res))
+}
+
+fn d0_value(value: &str) -> Value {
+    let numeric = !value.is_empty()
+        && value.trim_start_matches('-').chars().all(|c| c.is_ascii_digit() || c == '.')
+        && value.chars().any(|c| c.is_ascii_digit());
+    if !numeric {
+        return Value::String(value.to_string());
+    }
+    if let Ok(i) = value.parse::<i64>() {
+        return Value::from(i);
+    }
+    value.parse::<f64>().ok().and_then(Number::from_f64).map_or_else(|| Value::String(value.to_string()), Value::Number)
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+    use serde_json::json;
+    use super::*;
+
+    fn sml_fixture() -> Vec<u8> {
+        let hex: String = include_str!("fixtures/sml-three-phase.hex").split_whitespace().collect();
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn sml_telegram() {
+        let telegram = sml_fixture();
+        let mut framer = Framer::new(SmlProtocol::Sml);
+        // garbage of a partially received telegram, then the telegram in small pieces
+        framer.push(&[0x77, 0x1b, 0x1b, 0x00]);
+        for chunk in telegram.chunks(7) {
+            assert!(framer.next_telegram().is_none());
+            framer.push(chunk);
+        }
+        assert_eq!(framer.next_telegram().unwrap().unwrap(), json!({
+            "129-129:199.130.3": "EMH",
+            "1-0:0.0.9": "0a01454d480000abcdef",
+            "1-0:1.8.0": 12345678.9,
+            "1-0:2.8.0": 500.0,
+            "1-0:16.7.0": -350,
+            "1-0:36.7.0": 123.45,
+        }));
+        assert!(framer.next_telegram().is_none());
+
+        let mut corrupted = telegram.clone();
+        corrupted[100] ^= 1;
+        framer.push(&corrupted);
+        assert!(framer.next_telegram().unwrap().is_err());
+    }
+
+    #[test]
+    fn sml_invalid_nodes() {
+        // deeply nested lists
+        let mut data = vec![0x71; MAX_TELEGRAM];
+        data.push(0x01);
+        let mut parser = Parser { data: &data, pos: 0, depth: 0 };
+        assert_eq!(parser.node().unwrap_err(), format!("lists nested deeper than {MAX_DEPTH} levels at {}", MAX_DEPTH + 1));
+        // length growing with each type-length continuation
+        let data = [[0x8f; 32].as_slice(), &[0x0f]].concat();
+        let mut parser = Parser { data: &data, pos: 0, depth: 0 };
+        assert!(parser.node().unwrap_err().starts_with("invalid length"));
+    }
+
+    #[tokio::test]
+    async fn d0_file() {
+        let config: SmlConfig = toml::from_str(&format!(r#"
+            protocol = "d0"
+            file = "{}/src/frontend/fixtures/d0-easymeter.txt"
+        "#, env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let values: Vec<Value> = stream(config).collect().await;
+        assert_eq!(values, vec![json!({
+            "identification": "ESY5Q3DA1004 V3.04",
+            "1-0:0.0.0": "1ESY1160123456",
+            "1-0:1.8.0": 12345.6789012,
+            "1-0:2.8.0": 123.456,
+            "1-0:21.7.0": 123.45,
+            "1-0:41.7.0": 67.89,
+            "1-0:61.7.0": -12.34,
+            "1-0:1.7.0": 178.9,
+            "1-0:96.5.5": 82,
+            "0-0:96.1.255": "1ESY1160123456",
+        })]);
+    }
+}
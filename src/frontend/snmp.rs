@@ -0,0 +1,861 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use aes::Aes128;
+use cbc::cipher::{AsyncStreamCipher, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use des::Des;
+use futures::Stream;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use serde_json::{Map, Value};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use tokio::net::UdpSocket;
+use crate::config::{SnmpAuthProtocol, SnmpConfig, SnmpPrivProtocol, SnmpVersion};
+use crate::frontend::ber::{self, Oid, Reader};
+
+const GET_REQUEST: u8 = 0xa0;
+const RESPONSE: u8 = 0xa2;
+const GET_BULK_REQUEST: u8 = 0xa5;
+const REPORT: u8 = 0xa8;
+
+/// OIDs requested with a single GetRequest
+const MAX_GET_OIDS: usize = 32;
+/// varbinds requested with each GetBulkRequest of a walk
+const MAX_REPETITIONS: i64 = 25;
+/// usmStats, whose counters are returned in reports
+const USM_STATS: [u32; 9] = [1, 3, 6, 1, 6, 3, 15, 1, 1];
+const NOT_IN_TIME_WINDOWS: u32 = 2;
+const UNKNOWN_ENGINE_IDS: u32 = 4;
+/// seconds the engine time of an authenticated message may lag behind, see RFC 3414 2.2.3
+const TIME_WINDOW: u32 = 150;
+
+pub fn stream(config: SnmpConfig) -> impl Stream<Item = Value> + 'static {
+    assert!(!config.oids.is_empty() || !config.walks.is_empty(), "snmp frontend requires oids or walks");
+    let parse = |oid: &str| ber::parse_oid(oid).unwrap_or_else(|e| panic!("snmp frontend: {e}"));
+    let gets: Vec<(String, Oid)> = config.oids.iter()
+        .map(|(name, oid)| (name.clone(), parse(oid)))
+        .collect();
+    let walks: Vec<Walk> = config.walks.iter()
+        .map(|(name, walk)| {
+            let root = parse(&walk.oid);
+            let columns = walk.columns.iter()
+                .map(|(column, &number)| (column.clone(), [root.as_slice(), &[number]].concat()))
+                .collect();
+            Walk { name: name.clone(), root, columns }
+        }).collect();
+    let frequency = Duration::from_secs(config.frequency_secs as u64);
+    let client = Client::new(&config);
+    futures::stream::unfold((client, gets, walks, 0), move |(mut client, gets, walks, mut iteration)| async move {
+        loop {
+            if iteration != 0 {
+                tokio::time::sleep(frequency).await;
+            }
+            iteration += 1;
+            match poll(&mut client, &gets, &walks).await {
+                Ok(res) => return Some((Value::Object(res), (client, gets, walks, iteration))),
+                Err(e) => eprintln!("snmp: {e} - retry"),
+            }
+        }
+    })
+}
+
+struct Walk {
+    name: String,
+    root: Oid,
+    /// column names and their OIDs, empty to walk all columns
+    columns: Vec<(String, Oid)>,
+}
+
+async fn poll(client: &mut Client, gets: &[(String, Oid)], walks: &[Walk]) -> Result<Map<String, Value>, String> {
+    let mut res = Map::new();
+    for chunk in gets.chunks(MAX_GET_OIDS) {
+        let oids: Vec<Oid> = chunk.iter().map(|(_, oid)| oid.clone()).collect();
+        for ((name, _), varbind) in chunk.iter().zip(client.get(&oids).await?) {
+            if let Some(value) = varbind.to_json() {
+                res.insert(name.clone(), value);
+            }
+        }
+    }
+    for walk in walks {
+        // rows keyed by their index, containing the columns
+        let mut rows = Map::new();
+        let mut insert = |index: &[u32], column: String, varbind: VarBind| {
+            if let Some(value) = varbind.to_json() {
+                let row = rows.entry(ber::format_oid(index)).or_insert_with(|| Value::Object(Map::new()));
+                row.as_object_mut().unwrap().insert(column, value);
+            }
+        };
+        if walk.columns.is_empty() {
+            for varbind in client.walk(&walk.root).await? {
+                if let Some((column, index)) = varbind.oid[walk.root.len()..].split_first() {
+                    let (column, index) = (column.to_string(), index.to_vec());
+                    insert(&index, column, varbind);
+                }
+            }
+        } else {
+            for (name, column) in &walk.columns {
+                for varbind in client.walk(column).await? {
+                    let index = varbind.oid[column.len()..].to_vec();
+                    insert(&index, name.clone(), varbind);
+                }
+            }
+        }
+        res.insert(walk.name.clone(), Value::Object(rows));
+    }
+    Ok(res)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct VarBind {
+    oid: Oid,
+    tag: u8,
+    content: Vec<u8>,
+}
+
+impl VarBind {
+    fn null(oid: Oid) -> Self {
+        VarBind { oid, tag: ber::NULL, content: Vec::new() }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        ber::sequence(ber::SEQUENCE, &[ber::oid(&self.oid), ber::tlv(self.tag, &self.content)])
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, String> {
+        let mut varbind = reader.sequence(ber::SEQUENCE)?;
+        let oid = varbind.oid()?;
+        let (tag, content) = varbind.read()?;
+        Ok(VarBind { oid, tag, content: content.to_vec() })
+    }
+
+    /// `None` for missing objects, hex for types without a JSON equivalent like Opaque
+    fn to_json(&self) -> Option<Value> {
+        let content = &self.content;
+        match self.tag {
+            ber::INTEGER => ber::decode_integer(content).ok().map(Value::from),
+            ber::COUNTER32 | ber::GAUGE32 | ber::TIME_TICKS | ber::COUNTER64 => ber::decode_unsigned(content).ok().map(Value::from),
+            ber::OCTET_STRING => Some(Value::String(octets_to_string(content))),
+            ber::OBJECT_IDENTIFIER => ber::decode_oid(content).ok().map(|oid| Value::String(ber::format_oid(&oid))),
+            ber::IP_ADDRESS if content.len() == 4 => Some(Value::String(format!("{}.{}.{}.{}", content[0], content[1], content[2], content[3]))),
+            ber::NULL | ber::NO_SUCH_OBJECT | ber::NO_SUCH_INSTANCE | ber::END_OF_MIB_VIEW => None,
+            _ => Some(Value::String(hex(content))),
+        }
+    }
+}
+
+/// text if printable, colon-separated hex otherwise (e.g. MAC addresses)
+fn octets_to_string(octets: &[u8]) -> String {
+    match std::str::from_utf8(octets) {
+        Ok(s) if !s.trim_end_matches('\0').chars().any(|c| c.is_control() && !c.is_whitespace()) => s.trim_end_matches('\0').to_string(),
+        _ => hex(octets),
+    }
+}
+
+fn hex(octets: &[u8]) -> String {
+    octets.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(":")
+}
+
+#[derive(Debug)]
+struct Pdu {
+    tag: u8,
+    request_id: i32,
+    /// non-repeaters of GetBulkRequests
+    error_status: i64,
+    /// max-repetitions of GetBulkRequests
+    error_index: i64,
+    varbinds: Vec<VarBind>,
+}
+
+impl Pdu {
+    fn encode(&self) -> Vec<u8> {
+        let varbinds: Vec<Vec<u8>> = self.varbinds.iter().map(VarBind::encode).collect();
+        ber::sequence(self.tag, &[
+            ber::integer(self.request_id as i64),
+            ber::integer(self.error_status),
+            ber::integer(self.error_index),
+            ber::sequence(ber::SEQUENCE, &varbinds),
+        ])
+    }
+
+    fn decode(tag: u8, content: &[u8]) -> Result<Self, String> {
+        let mut pdu = Reader::new(content);
+        let request_id = pdu.integer()? as i32;
+        let error_status = pdu.integer()?;
+        let error_index = pdu.integer()?;
+        let mut list = pdu.sequence(ber::SEQUENCE)?;
+        let mut varbinds = Vec::new();
+        while !list.is_empty() {
+            varbinds.push(VarBind::decode(&mut list)?);
+        }
+        Ok(Pdu { tag, request_id, error_status, error_index, varbinds })
+    }
+}
+
+fn error_status_name(status: i64) -> String {
+    const NAMES: [&str; 19] = [
+        "noError", "tooBig", "noSuchName", "badValue", "readOnly", "genErr", "noAccess", "wrongType", "wrongLength", "wrongEncoding",
+        "wrongValue", "noCreation", "inconsistentValue", "resourceUnavailable", "commitFailed", "undoFailed", "authorizationError",
+        "notWritable", "inconsistentName",
+    ];
+    usize::try_from(status).ok().and_then(|i| NAMES.get(i)).map(|name| name.to_string())
+        .unwrap_or_else(|| format!("error status {status}"))
+}
+
+fn report_name(oid: &[u32]) -> String {
+    const NAMES: [&str; 6] = ["unsupportedSecLevels", "notInTimeWindows", "unknownUserNames", "unknownEngineIDs", "wrongDigests", "decryptionErrors"];
+    match oid.strip_prefix(&USM_STATS[..]) {
+        Some(&[stat, 0]) if (1..=6).contains(&stat) => NAMES[stat as usize - 1].to_string(),
+        _ => ber::format_oid(oid),
+    }
+}
+
+struct Client {
+    address: String,
+    socket: Option<UdpSocket>,
+    timeout: Duration,
+    retries: u32,
+    request_id: i32,
+    security: Security,
+}
+
+impl Client {
+    fn new(config: &SnmpConfig) -> Self {
+        Client {
+            address: format!("{}:{}", config.host, config.port),
+            socket: None,
+            timeout: Duration::from_secs(config.timeout_secs as u64),
+            retries: config.retries,
+            request_id: 0,
+            security: Security::new(config),
+        }
+    }
+
+    async fn get(&mut self, oids: &[Oid]) -> Result<Vec<VarBind>, String> {
+        let varbinds = self.request(GET_REQUEST, 0, 0, oids.iter().cloned().map(VarBind::null).collect()).await?;
+        if varbinds.len() != oids.len() {
+            return Err(format!("requested {} OIDs, got {}", oids.len(), varbinds.len()));
+        }
+        Ok(varbinds)
+    }
+
+    /// all objects below `root`
+    async fn walk(&mut self, root: &[u32]) -> Result<Vec<VarBind>, String> {
+        let mut res: Vec<VarBind> = Vec::new();
+        let mut oid = root.to_vec();
+        loop {
+            let varbinds = self.request(GET_BULK_REQUEST, 0, MAX_REPETITIONS, vec![VarBind::null(oid.clone())]).await?;
+            if varbinds.is_empty() {
+                return Ok(res);
+            }
+            for varbind in varbinds {
+                if !varbind.oid.starts_with(root) || varbind.tag == ber::END_OF_MIB_VIEW {
+                    return Ok(res);
+                }
+                if varbind.oid <= oid {
+                    return Err(format!("walk of {} not increasing at {}", ber::format_oid(root), ber::format_oid(&varbind.oid)));
+                }
+                oid.clone_from(&varbind.oid);
+                res.push(varbind);
+            }
+        }
+    }
+
+    async fn request(&mut self, tag: u8, error_status: i64, error_index: i64, varbinds: Vec<VarBind>) -> Result<Vec<VarBind>, String> {
+        // v3: engine discovery and one retry after the engine time was updated
+        for _ in 0..3 {
+            self.request_id = self.request_id.wrapping_add(1) & i32::MAX;
+            let discovery = matches!(&self.security, Security::Usm(usm) if usm.engine.is_none());
+            let pdu = match discovery {
+                true => Pdu { tag: GET_REQUEST, request_id: self.request_id, error_status: 0, error_index: 0, varbinds: Vec::new() },
+                false => Pdu { tag, request_id: self.request_id, error_status, error_index, varbinds: varbinds.clone() },
+            };
+            let response = self.send(&pdu).await?;
+            match response.tag {
+                RESPONSE if discovery => continue,
+                RESPONSE if response.error_status != 0 => return Err(format!("{} at varbind {}", error_status_name(response.error_status), response.error_index)),
+                RESPONSE => return Ok(response.varbinds),
+                REPORT => {
+                    let report = response.varbinds.first().map(|varbind| report_name(&varbind.oid)).unwrap_or_default();
+                    match report.as_str() {
+                        "unknownEngineIDs" if discovery => continue,
+                        "notInTimeWindows" if !discovery => continue,
+                        _ => return Err(format!("report {report}")),
+                    }
+                }
+                tag => return Err(format!("unexpected PDU type {tag:02x}")),
+            }
+        }
+        Err("engine discovery failed".to_string())
+    }
+
+    /// sends the request and waits for its response, retrying on timeouts
+    async fn send(&mut self, pdu: &Pdu) -> Result<Pdu, String> {
+        if self.socket.is_none() {
+            self.socket = Some(connect(&self.address).await?);
+        }
+        let socket = self.socket.as_ref().unwrap();
+        let message = self.security.encode(pdu)?;
+        let mut buf = vec![0; 65535];
+        for _ in 0..=self.retries {
+            socket.send(&message).await.map_err(|e| format!("error sending to {}: {e}", self.address))?;
+            let deadline = tokio::time::Instant::now() + self.timeout;
+            loop {
+                let len = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                    Err(_) => break,
+                    Ok(Err(e)) => return Err(format!("error receiving from {}: {e}", self.address)),
+                    Ok(Ok(len)) => len,
+                };
+                match self.security.decode(&buf[..len]) {
+                    Ok((id, response)) if id == pdu.request_id => return Ok(response),
+                    // late response to an earlier request
+                    Ok(_) => (),
+                    Err(e) => eprintln!("snmp: ignoring invalid response from {}: {e}", self.address),
+                }
+            }
+        }
+        Err(format!("timeout waiting for {}", self.address))
+    }
+}
+
+async fn connect(address: &str) -> Result<UdpSocket, String> {
+    let addr = tokio::net::lookup_host(address).await.ok().and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("can't resolve {address}"))?;
+    let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(local).await.map_err(|e| format!("can't bind UDP socket: {e}"))?;
+    socket.connect(addr).await.map_err(|e| format!("can't connect to {address}: {e}"))?;
+    Ok(socket)
+}
+
+enum Security {
+    /// v2c
+    Community(Vec<u8>),
+    /// v3 user-based security model
+    Usm(Usm),
+}
+
+impl Security {
+    fn new(config: &SnmpConfig) -> Self {
+        match config.version {
+            SnmpVersion::V2c => Security::Community(config.community.as_bytes().to_vec()),
+            SnmpVersion::V3 => Security::Usm(Usm::new(config)),
+        }
+    }
+
+    fn encode(&mut self, pdu: &Pdu) -> Result<Vec<u8>, String> {
+        match self {
+            Security::Community(community) => Ok(ber::sequence(ber::SEQUENCE, &[ber::integer(1), ber::octets(community), pdu.encode()])),
+            Security::Usm(usm) => usm.encode(pdu.request_id, pdu),
+        }
+    }
+
+    /// message id (request id for v2c) and PDU
+    fn decode(&mut self, data: &[u8]) -> Result<(i32, Pdu), String> {
+        match self {
+            Security::Community(_) => {
+                let mut message = Reader::new(data).sequence(ber::SEQUENCE)?;
+                let version = message.integer()?;
+                if version != 1 {
+                    return Err(format!("unexpected SNMP version {version}"));
+                }
+                message.octets()?;
+                let (tag, content) = message.read()?;
+                let pdu = Pdu::decode(tag, content)?;
+                Ok((pdu.request_id, pdu))
+            }
+            Security::Usm(usm) => usm.decode(data),
+        }
+    }
+}
+
+struct Usm {
+    user: Vec<u8>,
+    context: Vec<u8>,
+    auth: Option<(SnmpAuthProtocol, Vec<u8>)>,
+    privacy: Option<(SnmpPrivProtocol, Vec<u8>)>,
+    /// authoritative engine of the agent, unknown until discovered
+    engine: Option<Engine>,
+    salt: u64,
+}
+
+struct Engine {
+    id: Vec<u8>,
+    boots: u32,
+    time: u32,
+    received: Instant,
+    auth_key: Vec<u8>,
+    priv_key: Vec<u8>,
+}
+
+impl Engine {
+    fn time(&self) -> u32 {
+        self.time.saturating_add(self.received.elapsed().as_secs() as u32)
+    }
+}
+
+impl Usm {
+    fn new(config: &SnmpConfig) -> Self {
+        let user = config.user.as_ref().expect("snmp v3 requires user");
+        for password in [&config.auth_password, &config.priv_password].into_iter().flatten() {
+            assert!(password.len() >= 8, "snmp v3 passwords require at least 8 characters");
+        }
+        assert!(config.priv_password.is_none() || config.auth_password.is_some(), "snmp priv_password requires auth_password");
+        Usm {
+            user: user.as_bytes().to_vec(),
+            context: config.context.as_bytes().to_vec(),
+            auth: config.auth_password.as_ref().map(|password| (config.auth_protocol, password.as_bytes().to_vec())),
+            privacy: config.priv_password.as_ref().map(|password| (config.priv_protocol, password.as_bytes().to_vec())),
+            engine: None,
+            salt: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64,
+        }
+    }
+
+    fn set_engine(&mut self, id: &[u8], boots: u32, time: u32) {
+        let received = Instant::now();
+        if let Some(engine) = self.engine.as_mut().filter(|engine| engine.id == id) {
+            engine.boots = boots;
+            engine.time = time;
+            engine.received = received;
+            return;
+        }
+        let (auth_key, priv_key) = match &self.auth {
+            Some((protocol, password)) => (
+                localized_key(*protocol, password, id),
+                self.privacy.as_ref().map(|(_, password)| localized_key(*protocol, password, id)).unwrap_or_default(),
+            ),
+            None => Default::default(),
+        };
+        self.engine = Some(Engine { id: id.to_vec(), boots, time, received, auth_key, priv_key });
+    }
+
+    fn encode(&mut self, msg_id: i32, pdu: &Pdu) -> Result<Vec<u8>, String> {
+        let (engine_id, boots, time) = match &self.engine {
+            Some(engine) => (engine.id.clone(), engine.boots, engine.time()),
+            None => Default::default(),
+        };
+        // discovery is unauthenticated
+        let auth = self.auth.as_ref().zip(self.engine.as_ref());
+        let privacy = self.privacy.as_ref().zip(self.engine.as_ref());
+        let flags = 0x04 | auth.map_or(0, |_| 0x01) | privacy.map_or(0, |_| 0x02);
+        let scoped_pdu = ber::sequence(ber::SEQUENCE, &[ber::octets(&engine_id), ber::octets(&self.context), pdu.encode()]);
+        let (msg_data, priv_params) = match privacy {
+            Some(((protocol, _), engine)) => {
+                self.salt = self.salt.wrapping_add(1);
+                let (encrypted, salt) = encrypt(*protocol, &engine.priv_key, boots, time, self.salt, scoped_pdu);
+                (ber::octets(&encrypted), salt)
+            }
+            None => (scoped_pdu, Vec::new()),
+        };
+        let digest_len = auth.map_or(0, |((protocol, _), _)| digest_len(*protocol));
+        let params = [
+            ber::octets(&engine_id),
+            ber::integer(boots as i64),
+            ber::integer(time as i64),
+            ber::octets(if self.engine.is_some() { &self.user } else { &[] }),
+            ber::octets(&vec![0; digest_len]),
+            ber::octets(&priv_params),
+        ];
+        let security_params = ber::sequence(ber::SEQUENCE, &params);
+        let mut message = ber::sequence(ber::SEQUENCE, &[
+            ber::integer(3),
+            ber::sequence(ber::SEQUENCE, &[ber::integer(msg_id as i64), ber::integer(65507), ber::octets(&[flags]), ber::integer(3)]),
+            ber::octets(&security_params),
+            msg_data,
+        ]);
+        if let Some(((protocol, _), engine)) = auth {
+            let digest = hmac(*protocol, &engine.auth_key, &message);
+            let offset = digest_offset(&message)?;
+            message[offset..offset + digest_len].copy_from_slice(&digest);
+        }
+        Ok(message)
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<(i32, Pdu), String> {
+        let mut message = Reader::new(data).sequence(ber::SEQUENCE)?;
+        let version = message.integer()?;
+        if version != 3 {
+            return Err(format!("unexpected SNMP version {version}"));
+        }
+        let mut global = message.sequence(ber::SEQUENCE)?;
+        let msg_id = global.integer()? as i32;
+        global.integer()?;
+        let flags = global.octets()?.first().copied().unwrap_or(0);
+        let mut params = Reader::new(message.octets()?).sequence(ber::SEQUENCE)?;
+        let engine_id = params.octets()?;
+        let boots = params.integer()? as u32;
+        let time = params.integer()? as u32;
+        params.octets()?;
+        let digest = params.octets()?;
+        let priv_params = params.octets()?;
+
+        if flags & 0x01 != 0 {
+            let ((protocol, _), engine) = self.auth.as_ref().zip(self.engine.as_ref()).ok_or("unexpected authenticated message")?;
+            if engine_id != engine.id {
+                return Err(format!("authenticated message of unknown engine {engine_id:02x?}"));
+            }
+            let offset = digest.as_ptr() as usize - data.as_ptr() as usize;
+            let mut zeroed = data.to_vec();
+            zeroed[offset..offset + digest.len()].fill(0);
+            if hmac(*protocol, &engine.auth_key, &zeroed) != digest {
+                return Err("wrong digest".to_string());
+            }
+            // timeliness check of RFC 3414 3.2 step 7b against replayed messages
+            if boots >= i32::MAX as u32 || boots < engine.boots || boots == engine.boots && time.saturating_add(TIME_WINDOW) < engine.time() {
+                return Err(format!("message not in time window (boots {boots}, time {time})"));
+            }
+            if boots > engine.boots || time > engine.time {
+                self.set_engine(engine_id, boots, time);
+            }
+        }
+        let decrypted;
+        let mut scoped_pdu = if flags & 0x02 != 0 {
+            let ((protocol, _), engine) = self.privacy.as_ref().zip(self.engine.as_ref()).ok_or("unexpected encrypted message")?;
+            decrypted = decrypt(*protocol, &engine.priv_key, boots, time, priv_params, message.octets()?)?;
+            Reader::new(&decrypted).sequence(ber::SEQUENCE)?
+        } else {
+            message.sequence(ber::SEQUENCE)?
+        };
+        scoped_pdu.octets()?;
+        scoped_pdu.octets()?;
+        let (tag, content) = scoped_pdu.read()?;
+        let pdu = Pdu::decode(tag, content)?;
+
+        // after discovery everything must meet the configured security level, an unauthenticated
+        // report could otherwise reset the engine time
+        let authenticated = flags & 0x01 != 0;
+        let discovery = self.engine.is_none();
+        match pdu.tag {
+            RESPONSE if !discovery && (self.auth.is_some() && !authenticated || self.privacy.is_some() && flags & 0x02 == 0) =>
+                return Err("response below the configured security level".to_string()),
+            REPORT if !discovery && self.auth.is_some() && !authenticated =>
+                return Err("unauthenticated report after engine discovery".to_string()),
+            _ => (),
+        }
+        if pdu.tag == REPORT && !engine_id.is_empty() {
+            let stat = pdu.varbinds.first().and_then(|varbind| varbind.oid.strip_prefix(&USM_STATS[..]));
+            if matches!(stat, Some(&[NOT_IN_TIME_WINDOWS | UNKNOWN_ENGINE_IDS, 0])) {
+                self.set_engine(engine_id, boots, time);
+            }
+        }
+        Ok((msg_id, pdu))
+    }
+}
+
+/// offset of the msgAuthenticationParameters in an encoded message
+fn digest_offset(message: &[u8]) -> Result<usize, String> {
+    let mut reader = Reader::new(message).sequence(ber::SEQUENCE)?;
+    reader.integer()?;
+    reader.sequence(ber::SEQUENCE)?;
+    let mut params = Reader::new(reader.octets()?).sequence(ber::SEQUENCE)?;
+    params.octets()?;
+    params.integer()?;
+    params.integer()?;
+    params.octets()?;
+    Ok(params.octets()?.as_ptr() as usize - message.as_ptr() as usize)
+}
+
+fn digest_len(protocol: SnmpAuthProtocol) -> usize {
+    match protocol {
+        SnmpAuthProtocol::Md5 | SnmpAuthProtocol::Sha => 12,
+        SnmpAuthProtocol::Sha256 => 24,
+    }
+}
+
+/// truncated HMAC of a message
+fn hmac(protocol: SnmpAuthProtocol, key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut digest = match protocol {
+        SnmpAuthProtocol::Md5 => Hmac::<Md5>::new_from_slice(key).unwrap().chain_update(message).finalize().into_bytes().to_vec(),
+        SnmpAuthProtocol::Sha => Hmac::<Sha1>::new_from_slice(key).unwrap().chain_update(message).finalize().into_bytes().to_vec(),
+        SnmpAuthProtocol::Sha256 => Hmac::<Sha256>::new_from_slice(key).unwrap().chain_update(message).finalize().into_bytes().to_vec(),
+    };
+    digest.truncate(digest_len(protocol));
+    digest
+}
+
+/// password to key algorithm of RFC 3414 A.2, localized to an engine
+fn localized_key(protocol: SnmpAuthProtocol, password: &[u8], engine_id: &[u8]) -> Vec<u8> {
+    fn localize<D: Digest>(password: &[u8], engine_id: &[u8]) -> Vec<u8> {
+        let mut hasher = D::new();
+        let mut chunk = [0; 64];
+        for i in 0..1048576 / chunk.len() {
+            for (j, b) in chunk.iter_mut().enumerate() {
+                *b = password[(i * 64 + j) % password.len()];
+            }
+            hasher.update(chunk);
+        }
+        let key = hasher.finalize();
+        D::new().chain_update(&key).chain_update(engine_id).chain_update(&key).finalize().to_vec()
+    }
+    match protocol {
+        SnmpAuthProtocol::Md5 => localize::<Md5>(password, engine_id),
+        SnmpAuthProtocol::Sha => localize::<Sha1>(password, engine_id),
+        SnmpAuthProtocol::Sha256 => localize::<Sha256>(password, engine_id),
+    }
+}
+
+/// encrypted scoped PDU and privacy parameters (the salt)
+fn encrypt(protocol: SnmpPrivProtocol, key: &[u8], boots: u32, time: u32, salt: u64, mut data: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+    match protocol {
+        SnmpPrivProtocol::Des => {
+            let salt = [boots.to_be_bytes(), (salt as u32).to_be_bytes()].concat();
+            let iv: Vec<u8> = key[8..16].iter().zip(&salt).map(|(a, b)| a ^ b).collect();
+            data.resize(data.len().div_ceil(8) * 8, 0);
+            let mut cipher = cbc::Encryptor::<Des>::new_from_slices(&key[..8], &iv).unwrap();
+            for block in data.chunks_exact_mut(8) {
+                cipher.encrypt_block_mut(block.into());
+            }
+            (data, salt)
+        }
+        SnmpPrivProtocol::Aes => {
+            let iv = [&boots.to_be_bytes()[..], &time.to_be_bytes(), &salt.to_be_bytes()].concat();
+            cfb_mode::Encryptor::<Aes128>::new_from_slices(&key[..16], &iv).unwrap().encrypt(&mut data);
+            (data, salt.to_be_bytes().to_vec())
+        }
+    }
+}
+
+fn decrypt(protocol: SnmpPrivProtocol, key: &[u8], boots: u32, time: u32, salt: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    if salt.len() != 8 {
+        return Err(format!("invalid privacy parameters {salt:02x?}"));
+    }
+    let mut data = data.to_vec();
+    match protocol {
+        SnmpPrivProtocol::Des => {
+            if !data.len().is_multiple_of(8) {
+                return Err("encrypted data is no multiple of the DES block size".to_string());
+            }
+            let iv: Vec<u8> = key[8..16].iter().zip(salt).map(|(a, b)| a ^ b).collect();
+            let mut cipher = cbc::Decryptor::<Des>::new_from_slices(&key[..8], &iv).unwrap();
+            for block in data.chunks_exact_mut(8) {
+                cipher.decrypt_block_mut(block.into());
+            }
+        }
+        SnmpPrivProtocol::Aes => {
+            let iv = [&boots.to_be_bytes()[..], &time.to_be_bytes(), salt].concat();
+            cfb_mode::Decryptor::<Aes128>::new_from_slices(&key[..16], &iv).unwrap().decrypt(&mut data);
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+    use futures::StreamExt;
+    use serde_json::json;
+    use super::*;
+
+    /// agent answering Get and GetBulk requests from its MIB
+    struct Agent {
+        security: Security,
+        /// unauthenticated USM for reports during discovery
+        discovery: Usm,
+        /// answer with an unauthenticated response or notInTimeWindows report after discovery
+        downgrade: Option<u8>,
+        mib: BTreeMap<Oid, VarBind>,
+    }
+
+    impl Agent {
+        fn new(config: &SnmpConfig) -> Self {
+            let engine_id = b"\x80\x00\x1f\x88\x04test-agent";
+            let mut security = Security::new(config);
+            if let Security::Usm(usm) = &mut security {
+                usm.set_engine(engine_id, 3, 1000);
+            }
+            let mut discovery = Usm { user: Vec::new(), context: Vec::new(), auth: None, privacy: None, engine: None, salt: 0 };
+            discovery.set_engine(engine_id, 3, 1000);
+
+            let mut mib = BTreeMap::new();
+            let mut insert = |oid: &str, tag: u8, content: Vec<u8>| {
+                let oid = ber::parse_oid(oid).unwrap();
+                mib.insert(oid.clone(), VarBind { oid, tag, content });
+            };
+            insert("1.3.6.1.2.1.1.1.0", ber::OCTET_STRING, b"Test Switch".to_vec());
+            insert("1.3.6.1.2.1.1.3.0", ber::TIME_TICKS, ber::unsigned(ber::TIME_TICKS, 123456)[2..].to_vec());
+            // more interfaces than fit into a single GetBulkResponse
+            for i in 1..=30u8 {
+                insert(&format!("1.3.6.1.2.1.2.2.1.1.{i}"), ber::INTEGER, vec![i]);
+                insert(&format!("1.3.6.1.2.1.2.2.1.2.{i}"), ber::OCTET_STRING, format!("port{i}").into_bytes());
+                insert(&format!("1.3.6.1.2.1.2.2.1.6.{i}"), ber::OCTET_STRING, vec![0x00, 0x11, 0x22, 0x33, 0x44, i]);
+                insert(&format!("1.3.6.1.2.1.2.2.1.10.{i}"), ber::COUNTER32, ber::unsigned(ber::COUNTER32, 0x8000_0000 + i as u64)[2..].to_vec());
+            }
+            insert("1.3.6.1.2.1.4.1.0", ber::INTEGER, vec![1]);
+            Agent { security, discovery, downgrade: None, mib }
+        }
+
+        fn respond(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+            let (_, request) = self.security.decode(request).ok()?;
+            let mut response = Pdu { tag: RESPONSE, request_id: request.request_id, error_status: 0, error_index: 0, varbinds: Vec::new() };
+            if matches!(self.security, Security::Usm(_)) && request.varbinds.is_empty() {
+                response.tag = REPORT;
+                response.varbinds.push(VarBind { oid: [&USM_STATS[..], &[UNKNOWN_ENGINE_IDS, 0]].concat(), tag: ber::COUNTER32, content: vec![1] });
+                return self.discovery.encode(response.request_id, &response).ok();
+            }
+            if self.downgrade == Some(REPORT) {
+                let mut spoofed = Usm { user: Vec::new(), context: Vec::new(), auth: None, privacy: None, engine: None, salt: 0 };
+                spoofed.set_engine(&self.discovery.engine.as_ref().unwrap().id, 99, 0);
+                response.tag = REPORT;
+                response.varbinds.push(VarBind { oid: [&USM_STATS[..], &[NOT_IN_TIME_WINDOWS, 0]].concat(), tag: ber::COUNTER32, content: vec![1] });
+                return spoofed.encode(response.request_id, &response).ok();
+            }
+            let missing = |oid: &Oid, tag| VarBind { oid: oid.clone(), tag, content: Vec::new() };
+            match request.tag {
+                GET_REQUEST => for varbind in &request.varbinds {
+                    response.varbinds.push(self.mib.get(&varbind.oid).cloned().unwrap_or_else(|| missing(&varbind.oid, ber::NO_SUCH_OBJECT)));
+                }
+                GET_BULK_REQUEST => {
+                    let mut oid = request.varbinds[0].oid.clone();
+                    for _ in 0..request.error_index {
+                        let next = self.mib.range::<Oid, _>((Bound::Excluded(&oid), Bound::Unbounded)).next()
+                            .map(|(_, varbind)| varbind.clone())
+                            .unwrap_or_else(|| missing(&oid, ber::END_OF_MIB_VIEW));
+                        oid.clone_from(&next.oid);
+                        response.varbinds.push(next);
+                    }
+                }
+                tag => panic!("unexpected PDU type {tag:02x}"),
+            }
+            if self.downgrade == Some(RESPONSE) {
+                return self.discovery.encode(response.request_id, &response).ok();
+            }
+            self.security.encode(&response).ok()
+        }
+    }
+
+    async fn serve(mut agent: Agent) -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 65535];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                if let Some(response) = agent.respond(&buf[..len]) {
+                    socket.send_to(&response, peer).await.unwrap();
+                }
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn v2c_walks() {
+        let config: SnmpConfig = toml::from_str(r#"
+            host = "127.0.0.1"
+            frequency_secs = 0
+            oids.description = "1.3.6.1.2.1.1.1.0"
+            oids.uptime = ".1.3.6.1.2.1.1.3.0"
+            oids.missing = "1.3.6.1.2.1.1.99.0"
+            walks.interfaces = { oid = "1.3.6.1.2.1.2.2.1", columns = { name = 2, in-octets = 10 } }
+            walks.if-table = { oid = "1.3.6.1.2.1.2.2.1" }
+        "#).unwrap();
+        let port = serve(Agent::new(&config)).await;
+        let mut stream = stream(SnmpConfig { port, ..config }).boxed();
+        let res = stream.next().await.unwrap();
+        assert_eq!(res["description"], "Test Switch");
+        assert_eq!(res["uptime"], 123456);
+        assert_eq!(res.get("missing"), None);
+        assert_eq!(res["interfaces"].as_object().unwrap().len(), 30);
+        assert_eq!(res["interfaces"]["17"], json!({ "name": "port17", "in-octets": 0x8000_0011u32 }));
+        assert_eq!(res["if-table"]["3"], json!({ "1": 3, "2": "port3", "6": "00:11:22:33:44:03", "10": 0x8000_0003u32 }));
+        assert_eq!(res.pointer("/if-table/30/2"), Some(&json!("port30")));
+    }
+
+    #[tokio::test]
+    async fn v3_security() {
+        for (auth, privacy) in [("md5", None), ("sha", Some("des")), ("sha", Some("aes")), ("sha256", Some("aes"))] {
+            let mut config: SnmpConfig = toml::from_str(&format!(r#"
+                host = "127.0.0.1"
+                version = "v3"
+                user = "iot2db"
+                auth_protocol = "{auth}"
+                auth_password = "authpassword"
+                frequency_secs = 0
+                oids.description = "1.3.6.1.2.1.1.1.0"
+            "#)).unwrap();
+            if let Some(privacy) = privacy {
+                config.priv_protocol = toml::Value::from(privacy).try_into().unwrap();
+                config.priv_password = Some("privpassword".to_string());
+            }
+            config.port = serve(Agent::new(&config)).await;
+            let mut client = Client::new(&config);
+            let oid = ber::parse_oid("1.3.6.1.2.1.1.1.0").unwrap();
+            let res = client.get(std::slice::from_ref(&oid)).await.unwrap();
+            assert_eq!(res[0].to_json(), Some(json!("Test Switch")), "{auth} {privacy:?}");
+            let Security::Usm(usm) = &client.security else { unreachable!() };
+            assert_eq!(usm.engine.as_ref().unwrap().boots, 3);
+
+            // the agent ignores requests with a wrong digest
+            if privacy.is_none() {
+                config.auth_password = Some("wrongpassword".to_string());
+                config.timeout_secs = 1;
+                config.retries = 0;
+                let mut client = Client::new(&config);
+                assert_eq!(client.get(&[oid]).await, Err(format!("timeout waiting for 127.0.0.1:{}", config.port)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn v3_downgrade() {
+        let config: SnmpConfig = toml::from_str(r#"
+            host = "127.0.0.1"
+            version = "v3"
+            user = "iot2db"
+            auth_protocol = "sha"
+            auth_password = "authpassword"
+            priv_protocol = "aes"
+            priv_password = "privpassword"
+            frequency_secs = 0
+            timeout_secs = 1
+            retries = 0
+        "#).unwrap();
+        let oid = ber::parse_oid("1.3.6.1.2.1.1.1.0").unwrap();
+        for downgrade in [RESPONSE, REPORT] {
+            let mut agent = Agent::new(&config);
+            agent.downgrade = Some(downgrade);
+            let port = serve(agent).await;
+            let mut client = Client::new(&SnmpConfig { port, ..config.clone() });
+            assert_eq!(client.get(std::slice::from_ref(&oid)).await, Err(format!("timeout waiting for 127.0.0.1:{port}")));
+            // the spoofed report didn't reset the engine time
+            let Security::Usm(usm) = &client.security else { unreachable!() };
+            assert_eq!(usm.engine.as_ref().unwrap().boots, 3);
+        }
+    }
+
+    #[test]
+    fn v3_time_window() {
+        let config: SnmpConfig = toml::from_str(r#"
+            host = "127.0.0.1"
+            version = "v3"
+            user = "iot2db"
+            auth_protocol = "sha"
+            auth_password = "authpassword"
+            frequency_secs = 0
+            oids.description = "1.3.6.1.2.1.1.1.0"
+        "#).unwrap();
+        let engine_id = b"\x80\x00\x1f\x88\x04test-agent";
+        let mut client = Usm::new(&config);
+        client.set_engine(engine_id, 3, 1000);
+        let mut agent = Usm::new(&config);
+        let response = Pdu { tag: RESPONSE, request_id: 1, error_status: 0, error_index: 0, varbinds: Vec::new() };
+        let mut respond = |boots, time| {
+            agent.set_engine(engine_id, boots, time);
+            let message = agent.encode(1, &response).unwrap();
+            client.decode(&message).map(|_| client.engine.as_ref().map(|engine| (engine.boots, engine.time)).unwrap())
+        };
+        assert_eq!(respond(3, 1010), Ok((3, 1010)));
+        assert_eq!(respond(3, 900), Ok((3, 1010)));
+        // replayed messages of an earlier time or boot
+        assert_eq!(respond(3, 800), Err("message not in time window (boots 3, time 800)".to_string()));
+        assert_eq!(respond(2, 5000), Err("message not in time window (boots 2, time 5000)".to_string()));
+        assert_eq!(respond(4, 10), Ok((4, 10)));
+        assert_eq!(respond(i32::MAX as u32, 10), Err(format!("message not in time window (boots {}, time 10)", i32::MAX)));
+    }
+
+    #[test]
+    fn key_localization() {
+        // RFC 3414 A.3
+        let engine_id = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+        assert_eq!(hex(&localized_key(SnmpAuthProtocol::Md5, b"maplesyrup", &engine_id)), "52:6f:5e:ed:9f:cc:e2:6f:89:64:c2:93:07:87:d8:2b");
+        assert_eq!(hex(&localized_key(SnmpAuthProtocol::Sha, b"maplesyrup", &engine_id)), "66:95:fe:bc:92:88:e3:62:82:23:5f:c7:15:1f:12:84:97:b3:8f:3f");
+    }
+}
@@ -0,0 +1,321 @@
+//! Discovery and decoding of [SunSpec](https://sunspec.org/) model blocks
+//!
+//! Points of known models are returned by their SunSpec name with scale factors applied,
+//! unimplemented points are left out. Unknown models are returned as raw registers.
+
+use serde_json::{Map, Number, Value};
+use crate::config::ModbusFunction;
+use crate::frontend::modbus::{Data, Error, Read, Transport};
+
+/// `SunS`
+const MARKER: [u16; 2] = [0x5375, 0x6e53];
+const END: u16 = 0xffff;
+const DEFAULT_BASE_ADDRESSES: [u16; 3] = [40000, 50000, 0];
+const MAX_REGISTERS: u16 = 125;
+
+/// model block found during discovery
+#[derive(Debug, Clone, PartialEq)]
+pub struct Model {
+    pub id: u16,
+    /// key the model is returned under, e.g. `inverter` or `inverter_2` for the second one
+    pub name: String,
+    /// address of the first point after the id and length
+    pub address: u16,
+    pub length: u16,
+}
+
+/// Finds the model blocks of the device, trying the default base addresses if none is given.
+pub async fn discover(transport: &mut impl Transport, unit: u8, base_address: Option<u16>) -> Result<Vec<Model>, Error> {
+    let candidates = match base_address {
+        Some(address) => vec![address],
+        None => DEFAULT_BASE_ADDRESSES.to_vec(),
+    };
+    let mut base = None;
+    let mut last_error = None;
+    for address in candidates {
+        match read_registers(transport, unit, address, 2).await {
+            Ok(marker) if marker == MARKER => {
+                base = Some(address);
+                break;
+            }
+            Ok(_) | Err(Error::Exception(_)) => (),
+            // some devices don't answer requests of unmapped addresses at all
+            Err(e) if base_address.is_none() => last_error = Some(e),
+            Err(e) => return Err(e),
+        }
+    }
+    let Some(base) = base else {
+        return Err(Error::Transport(match last_error {
+            Some(e) => format!("no SunSpec marker found at unit {unit}, last error: {e}"),
+            None => format!("no SunSpec marker found at unit {unit}"),
+        }));
+    };
+
+    let mut models: Vec<Model> = Vec::new();
+    let mut address = base as u32 + 2;
+    while address + 2 <= 0x10000 {
+        let header = read_registers(transport, unit, address as u16, 2).await?;
+        let (id, length) = (header[0], header[1]);
+        if id == END {
+            break;
+        }
+        let mut name = definition(id).map_or_else(|| format!("model_{id}"), |(name, _)| name.to_string());
+        let count = models.iter().filter(|model| model.id == id).count();
+        if count > 0 {
+            name = format!("{name}_{}", count + 1);
+        }
+        let data = u16::try_from(address + 2).ok()
+            .filter(|&data| data as u32 + length as u32 <= 0x10000)
+            .ok_or_else(|| Error::Transport(format!("SunSpec model {id} at {address} with length {length} exceeds the register space")))?;
+        models.push(Model { id, name, address: data, length });
+        address += 2 + length as u32;
+    }
+    Ok(models)
+}
+
+/// current values of all points of the models
+pub async fn read(transport: &mut impl Transport, unit: u8, models: &[Model]) -> Result<Map<String, Value>, Error> {
+    let mut res = Map::new();
+    for model in models {
+        let mut words = Vec::with_capacity(model.length as usize);
+        let mut offset = 0;
+        while offset < model.length {
+            let count = (model.length - offset).min(MAX_REGISTERS);
+            let address = model.address.checked_add(offset)
+                .ok_or_else(|| Error::Transport(format!("SunSpec model {} exceeds the register space", model.name)))?;
+            words.extend(read_registers(transport, unit, address, count).await?);
+            offset += count;
+        }
+        let value = match definition(model.id) {
+            Some((_, points)) => Value::Object(decode(points, &words)),
+            None => Value::Array(words.into_iter().map(Value::from).collect()),
+        };
+        res.insert(model.name.clone(), value);
+    }
+    Ok(res)
+}
+
+async fn read_registers(transport: &mut impl Transport, unit: u8, address: u16, count: u16) -> Result<Vec<u16>, Error> {
+    let read = Read { unit, function: ModbusFunction::Holding, address, count, registers: Vec::new() };
+    match read.execute(transport).await? {
+        Data::Registers(words) => Ok(words),
+        Data::Bits(_) => unreachable!("holding registers are read"),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Type {
+    U16,
+    I16,
+    Acc32,
+    F32,
+    Enum16,
+    Bitfield32,
+    /// number of registers
+    String(u16),
+    /// scale factor of other points
+    Sf,
+}
+
+/// name, offset after the model's length, type and name of its scale factor
+type Point = (&'static str, u16, Type, Option<&'static str>);
+
+fn decode(points: &[Point], words: &[u16]) -> Map<String, Value> {
+    let raw = |offset: u16, ty: Type| -> Option<Value> {
+        let offset = offset as usize;
+        let word = |i: usize| words.get(offset + i).copied();
+        let double = || Some((word(0)? as u32) << 16 | word(1)? as u32);
+        match ty {
+            Type::U16 | Type::Enum16 => word(0).filter(|&w| w != 0xffff).map(Value::from),
+            Type::I16 | Type::Sf => word(0).map(|w| w as i16).filter(|&w| w != i16::MIN).map(Value::from),
+            Type::Bitfield32 => double().filter(|&w| w != u32::MAX).map(Value::from),
+            Type::Acc32 => double().map(Value::from),
+            Type::F32 => double().map(f32::from_bits).and_then(|f| Number::from_f64(f as f64)).map(Value::Number),
+            Type::String(len) => {
+                let bytes: Vec<u8> = words.get(offset..offset + len as usize)?.iter().flat_map(|w| w.to_be_bytes()).collect();
+                let text = String::from_utf8_lossy(&bytes);
+                Some(Value::String(text.trim_end_matches(['\0', ' ']).to_string()))
+            }
+        }
+    };
+
+    let mut res = Map::new();
+    for &(name, offset, ty, sf) in points {
+        if let Type::Sf = ty {
+            continue;
+        }
+        let Some(value) = raw(offset, ty) else { continue };
+        let value = match sf {
+            None => value,
+            Some(sf) => {
+                let &(_, sf_offset, _, _) = points.iter().find(|(name, ..)| *name == sf).expect("scale factor of point is defined");
+                let Some(sf) = raw(sf_offset, Type::Sf).and_then(|sf| sf.as_i64()) else { continue };
+                if sf == 0 {
+                    res.insert(name.to_string(), value);
+                    continue;
+                }
+                let value = value.as_f64().unwrap();
+                // dividing by 10^-sf avoids results like `230.10000000000002`
+                let scaled = match sf {
+                    sf if sf < 0 => value / 10f64.powi(-sf as i32),
+                    sf => value * 10f64.powi(sf as i32),
+                };
+                match Number::from_f64(scaled) {
+                    Some(number) => Value::Number(number),
+                    None => continue,
+                }
+            }
+        };
+        res.insert(name.to_string(), value);
+    }
+    res
+}
+
+/// name and points of known models
+fn definition(id: u16) -> Option<(&'static str, &'static [Point])> {
+    match id {
+        1 => Some(("common", COMMON)),
+        101..=103 => Some(("inverter", INVERTER)),
+        111..=113 => Some(("inverter", INVERTER_FLOAT)),
+        201..=204 => Some(("ac_meter", AC_METER)),
+        _ => None,
+    }
+}
+
+const COMMON: &[Point] = &[
+    ("Mn", 0, Type::String(16), None),
+    ("Md", 16, Type::String(16), None),
+    ("Opt", 32, Type::String(8), None),
+    ("Vr", 40, Type::String(8), None),
+    ("SN", 48, Type::String(16), None),
+    ("DA", 64, Type::U16, None),
+];
+
+const INVERTER: &[Point] = &[
+    ("A", 0, Type::U16, Some("A_SF")),
+    ("AphA", 1, Type::U16, Some("A_SF")),
+    ("AphB", 2, Type::U16, Some("A_SF")),
+    ("AphC", 3, Type::U16, Some("A_SF")),
+    ("A_SF", 4, Type::Sf, None),
+    ("PPVphAB", 5, Type::U16, Some("V_SF")),
+    ("PPVphBC", 6, Type::U16, Some("V_SF")),
+    ("PPVphCA", 7, Type::U16, Some("V_SF")),
+    ("PhVphA", 8, Type::U16, Some("V_SF")),
+    ("PhVphB", 9, Type::U16, Some("V_SF")),
+    ("PhVphC", 10, Type::U16, Some("V_SF")),
+    ("V_SF", 11, Type::Sf, None),
+    ("W", 12, Type::I16, Some("W_SF")),
+    ("W_SF", 13, Type::Sf, None),
+    ("Hz", 14, Type::U16, Some("Hz_SF")),
+    ("Hz_SF", 15, Type::Sf, None),
+    ("VA", 16, Type::I16, Some("VA_SF")),
+    ("VA_SF", 17, Type::Sf, None),
+    ("VAr", 18, Type::I16, Some("VAr_SF")),
+    ("VAr_SF", 19, Type::Sf, None),
+    ("PF", 20, Type::I16, Some("PF_SF")),
+    ("PF_SF", 21, Type::Sf, None),
+    ("WH", 22, Type::Acc32, Some("WH_SF")),
+    ("WH_SF", 24, Type::Sf, None),
+    ("DCA", 25, Type::U16, Some("DCA_SF")),
+    ("DCA_SF", 26, Type::Sf, None),
+    ("DCV", 27, Type::U16, Some("DCV_SF")),
+    ("DCV_SF", 28, Type::Sf, None),
+    ("DCW", 29, Type::I16, Some("DCW_SF")),
+    ("DCW_SF", 30, Type::Sf, None),
+    ("TmpCab", 31, Type::I16, Some("Tmp_SF")),
+    ("TmpSnk", 32, Type::I16, Some("Tmp_SF")),
+    ("TmpTrns", 33, Type::I16, Some("Tmp_SF")),
+    ("TmpOt", 34, Type::I16, Some("Tmp_SF")),
+    ("Tmp_SF", 35, Type::Sf, None),
+    ("St", 36, Type::Enum16, None),
+    ("StVnd", 37, Type::Enum16, None),
+    ("Evt1", 38, Type::Bitfield32, None),
+    ("Evt2", 40, Type::Bitfield32, None),
+    ("EvtVnd1", 42, Type::Bitfield32, None),
+    ("EvtVnd2", 44, Type::Bitfield32, None),
+    ("EvtVnd3", 46, Type::Bitfield32, None),
+    ("EvtVnd4", 48, Type::Bitfield32, None),
+];
+
+const INVERTER_FLOAT: &[Point] = &[
+    ("A", 0, Type::F32, None),
+    ("AphA", 2, Type::F32, None),
+    ("AphB", 4, Type::F32, None),
+    ("AphC", 6, Type::F32, None),
+    ("PPVphAB", 8, Type::F32, None),
+    ("PPVphBC", 10, Type::F32, None),
+    ("PPVphCA", 12, Type::F32, None),
+    ("PhVphA", 14, Type::F32, None),
+    ("PhVphB", 16, Type::F32, None),
+    ("PhVphC", 18, Type::F32, None),
+    ("W", 20, Type::F32, None),
+    ("Hz", 22, Type::F32, None),
+    ("VA", 24, Type::F32, None),
+    ("VAr", 26, Type::F32, None),
+    ("PF", 28, Type::F32, None),
+    ("WH", 30, Type::F32, None),
+    ("DCA", 32, Type::F32, None),
+    ("DCV", 34, Type::F32, None),
+    ("DCW", 36, Type::F32, None),
+    ("TmpCab", 38, Type::F32, None),
+    ("TmpSnk", 40, Type::F32, None),
+    ("TmpTrns", 42, Type::F32, None),
+    ("TmpOt", 44, Type::F32, None),
+    ("St", 46, Type::Enum16, None),
+    ("StVnd", 47, Type::Enum16, None),
+    ("Evt1", 48, Type::Bitfield32, None),
+    ("Evt2", 50, Type::Bitfield32, None),
+    ("EvtVnd1", 52, Type::Bitfield32, None),
+    ("EvtVnd2", 54, Type::Bitfield32, None),
+    ("EvtVnd3", 56, Type::Bitfield32, None),
+    ("EvtVnd4", 58, Type::Bitfield32, None),
+];
+
+/// the current, voltage, power and energy points of the integer meter models
+const AC_METER: &[Point] = &[
+    ("A", 0, Type::I16, Some("A_SF")),
+    ("AphA", 1, Type::I16, Some("A_SF")),
+    ("AphB", 2, Type::I16, Some("A_SF")),
+    ("AphC", 3, Type::I16, Some("A_SF")),
+    ("A_SF", 4, Type::Sf, None),
+    ("PhV", 5, Type::I16, Some("V_SF")),
+    ("PhVphA", 6, Type::I16, Some("V_SF")),
+    ("PhVphB", 7, Type::I16, Some("V_SF")),
+    ("PhVphC", 8, Type::I16, Some("V_SF")),
+    ("PPV", 9, Type::I16, Some("V_SF")),
+    ("PPVphAB", 10, Type::I16, Some("V_SF")),
+    ("PPVphBC", 11, Type::I16, Some("V_SF")),
+    ("PPVphCA", 12, Type::I16, Some("V_SF")),
+    ("V_SF", 13, Type::Sf, None),
+    ("Hz", 14, Type::I16, Some("Hz_SF")),
+    ("Hz_SF", 15, Type::Sf, None),
+    ("W", 16, Type::I16, Some("W_SF")),
+    ("WphA", 17, Type::I16, Some("W_SF")),
+    ("WphB", 18, Type::I16, Some("W_SF")),
+    ("WphC", 19, Type::I16, Some("W_SF")),
+    ("W_SF", 20, Type::Sf, None),
+    ("VA", 21, Type::I16, Some("VA_SF")),
+    ("VAphA", 22, Type::I16, Some("VA_SF")),
+    ("VAphB", 23, Type::I16, Some("VA_SF")),
+    ("VAphC", 24, Type::I16, Some("VA_SF")),
+    ("VA_SF", 25, Type::Sf, None),
+    ("VAR", 26, Type::I16, Some("VAR_SF")),
+    ("VARphA", 27, Type::I16, Some("VAR_SF")),
+    ("VARphB", 28, Type::I16, Some("VAR_SF")),
+    ("VARphC", 29, Type::I16, Some("VAR_SF")),
+    ("VAR_SF", 30, Type::Sf, None),
+    ("PF", 31, Type::I16, Some("PF_SF")),
+    ("PFphA", 32, Type::I16, Some("PF_SF")),
+    ("PFphB", 33, Type::I16, Some("PF_SF")),
+    ("PFphC", 34, Type::I16, Some("PF_SF")),
+    ("PF_SF", 35, Type::Sf, None),
+    ("TotWhExp", 36, Type::Acc32, Some("TotWh_SF")),
+    ("TotWhExpPhA", 38, Type::Acc32, Some("TotWh_SF")),
+    ("TotWhExpPhB", 40, Type::Acc32, Some("TotWh_SF")),
+    ("TotWhExpPhC", 42, Type::Acc32, Some("TotWh_SF")),
+    ("TotWhImp", 44, Type::Acc32, Some("TotWh_SF")),
+    ("TotWhImpPhA", 46, Type::Acc32, Some("TotWh_SF")),
+    ("TotWhImpPhB", 48, Type::Acc32, Some("TotWh_SF")),
+    ("TotWhImpPhC", 50, Type::Acc32, Some("TotWh_SF")),
+    ("TotWh_SF", 52, Type::Sf, None),
+];
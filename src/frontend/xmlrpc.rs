@@ -0,0 +1,313 @@
+//! Minimal XML-RPC encoding, with values represented as JSON
+//!
+//! `base64` and `dateTime.iso8601` values are kept as strings.
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde_json::{Map, Number, Value};
+
+/// parse a `methodCall`, returning its method name and parameters
+pub fn parse_method_call(xml: &str) -> Result<(String, Vec<Value>), String> {
+    let mut parser = Parser::new(xml);
+    parser.expect_start("methodCall")?;
+    parser.expect_start("methodName")?;
+    let method = parser.text_until_end("methodName")?;
+    let mut params = Vec::new();
+    match parser.next_tag()? {
+        Tag::Start(name) if name == "params" => {
+            loop {
+                match parser.next_tag()? {
+                    Tag::Start(name) if name == "param" => {
+                        parser.expect_start("value")?;
+                        params.push(parser.value()?);
+                        parser.expect_end("param")?;
+                    }
+                    Tag::End(name) if name == "params" => break,
+                    tag => return Err(format!("unexpected {tag:?} in params")),
+                }
+            }
+            parser.expect_end("methodCall")?;
+        }
+        Tag::Empty(name) if name == "params" => parser.expect_end("methodCall")?,
+        Tag::End(name) if name == "methodCall" => (),
+        tag => return Err(format!("unexpected {tag:?} in methodCall")),
+    }
+    Ok((method, params))
+}
+
+/// encode a successful `methodResponse`
+pub fn method_response(value: &Value) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0"?><methodResponse><params><param>"#);
+    encode_value(value, &mut xml);
+    xml.push_str("</param></params></methodResponse>");
+    xml
+}
+
+/// encode a `methodCall`
+pub fn method_call(method: &str, params: &[Value]) -> String {
+    let mut xml = format!(r#"<?xml version="1.0"?><methodCall><methodName>{}</methodName><params>"#, escape(method));
+    for param in params {
+        xml.push_str("<param>");
+        encode_value(param, &mut xml);
+        xml.push_str("</param>");
+    }
+    xml.push_str("</params></methodCall>");
+    xml
+}
+
+/// parse a `methodResponse`, returning its value or the fault as error
+pub fn parse_method_response(xml: &str) -> Result<Value, String> {
+    let mut parser = Parser::new(xml);
+    parser.expect_start("methodResponse")?;
+    match parser.next_tag()? {
+        Tag::Start(name) if name == "params" => {
+            parser.expect_start("param")?;
+            parser.expect_start("value")?;
+            parser.value()
+        }
+        Tag::Start(name) if name == "fault" => {
+            parser.expect_start("value")?;
+            Err(format!("XML-RPC fault: {}", parser.value()?))
+        }
+        tag => Err(format!("unexpected {tag:?} in methodResponse")),
+    }
+}
+
+fn encode_value(value: &Value, xml: &mut String) {
+    xml.push_str("<value>");
+    match value {
+        Value::Null => xml.push_str("<nil/>"),
+        Value::Bool(b) => xml.push_str(&format!("<boolean>{}</boolean>", *b as u8)),
+        Value::Number(n) if n.as_i64().is_some_and(|i| i32::try_from(i).is_ok()) => xml.push_str(&format!("<i4>{n}</i4>")),
+        // 64-bit extension of the XML-RPC spec
+        Value::Number(n) if n.is_i64() => xml.push_str(&format!("<i8>{n}</i8>")),
+        Value::Number(n) => xml.push_str(&format!("<double>{n}</double>")),
+        Value::String(s) => xml.push_str(&escape(s)),
+        Value::Array(values) => {
+            xml.push_str("<array><data>");
+            for value in values {
+                encode_value(value, xml);
+            }
+            xml.push_str("</data></array>");
+        }
+        Value::Object(map) => {
+            xml.push_str("<struct>");
+            for (name, value) in map {
+                xml.push_str(&format!("<member><name>{}</name>", escape(name)));
+                encode_value(value, xml);
+                xml.push_str("</member>");
+            }
+            xml.push_str("</struct>");
+        }
+    }
+    xml.push_str("</value>");
+}
+
+fn escape(s: &str) -> String {
+    quick_xml::escape::escape(s).into_owned()
+}
+
+#[derive(Debug)]
+enum Tag {
+    Start(String),
+    End(String),
+    Empty(String),
+}
+
+struct Parser<'a> {
+    reader: Reader<&'a [u8]>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(xml: &'a str) -> Self {
+        let mut reader = Reader::from_str(xml);
+        reader.trim_text(true);
+        Parser { reader }
+    }
+
+    /// next start, end or empty tag, skipping declarations and comments
+    fn next_tag(&mut self) -> Result<Tag, String> {
+        loop {
+            match self.reader.read_event().map_err(|e| format!("invalid xml: {e}"))? {
+                Event::Start(start) => return Ok(Tag::Start(String::from_utf8_lossy(start.local_name().as_ref()).into_owned())),
+                Event::End(end) => return Ok(Tag::End(String::from_utf8_lossy(end.local_name().as_ref()).into_owned())),
+                Event::Empty(empty) => return Ok(Tag::Empty(String::from_utf8_lossy(empty.local_name().as_ref()).into_owned())),
+                Event::Eof => return Err("unexpected end of xml".to_string()),
+                Event::Text(text) => return Err(format!("unexpected text {:?}", String::from_utf8_lossy(&text))),
+                _ => (),
+            }
+        }
+    }
+
+    fn expect_start(&mut self, expected: &str) -> Result<(), String> {
+        match self.next_tag()? {
+            Tag::Start(name) if name == expected => Ok(()),
+            tag => Err(format!("expected <{expected}>, got {tag:?}")),
+        }
+    }
+
+    fn expect_end(&mut self, expected: &str) -> Result<(), String> {
+        match self.next_tag()? {
+            Tag::End(name) if name == expected => Ok(()),
+            tag => Err(format!("expected </{expected}>, got {tag:?}")),
+        }
+    }
+
+    /// text content up to the closing tag
+    fn text_until_end(&mut self, tag: &str) -> Result<String, String> {
+        let mut text = String::new();
+        loop {
+            match self.reader.read_event().map_err(|e| format!("invalid xml: {e}"))? {
+                Event::Text(t) => text.push_str(&t.unescape().map_err(|e| format!("invalid xml text: {e}"))?),
+                Event::CData(cdata) => text.push_str(&String::from_utf8_lossy(&cdata)),
+                Event::End(end) if end.local_name().as_ref() == tag.as_bytes() => return Ok(text),
+                event => return Err(format!("unexpected {event:?} in <{tag}>")),
+            }
+        }
+    }
+
+    /// value after its opening `<value>` tag, including the closing tag
+    fn value(&mut self) -> Result<Value, String> {
+        let mut text = String::new();
+        loop {
+            match self.reader.read_event().map_err(|e| format!("invalid xml: {e}"))? {
+                // untyped values are strings
+                Event::Text(t) => text.push_str(&t.unescape().map_err(|e| format!("invalid xml text: {e}"))?),
+                Event::End(_) => return Ok(Value::String(text)),
+                Event::Empty(empty) => {
+                    let value = match empty.local_name().as_ref() {
+                        b"nil" => Value::Null,
+                        b"string" | b"base64" => Value::String(String::new()),
+                        b"array" => Value::Array(Vec::new()),
+                        b"struct" => Value::Object(Map::new()),
+                        name => return Err(format!("unsupported empty value <{}/>", String::from_utf8_lossy(name))),
+                    };
+                    self.expect_end("value")?;
+                    return Ok(value);
+                }
+                Event::Start(start) => {
+                    let kind = String::from_utf8_lossy(start.local_name().as_ref()).into_owned();
+                    let value = match kind.as_str() {
+                        "array" => self.array()?,
+                        "struct" => self.struct_()?,
+                        _ => {
+                            let text = self.text_until_end(&kind)?;
+                            scalar(&kind, text.trim())?
+                        }
+                    };
+                    self.expect_end("value")?;
+                    return Ok(value);
+                }
+                event => return Err(format!("unexpected {event:?} in <value>")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        let mut values = Vec::new();
+        match self.next_tag()? {
+            Tag::Start(name) if name == "data" => loop {
+                match self.next_tag()? {
+                    Tag::Start(name) if name == "value" => values.push(self.value()?),
+                    Tag::Empty(name) if name == "value" => values.push(Value::String(String::new())),
+                    Tag::End(name) if name == "data" => break,
+                    tag => return Err(format!("unexpected {tag:?} in array")),
+                }
+            },
+            Tag::Empty(name) if name == "data" => (),
+            tag => return Err(format!("unexpected {tag:?} in array")),
+        }
+        self.expect_end("array")?;
+        Ok(Value::Array(values))
+    }
+
+    fn struct_(&mut self) -> Result<Value, String> {
+        let mut map = Map::new();
+        loop {
+            match self.next_tag()? {
+                Tag::Start(name) if name == "member" => {
+                    self.expect_start("name")?;
+                    let name = self.text_until_end("name")?;
+                    let value = match self.next_tag()? {
+                        Tag::Start(tag) if tag == "value" => self.value()?,
+                        Tag::Empty(tag) if tag == "value" => Value::String(String::new()),
+                        tag => return Err(format!("unexpected {tag:?} in member")),
+                    };
+                    self.expect_end("member")?;
+                    map.insert(name, value);
+                }
+                Tag::End(name) if name == "struct" => return Ok(Value::Object(map)),
+                tag => return Err(format!("unexpected {tag:?} in struct")),
+            }
+        }
+    }
+}
+
+fn scalar(kind: &str, text: &str) -> Result<Value, String> {
+    let invalid = || format!("invalid {kind} value {text:?}");
+    match kind {
+        "i4" | "int" | "i8" => text.parse::<i64>().map(Value::from).map_err(|_| invalid()),
+        "boolean" => match text {
+            "1" => Ok(Value::Bool(true)),
+            "0" => Ok(Value::Bool(false)),
+            _ => Err(invalid()),
+        },
+        "double" => text.parse::<f64>().ok().and_then(Number::from_f64).map(Value::Number).ok_or_else(invalid),
+        "string" | "base64" | "dateTime.iso8601" => Ok(Value::String(text.to_string())),
+        _ => Err(format!("unsupported value type <{kind}>")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn multicall() {
+        let xml = r#"<?xml version="1.0"?>
+            <methodCall><methodName>system.multicall</methodName><params><param><value><array><data>
+                <value><struct>
+                    <member><name>methodName</name><value>event</value></member>
+                    <member><name>params</name><value><array><data>
+                        <value>iot2db-HmIP-RF</value>
+                        <value><string>000A:1</string></value>
+                        <value>ACTUAL_TEMPERATURE</value>
+                        <value><double>21.5</double></value>
+                    </data></array></value></member>
+                </struct></value>
+                <value><struct>
+                    <member><name>methodName</name><value>event</value></member>
+                    <member><name>params</name><value><array><data>
+                        <value>iot2db-HmIP-RF</value><value>000B:1</value><value>STATE</value><value><boolean>1</boolean></value>
+                    </data></array></value></member>
+                </struct></value>
+            </data></array></value></param></params></methodCall>"#;
+        let (method, params) = parse_method_call(xml).unwrap();
+        assert_eq!(method, "system.multicall");
+        assert_eq!(params, vec![json!([
+            { "methodName": "event", "params": ["iot2db-HmIP-RF", "000A:1", "ACTUAL_TEMPERATURE", 21.5] },
+            { "methodName": "event", "params": ["iot2db-HmIP-RF", "000B:1", "STATE", true] },
+        ])]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let params = vec![json!("http://host:9299"), json!({ "a": [1, 2.5, false, "x<y"] })];
+        let (method, parsed) = parse_method_call(&method_call("init", &params)).unwrap();
+        assert_eq!(method, "init");
+        assert_eq!(parsed, params);
+        assert_eq!(parse_method_response(&method_response(&json!([]))).unwrap(), json!([]));
+    }
+
+    #[test]
+    fn integer_sizes() {
+        let params = vec![json!(i32::MAX), json!(i32::MIN), json!(i32::MAX as i64 + 1), json!(i64::MIN), json!(u64::MAX)];
+        let xml = method_call("event", &params);
+        assert!(xml.contains("<i4>2147483647</i4>") && xml.contains("<i4>-2147483648</i4>"), "{xml}");
+        assert!(xml.contains("<i8>2147483648</i8>") && xml.contains("<i8>-9223372036854775808</i8>"), "{xml}");
+        assert!(xml.contains("<double>18446744073709551615</double>"), "{xml}");
+        let (_, parsed) = parse_method_call(&xml).unwrap();
+        assert_eq!(parsed[..4], params[..4]);
+    }
+}
@@ -2,13 +2,15 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
-use futures::{future, StreamExt};
+use futures::{future, stream, StreamExt};
 use crate::config::{BackendConfig, BackendRef, Config, DataType};
-use rebo::{FromValue, IntoValue, ReboConfig, ReturnValue};
 use serde_json::Value as JsonValue;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio_stream::wrappers::IntervalStream;
 use crate::backend::{Backend, DataToInsert, Stdout};
 use crate::backend::postgres::PostgresBackend;
-use crate::data::{DataMapper, NarrowToWide, WideToWide};
+use crate::data::{DataMapper, NarrowToNarrow, NarrowToWide, WideToWide};
 use crate::frontend::Frontends;
 
 mod config;
@@ -16,6 +18,7 @@ mod frontend;
 mod data;
 mod backend;
 mod iter_json_value;
+mod script;
 
 #[tokio::main]
 async fn main() {
@@ -33,6 +36,10 @@ async fn main() {
         .expect("can't read config file");
     let config: Config = toml::from_str(&config_content)
         .expect("error in config file");
+    if let Err(e) = config.check_scripts() {
+        eprintln!("error in config file: {e}");
+        return;
+    }
 
     let mut pg_backends = HashMap::new();
     for (name, config) in config.backend {
@@ -46,11 +53,12 @@ async fn main() {
         frontends.add(name, config).await;
     }
 
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
     let mut spawn_handles = Vec::new();
     for (data_name, data) in config.data {
         // get frontend stream
         let frontend_data_type = data.frontend.data_type;
-        let stream = frontends.stream(data.frontend, data.mapping.values.values()).await;
+        let stream = frontends.stream(data.frontend, &data.mapping).await;
 
         // get backend sink
         let (escaper, inserter) = match data.backend {
@@ -84,19 +92,40 @@ async fn main() {
         };
 
         // get value- / data mapper
-        let mut mapper: Box<dyn DataMapper + Send> = match frontend_data_type {
-            DataType::Wide => Box::new(WideToWide::new(data.mapping, escaper)),
-            DataType::Narrow => Box::new(NarrowToWide::new(data.mapping, escaper)),
+        let idle_timeout_secs = data.mapping.flush.idle_timeout_secs;
+        let mut mapper: Box<dyn DataMapper + Send> = match (frontend_data_type, &data.mapping.narrow) {
+            (DataType::Wide, None) => Box::new(WideToWide::new(data.mapping, data.transform, escaper)),
+            (DataType::Narrow, None) => Box::new(NarrowToWide::new(data.mapping, data.transform, escaper)),
+            (DataType::Narrow, Some(_)) => Box::new(NarrowToNarrow::new(data.mapping, data.transform, escaper)),
+            (DataType::Wide, Some(_)) => panic!("narrow table layout of data {data_name:?} requires frontend.data_type = \"narrow\""),
+        };
+
+        // periodically tick the mapper to flush records after their timeout
+        let ticks = match idle_timeout_secs {
+            Some(_) => IntervalStream::new(tokio::time::interval(Duration::from_secs(1)))
+                .map(|_| PipelineEvent::Tick)
+                .boxed(),
+            None => stream::pending().boxed(),
         };
+        let mut shutdown_rx = shutdown_rx.clone();
+        let shutdown = async move { let _ = shutdown_rx.wait_for(|&shutdown| shutdown).await; };
 
         // pipe everything into another
-        let future = stream
+        let values = stream
             .filter(move |value| future::ready({
                 data.filter.as_ref()
-                    .map(|code| filter_rebo(code.clone(), value.clone()))
+                    .map(|script| script.run(value.clone()).unwrap_or(false))
                     .unwrap_or(true)
             }))
-            .filter_map(move |value| future::ready(mapper.consume_value(value)))
+            .map(PipelineEvent::Value);
+        let future = stream::select(values, ticks)
+            .take_until(shutdown)
+            .chain(stream::once(future::ready(PipelineEvent::Shutdown)))
+            .flat_map(move |event| stream::iter(match event {
+                PipelineEvent::Value(value) => mapper.consume_value(value),
+                PipelineEvent::Tick => mapper.tick(),
+                PipelineEvent::Shutdown => mapper.flush(),
+            }))
             .map(move |values| DataToInsert { escaped_values: values, persistent_every_secs: data.persistent_every_secs })
             .for_each(move |data| {
                 let inserter = Arc::clone(&inserter);
@@ -106,19 +135,23 @@ async fn main() {
         spawn_handles.push(handle);
     }
 
-    future::join_all(spawn_handles).await;
-}
+    // on shutdown, let all pipelines flush their buffered data
+    tokio::spawn(async move {
+        let mut sigterm = signal(SignalKind::terminate())
+            .expect("can't register SIGTERM handler");
+        tokio::select! {
+            _ = sigterm.recv() => (),
+            _ = tokio::signal::ctrl_c() => (),
+        }
+        eprintln!("shutting down");
+        shutdown_tx.send(true).unwrap();
+    });
 
-fn filter_rebo(code: String, value: JsonValue) -> bool {
-    let config = ReboConfig::new().add_external_value("values".to_string(), value);
-    let res = rebo::run_with_config("processing".to_string(), code, config);
-    let ReturnValue::Ok(value) = res.return_value else { panic!("invalid rebo code") };
-    bool::from_value(value)
+    future::join_all(spawn_handles).await;
 }
 
-fn run_rebo<T: FromValue + IntoValue>(code: String, value: T) -> T {
-    let config = ReboConfig::new().add_external_value("value".to_string(), value);
-    let res = rebo::run_with_config("processing".to_string(), code, config);
-    let ReturnValue::Ok(value) = res.return_value else { panic!("invalid rebo code") };
-    T::from_value(value)
+enum PipelineEvent {
+    Value(JsonValue),
+    Tick,
+    Shutdown,
 }
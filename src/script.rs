@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex as StdMutex};
+use rebo::{FromValue, IntoValue, ReboConfig, ReturnValue};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value as JsonValue;
+
+/// maximum number of cached results per script before the cache is cleared
+const CACHE_SIZE: usize = 1024;
+
+/// `preprocess` / `postprocess` script taking the `value`-string and returning its replacement-string
+pub type ValueScript = Script<String, String>;
+/// `filter` script taking the `values`-json and returning if it should be processed
+pub type FilterScript = Script<JsonValue, bool>;
+
+/// Input type of a rebo script
+pub trait ScriptInput: IntoValue {
+    /// name of the external value the input is passed as
+    const NAME: &'static str;
+    /// input used to check the script when loading the config
+    fn example() -> Self;
+    /// key of the input in the result cache, `None` if results aren't cached
+    fn cache_key(&self) -> Option<String>;
+}
+
+impl ScriptInput for String {
+    const NAME: &'static str = "value";
+    fn example() -> Self { "0".to_string() }
+    fn cache_key(&self) -> Option<String> { Some(self.clone()) }
+}
+impl ScriptInput for JsonValue {
+    const NAME: &'static str = "values";
+    fn example() -> Self { JsonValue::Object(Default::default()) }
+    // whole messages hardly ever repeat
+    fn cache_key(&self) -> Option<String> { None }
+}
+
+/// Result of executing rebo code
+pub enum Execution<O> {
+    Ok(O),
+    /// syntax- or type-error, the code can't succeed for any input
+    Invalid(String),
+    /// runtime error for this input
+    Failed,
+}
+
+/// A rebo script with a cache of its results, executed with an example input by `Script::check`
+/// after loading the config.
+///
+/// Scripts are not compiled once: rebo has no API to parse and typecheck code without running it,
+/// so every uncached execution lexes, parses and typechecks it again, and `check` only finds type
+/// errors on the path taken by the example input. This needs a rebo revision with such an API.
+///
+/// The result cache is unrelated to that; it only saves executions of value scripts for recurring
+/// input strings (e.g. constants or states), not for changing measurements.
+pub struct Script<I, O> {
+    code: Arc<str>,
+    cache: Arc<StdMutex<HashMap<String, O>>>,
+    execute: fn(&str, I) -> Execution<O>,
+}
+
+impl<I: ScriptInput, O: FromValue + Clone> Script<I, O> {
+    pub fn new(code: String) -> Self {
+        Self::with_executor(code, execute)
+    }
+}
+
+impl<I: ScriptInput, O: Clone> Script<I, O> {
+    pub(crate) fn with_executor(code: String, execute: fn(&str, I) -> Execution<O>) -> Self {
+        Script {
+            code: code.into(),
+            cache: Arc::new(StdMutex::new(HashMap::new())),
+            execute,
+        }
+    }
+
+    /// check the code for syntax errors and type errors on the path of an example input
+    ///
+    /// As rebo can't check code without executing it, the script is executed with an example
+    /// input (`"0"` or `{}`); runtime errors caused by that input are ignored.
+    pub fn check(&self) -> Result<(), String> {
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| (self.execute)(&self.code, I::example())));
+        match res {
+            Ok(Execution::Invalid(e)) => Err(format!("{e} in rebo code `{}`", self.code)),
+            _ => Ok(()),
+        }
+    }
+
+    /// execute the script, returning `None` if it fails
+    pub fn run(&self, input: I) -> Option<O> {
+        let key = input.cache_key();
+        if let Some(output) = key.as_ref().and_then(|key| self.cache.lock().unwrap().get(key).cloned()) {
+            return Some(output);
+        }
+        let output = match (self.execute)(&self.code, input) {
+            Execution::Ok(output) => output,
+            Execution::Invalid(e) => {
+                eprintln!("{e} in rebo code `{}`", self.code);
+                return None
+            }
+            Execution::Failed => {
+                eprintln!("error executing rebo code `{}` for {} {key:?}", self.code, I::NAME);
+                return None
+            }
+        };
+        if let Some(key) = key {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.len() >= CACHE_SIZE {
+                cache.clear();
+            }
+            cache.insert(key, output.clone());
+        }
+        Some(output)
+    }
+}
+
+fn execute<I: ScriptInput, O: FromValue>(code: &str, input: I) -> Execution<O> {
+    let config = ReboConfig::new().add_external_value(I::NAME.to_string(), input);
+    execution(rebo::run_with_config("processing".to_string(), code.to_string(), config).return_value)
+}
+
+fn execution<O: FromValue>(return_value: ReturnValue) -> Execution<O> {
+    match return_value {
+        ReturnValue::Ok(value) => Execution::Ok(O::from_value(value)),
+        ReturnValue::ParseError => Execution::Invalid("syntax error".to_string()),
+        ReturnValue::Diagnostics(num) => Execution::Invalid(format!("{num} error(s)")),
+        #[allow(unreachable_patterns)]
+        _ => Execution::Failed,
+    }
+}
+
+/// `transform` script taking the mapped `record` and the `state` of the previous execution,
+/// returning `{ "records": [...], "state": ... }`
+///
+/// As the result depends on the state, it is never cached.
+#[derive(Clone)]
+pub struct TransformScript {
+    code: Arc<str>,
+    execute: fn(&str, JsonValue, JsonValue) -> Execution<JsonValue>,
+}
+
+impl TransformScript {
+    #[cfg(test)]
+    pub(crate) fn with_executor(code: String, execute: fn(&str, JsonValue, JsonValue) -> Execution<JsonValue>) -> Self {
+        TransformScript { code: code.into(), execute }
+    }
+
+    /// check the code for syntax errors and type errors, see `Script::check`
+    pub fn check(&self) -> Result<(), String> {
+        let empty = JsonValue::Object(Default::default());
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| (self.execute)(&self.code, empty.clone(), empty)));
+        match res {
+            Ok(Execution::Invalid(e)) => Err(format!("{e} in rebo code `{}`", self.code)),
+            _ => Ok(()),
+        }
+    }
+
+    /// execute the script, returning `None` if it fails
+    pub fn run(&self, record: JsonValue, state: JsonValue) -> Option<JsonValue> {
+        match (self.execute)(&self.code, record.clone(), state) {
+            Execution::Ok(value) => Some(value),
+            Execution::Invalid(e) => {
+                eprintln!("{e} in rebo code `{}`", self.code);
+                None
+            }
+            Execution::Failed => {
+                eprintln!("error executing rebo code `{}` for record {record}", self.code);
+                None
+            }
+        }
+    }
+}
+
+fn execute_transform(code: &str, record: JsonValue, state: JsonValue) -> Execution<JsonValue> {
+    let config = ReboConfig::new()
+        .add_external_value("record".to_string(), record)
+        .add_external_value("state".to_string(), state);
+    execution(rebo::run_with_config("transform".to_string(), code.to_string(), config).return_value)
+}
+
+impl<I, O> Clone for Script<I, O> {
+    fn clone(&self) -> Self {
+        Script {
+            code: Arc::clone(&self.code),
+            cache: Arc::clone(&self.cache),
+            execute: self.execute,
+        }
+    }
+}
+impl<I, O> fmt::Debug for Script<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Script").field(&self.code).finish()
+    }
+}
+impl fmt::Debug for TransformScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TransformScript").field(&self.code).finish()
+    }
+}
+impl<'de> Deserialize<'de> for TransformScript {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(TransformScript { code: String::deserialize(deserializer)?.into(), execute: execute_transform })
+    }
+}
+impl<'de, I: ScriptInput, O: FromValue + Clone> Deserialize<'de> for Script<I, O> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Script::new(String::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::config::Config;
+    use super::*;
+
+    /// executor interpreting the code as the name of an operation instead of rebo
+    fn value_executor(code: &str, value: String) -> Execution<String> {
+        match code {
+            "invalid" => Execution::Invalid("syntax error".to_string()),
+            "fail" => Execution::Failed,
+            "panic" => panic!("runtime error"),
+            _ => Execution::Ok(value.to_uppercase()),
+        }
+    }
+
+    #[test]
+    fn value_results_are_cached() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let script = ValueScript::with_executor("upper".to_string(), |code, value| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            value_executor(code, value)
+        });
+        assert_eq!(script.run("on".to_string()).as_deref(), Some("ON"));
+        assert_eq!(script.clone().run("on".to_string()).as_deref(), Some("ON"));
+        assert_eq!(script.run("off".to_string()).as_deref(), Some("OFF"));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+        let failing = ValueScript::with_executor("fail".to_string(), value_executor);
+        assert_eq!(failing.run("on".to_string()), None);
+    }
+
+    #[test]
+    fn filter_results_are_not_cached() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let script = FilterScript::with_executor("has-message".to_string(), |_, values| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            Execution::Ok(values.get("MESSAGE").is_some())
+        });
+        let values = serde_json::json!({ "MESSAGE": "foo" });
+        assert_eq!(script.run(values.clone()), Some(true));
+        assert_eq!(script.run(values), Some(true));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+        assert!(script.cache.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_reports_invalid_scripts_at_startup() {
+        let mut config: Config = toml::from_str(r#"
+            [data.meter]
+            frontend = { name = "mqtt", data_type = "wide" }
+            backend.name = "stdout"
+            values.power = "/power"
+            values.energy = "/energy"
+        "#).unwrap();
+        let values = &mut config.data["meter"].mapping.values;
+        values["power"].preprocess = Some(ValueScript::with_executor("fail".to_string(), value_executor));
+        values["energy"].postprocess = Some(ValueScript::with_executor("panic".to_string(), value_executor));
+        // runtime errors with the example input don't prevent starting
+        assert_eq!(config.check_scripts(), Ok(()));
+
+        config.data["meter"].mapping.values["energy"].postprocess = Some(ValueScript::with_executor("invalid".to_string(), value_executor));
+        assert_eq!(config.check_scripts(), Err("postprocess of value \"energy\" of data \"meter\": syntax error in rebo code `invalid`".to_string()));
+    }
+}